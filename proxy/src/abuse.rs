@@ -0,0 +1,241 @@
+//! Automatic temporary bans of abusive addresses.
+//!
+//! Worlds make a handful of searches a minute, and only fetch links they
+//! were handed. An address that makes hundreds of different searches a
+//! minute is flooding e621 through the proxy, and one that fetches many
+//! links that never existed is guessing link ids. If the instance is
+//! configured with thresholds for either, addresses that go over them are
+//! banned for a while, and for longer each time they do it again, until they
+//! behave for a day.
+//!
+//! Bans are kept in memory, logged, and listed through the admin API, which
+//! can also lift them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::{client, epoch};
+
+/// How long after a ban ends its address is remembered, so that the next ban
+/// is longer.
+const FORGIVE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Why an address was banned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// Too many different searches in a minute.
+    SearchFlood,
+    /// Too many fetches of links that never existed in a minute.
+    LinkEnumeration,
+}
+
+impl Reason {
+    /// A short, stable identifier for this reason.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::SearchFlood => "search_flood",
+            Self::LinkEnumeration => "link_enumeration",
+        }
+    }
+}
+
+/// A ban of an address.
+#[derive(Debug, Clone, Copy)]
+pub struct Ban {
+    pub reason: Reason,
+    /// When the ban ends.
+    pub until: Instant,
+    /// How many times the address was banned, including this one.
+    pub offences: u32,
+}
+
+impl Ban {
+    /// The time left before the ban ends.
+    pub fn remaining(&self) -> Duration {
+        self.until.saturating_duration_since(Instant::now())
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = self.remaining().as_secs().div_ceil(60);
+        write!(
+            f,
+            "error,banned,This address is temporarily banned for abuse. Try again in {minutes} minutes."
+        )
+    }
+}
+
+/// What each address did in the current minute.
+#[derive(Default)]
+struct Activity {
+    /// Hashes of the different searches made.
+    queries: HashSet<u64>,
+    /// The number of links fetched that never existed.
+    missing_links: u32,
+}
+
+/// The activity and bans of every address.
+#[derive(Default)]
+struct Tracker {
+    /// The minute `activity` is for, in minutes since the unix epoch.
+    minute: u64,
+    activity: HashMap<IpAddr, Activity>,
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl Tracker {
+    /// Get a lock to the global `Tracker`.
+    fn get_lock() -> &'static Mutex<Self> {
+        static TRACKER: OnceLock<Mutex<Tracker>> = OnceLock::new();
+        TRACKER.get_or_init(Default::default)
+    }
+
+    /// The activity of an address in the current minute, starting a new
+    /// minute if it changed.
+    fn activity(&mut self, ip: IpAddr) -> &mut Activity {
+        let minute = epoch::minute();
+        if self.minute != minute {
+            self.minute = minute;
+            self.activity.clear();
+
+            let now = Instant::now();
+            self.bans.retain(|_, ban| ban.until + FORGIVE_AFTER > now);
+        }
+
+        self.activity.entry(ip).or_default()
+    }
+
+    /// Ban an address, for longer than its last ban if it had one recently.
+    fn ban(&mut self, ip: IpAddr, reason: Reason) {
+        let durations = &Config::global().server.abuse.ban_durations;
+        let offences = self.bans.get(&ip).map_or(0, |ban| ban.offences) + 1;
+
+        let index = usize::try_from(offences - 1).unwrap_or(usize::MAX);
+        let Some(&secs) = durations.get(index).or(durations.last()) else {
+            return;
+        };
+
+        tracing::warn!(
+            client_ip = %ip,
+            reason = reason.code(),
+            offences,
+            ban_secs = secs,
+            "banning {ip} for {secs}s: {}",
+            reason.code()
+        );
+
+        let until = Instant::now() + Duration::from_secs(secs);
+        self.bans.insert(
+            ip,
+            Ban {
+                reason,
+                until,
+                offences,
+            },
+        );
+        self.activity.remove(&ip);
+    }
+}
+
+/// The ban of an address, if it is banned.
+pub fn banned(ip: IpAddr) -> Option<Ban> {
+    let tracker = Tracker::get_lock().lock().expect("poisoned");
+    let ban = tracker.bans.get(&ip)?;
+
+    (ban.remaining() > Duration::ZERO).then_some(*ban)
+}
+
+/// Count a search by the client of the current request, banning it if it
+/// made too many different searches this minute.
+pub fn record_query(query: &str) {
+    let Some(max) = Config::global().server.abuse.max_unique_queries else {
+        return;
+    };
+    let Some(ip) = client::current() else {
+        return;
+    };
+
+    let mut hasher = DefaultHasher::new();
+    query.hash(&mut hasher);
+
+    let mut tracker = Tracker::get_lock().lock().expect("poisoned");
+    let activity = tracker.activity(ip);
+    activity.queries.insert(hasher.finish());
+    if activity.queries.len() > max {
+        tracker.ban(ip, Reason::SearchFlood);
+    }
+}
+
+/// Count a fetch of a link that never existed by the client of the current
+/// request, banning it if it fetched too many this minute.
+pub fn record_missing_link() {
+    let Some(max) = Config::global().server.abuse.max_missing_links else {
+        return;
+    };
+    let Some(ip) = client::current() else {
+        return;
+    };
+
+    let mut tracker = Tracker::get_lock().lock().expect("poisoned");
+    let activity = tracker.activity(ip);
+    activity.missing_links += 1;
+    if activity.missing_links > max {
+        tracker.ban(ip, Reason::LinkEnumeration);
+    }
+}
+
+/// The addresses that are banned, with their bans.
+pub fn bans() -> Vec<(IpAddr, Ban)> {
+    let tracker = Tracker::get_lock().lock().expect("poisoned");
+
+    tracker
+        .bans
+        .iter()
+        .filter(|(_, ban)| ban.remaining() > Duration::ZERO)
+        .map(|(ip, ban)| (*ip, *ban))
+        .collect()
+}
+
+/// Lift the ban of an address, and forget its past offences. Returns whether
+/// it was banned.
+pub fn lift(ip: IpAddr) -> bool {
+    let mut tracker = Tracker::get_lock().lock().expect("poisoned");
+    let lifted = tracker.bans.remove(&ip.to_canonical()).is_some();
+
+    if lifted {
+        tracing::info!("lifted the ban of {ip}");
+    }
+    lifted
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use super::{Reason, Tracker};
+
+    #[test]
+    fn test_escalating_bans() {
+        let mut tracker = Tracker::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        tracker.ban(ip, Reason::SearchFlood);
+        let first = tracker.bans[&ip];
+        assert_eq!(first.offences, 1);
+        assert!(first.remaining() <= Duration::from_secs(10 * 60));
+
+        tracker.ban(ip, Reason::LinkEnumeration);
+        let second = tracker.bans[&ip];
+        assert_eq!(second.offences, 2);
+        assert_eq!(second.reason, Reason::LinkEnumeration);
+        assert!(second.remaining() > Duration::from_secs(10 * 60));
+    }
+}
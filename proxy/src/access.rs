@@ -0,0 +1,197 @@
+//! Restricting which addresses may use the proxy.
+//!
+//! If the instance is configured with an access list, it is read from disk
+//! on startup, and read again whenever the file changes, so that operators
+//! can ban an abusive address without a restart. The file lists CIDR ranges
+//! to allow and to deny:
+//!
+//! ```json
+//! { "allow": ["10.0.0.0/8"], "deny": ["10.1.2.3", "2001:db8::/32"] }
+//! ```
+//!
+//! Denied ranges win over allowed ones. If no range is allowed, every
+//! address that isn't denied is.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+
+/// How often the access list file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The ranges of addresses allowed and denied by the access list.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Get a lock to the global `AccessList`.
+    fn get_lock() -> &'static RwLock<Arc<Self>> {
+        static LIST: OnceLock<RwLock<Arc<AccessList>>> = OnceLock::new();
+        LIST.get_or_init(Default::default)
+    }
+
+    /// Whether an address may use the proxy.
+    pub fn allows(ip: IpAddr) -> bool {
+        let list = Self::get_lock().read().expect("poisoned").clone();
+
+        let ip = ip.to_canonical();
+        let allowed = list.allow.is_empty() || list.allow.iter().any(|cidr| cidr.contains(ip));
+
+        allowed && !list.deny.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Read the access list at a path, keeping the current one if it can't
+    /// be read.
+    async fn load(path: &Path) {
+        let list = match tokio::fs::read(path).await {
+            Ok(file) => serde_json::from_slice::<Self>(&file),
+            Err(e) => {
+                tracing::error!("failed to read access list {}: {e}", path.display());
+                return;
+            }
+        };
+
+        match list {
+            Ok(list) => {
+                tracing::info!(
+                    "loaded access list: {} allowed, {} denied",
+                    list.allow.len(),
+                    list.deny.len()
+                );
+                *Self::get_lock().write().expect("poisoned") = Arc::new(list);
+            }
+            Err(e) => tracing::error!("invalid access list {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Read the configured access list, if there is one, and spawn a task that
+/// reads it again whenever it changes.
+pub async fn init() {
+    let Some(path) = &Config::global().server.access_list else {
+        return;
+    };
+
+    AccessList::load(path).await;
+    let mut loaded = modified(path).await;
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        // the first tick completes immediately, and the list was just loaded
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let modified = modified(path).await;
+            if modified != loaded {
+                AccessList::load(path).await;
+                loaded = modified;
+            }
+        }
+    });
+}
+
+/// When a file was last modified, if it can be told.
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+/// A range of addresses, like `10.0.0.0/8`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether an address is in this range. IPv4 ranges never contain IPv6
+    /// addresses, and the other way around.
+    pub fn contains(self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(addr) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(addr) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr)
+            .map_err(|_| format!("invalid address: {s}"))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length: {s}"))?,
+            None => max,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cidr;
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+
+        let single: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(single.contains("192.168.1.1".parse().unwrap()));
+        assert!(!single.contains("192.168.1.2".parse().unwrap()));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("1.2.3.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Access logs of requests.
+//!
+//! If enabled, every request is logged once it is answered, with its method,
+//! path, status, latency, size, the address of the client and, for links, the
+//! kind of link. Logs are written either as journald fields, which
+//! `journalctl -o json` can filter on, or as a JSON line, for log pipelines
+//! that only keep messages.
+
+use std::net::IpAddr;
+use std::time::Instant;
+
+use axum::body::HttpBody;
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+
+use crate::client::ClientIp;
+use crate::config::{AccessLogFormat, Config};
+use crate::request_id::RequestId;
+
+/// The kind of link a response was served from, as an extension of the
+/// response.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkKind(pub &'static str);
+
+/// Middleware that logs requests once they are answered, in the configured
+/// format.
+pub async fn log(
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(format) = Config::global().server.access_log else {
+        return next.run(req).await;
+    };
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let start = Instant::now();
+
+    let res = next.run(req).await;
+
+    let entry = Entry {
+        method: method.as_str(),
+        path: &path,
+        status: res.status().as_u16(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        bytes: body_len(&res),
+        client_ip: ip,
+        link_kind: res.extensions().get::<LinkKind>().map(|kind| kind.0),
+    };
+    entry.write(format);
+
+    res
+}
+
+/// The size of the body of a response, if it is known up front. Streamed
+/// files only know it through their `Content-Length`.
+pub fn body_len(res: &Response) -> Option<u64> {
+    res.body().size_hint().exact().or_else(|| {
+        res.headers()
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    })
+}
+
+/// A line of the access log.
+struct Entry<'a> {
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    latency_ms: u64,
+    bytes: Option<u64>,
+    client_ip: IpAddr,
+    link_kind: Option<&'static str>,
+}
+
+impl Entry<'_> {
+    fn write(&self, format: AccessLogFormat) {
+        let Self {
+            method,
+            path,
+            status,
+            latency_ms,
+            bytes,
+            client_ip,
+            link_kind,
+        } = *self;
+
+        match format {
+            AccessLogFormat::Journald => tracing::info!(
+                target: "access",
+                method,
+                path,
+                status,
+                latency_ms,
+                bytes,
+                %client_ip,
+                link_kind,
+                "{method} {path} {status} {latency_ms}ms"
+            ),
+            AccessLogFormat::Json => tracing::info!(
+                target: "access",
+                "{}",
+                serde_json::json!({
+                    "method": method,
+                    "path": path,
+                    "status": status,
+                    "latency_ms": latency_ms,
+                    "bytes": bytes,
+                    "client_ip": client_ip,
+                    "link_kind": link_kind,
+                    "request_id": RequestId::current().map(|id| id.to_string()),
+                })
+            ),
+        }
+    }
+}
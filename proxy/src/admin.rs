@@ -0,0 +1,345 @@
+//! The admin API.
+//!
+//! If the instance is configured with a token, `/admin/` lets its operator
+//! list the live links with their TTLs, purge a link or every link, flush the
+//! caches, see and reload the effective configuration, see how often each
+//! query is searched, turn maintenance mode on or off, manage banned search
+//! terms, see and lift abuse bans and see the usage of client tokens.
+//! Requests must send the token as a bearer token, and by default must come
+//! from the same host without going through a reverse proxy, so that the API
+//! can't be reached from the internet even if the token leaks.
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::{ConnectInfo, Path, Query as Params, Request};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Extension, Json, Router};
+use serde_json::{json, Map, Value};
+
+use crate::alias::Aliases;
+use crate::banned::{self, BannedTerms};
+use crate::client::{self, ClientIp};
+use crate::config::Config;
+use crate::links::{LinkId, LinkMap};
+use crate::metrics::Metrics;
+use crate::{abuse, api, maintenance, signing, store, tokens};
+
+/// The number of links or queries listed, unless a request asks for another.
+const DEFAULT_LIMIT: usize = 1000;
+
+/// The routes of the admin API, if it is enabled.
+pub fn router() -> Router {
+    if Config::global().server.admin.token.is_none() {
+        return Router::new();
+    }
+
+    Router::new()
+        .route("/admin/links", get(links).delete(purge_all))
+        .route("/admin/links/:id", delete(purge))
+        .route("/admin/caches/flush", post(flush))
+        .route("/admin/config", get(config))
+        .route("/admin/config/reload", post(reload))
+        .route("/admin/queries", get(queries))
+        .route(
+            "/admin/maintenance",
+            get(maintenance_status)
+                .put(|| set_maintenance(Some(true)))
+                .delete(|| set_maintenance(Some(false))),
+        )
+        .route("/admin/maintenance/reset", post(|| set_maintenance(None)))
+        .route("/admin/bans", get(bans))
+        .route("/admin/bans/:ip", delete(lift_ban))
+        .route("/admin/banned", get(banned_terms))
+        .route("/admin/banned/:term", put(ban_term).delete(unban_term))
+        .route("/admin/clients", get(clients))
+        .route_layer(axum::middleware::from_fn(authorize))
+}
+
+/// Middleware that refuses requests without the configured token, or from
+/// other hosts or through reverse proxies if the API is local only.
+async fn authorize(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let config = &Config::global().server.admin;
+
+    if config.local_only && !client::is_local(peer.ip(), req.headers()) {
+        tracing::warn!("refused admin request from {ip}");
+        return (StatusCode::FORBIDDEN, "admin API is local only").into_response();
+    }
+
+    let authorized = match (&config.token, bearer(req.headers())) {
+        (Some(token), Some(sent)) => {
+            signing::constant_time_eq(token.expose().as_bytes(), sent.as_bytes())
+        }
+        _ => false,
+    };
+    if !authorized {
+        tracing::warn!("refused unauthorized admin request from {ip}");
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "missing or wrong admin token",
+        )
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+/// The bearer token of a request, if it has one.
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// URL query parameters accepted by the listing endpoints.
+#[derive(Debug, serde::Deserialize)]
+struct ListParams {
+    limit: Option<usize>,
+}
+
+/// Handler for `GET /admin/links`.
+///
+/// Responds with the live links, by group, with the time they have left in
+/// milliseconds.
+async fn links(Params(params): Params<ListParams>) -> Json<Value> {
+    let map = LinkMap::global();
+    let links = map
+        .list(params.limit.unwrap_or(DEFAULT_LIMIT))
+        .into_iter()
+        .map(|link| {
+            json!({
+                "id": link.id,
+                "kind": link.kind,
+                "group": link.group,
+                "ttl_ms": link.remaining.map(|remaining| remaining.as_millis() as u64),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(json!({ "total": map.count(), "links": links }))
+}
+
+/// Handler for `DELETE /admin/links/:id`.
+///
+/// Purges the search or post a link belongs to. Clients fetching its links
+/// are told they were removed.
+async fn purge(Path(id): Path<LinkId>) -> Response {
+    let removed = LinkMap::global().purge(id);
+    if removed.is_empty() {
+        return (StatusCode::NOT_FOUND, "no such link").into_response();
+    }
+
+    tracing::info!("purged link {id} through the admin API");
+    store::forget(&removed).await;
+    Json(json!({ "purged": removed.len() })).into_response()
+}
+
+/// Handler for `DELETE /admin/links`.
+///
+/// Purges every link held by this instance.
+async fn purge_all() -> Json<Value> {
+    let removed = LinkMap::global().purge_all();
+
+    tracing::info!("purged all links through the admin API");
+    store::forget(&removed).await;
+    Json(json!({ "purged": removed.len() }))
+}
+
+/// Handler for `POST /admin/caches/flush`.
+///
+/// Empties the response, failure, image and alias caches, responding with
+/// how many entries each held.
+async fn flush() -> Json<Value> {
+    let mut flushed = api::flush_caches()
+        .await
+        .into_iter()
+        .map(|(cache, len)| (cache.to_owned(), len.into()))
+        .collect::<Map<_, _>>();
+    flushed.insert("aliases".into(), Aliases::flush().await.into());
+
+    tracing::info!("flushed caches through the admin API");
+    Json(Value::Object(flushed))
+}
+
+/// Handler for `GET /admin/config`.
+///
+/// Responds with the effective configuration, defaults included, with
+/// secrets redacted.
+async fn config() -> String {
+    format!("{:#?}\n", Config::global())
+}
+
+/// Handler for `POST /admin/config/reload`.
+///
+/// Reads the configuration file again, like `SIGHUP`, answering with why it
+/// was kept if the file is invalid.
+async fn reload() -> Response {
+    match Config::reload() {
+        Ok(()) => Json(json!({ "reloaded": true })).into_response(),
+        Err(e) => {
+            tracing::error!("failed to reload config, keeping the current one: {e}");
+            let body = json!({ "reloaded": false, "error": e.to_string() });
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+        }
+    }
+}
+
+/// Handler for `GET /admin/queries`.
+///
+/// Responds with how often the most searched queries were searched, shared
+/// and failed, and how long their searches took on average.
+async fn queries(Params(params): Params<ListParams>) -> Json<Value> {
+    let queries = Metrics::global()
+        .top_queries(params.limit.unwrap_or(DEFAULT_LIMIT))
+        .into_iter()
+        .map(|(tags, stats)| {
+            let average = stats
+                .elapsed
+                .checked_div(u32::try_from(stats.searches).unwrap_or(u32::MAX))
+                .map(|average| average.as_millis() as u64);
+
+            json!({
+                "tags": tags,
+                "searches": stats.searches,
+                "shared": stats.shared,
+                "failed": stats.failed,
+                "average_ms": average,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(json!({ "queries": queries }))
+}
+
+/// Handler for `GET /admin/maintenance`.
+async fn maintenance_status() -> Json<Value> {
+    Json(json!({ "maintenance": maintenance::enabled() }))
+}
+
+/// Handler for `PUT` and `DELETE /admin/maintenance`, which turn maintenance
+/// mode on and off, and `POST /admin/maintenance/reset`, which leaves it to
+/// the configuration again.
+async fn set_maintenance(enabled: Option<bool>) -> Json<Value> {
+    maintenance::set(enabled);
+    maintenance_status().await
+}
+
+/// Handler for `GET /admin/bans`.
+///
+/// Responds with the addresses banned for abuse, why, for how much longer,
+/// and how many times they were banned.
+async fn bans() -> Json<Value> {
+    let bans = abuse::bans()
+        .into_iter()
+        .map(|(ip, ban)| {
+            json!({
+                "ip": ip,
+                "reason": ban.reason.code(),
+                "remaining_secs": ban.remaining().as_secs(),
+                "offences": ban.offences,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(json!({ "bans": bans }))
+}
+
+/// Handler for `DELETE /admin/bans/:ip`.
+async fn lift_ban(Path(ip): Path<IpAddr>) -> Response {
+    if abuse::lift(ip) {
+        Json(json!({ "ip": ip, "lifted": true })).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "address isn't banned").into_response()
+    }
+}
+
+/// Handler for `GET /admin/banned`.
+///
+/// Responds with the banned search terms, with how many searches each
+/// rejected since the proxy started.
+async fn banned_terms() -> Json<Value> {
+    let terms = BannedTerms::list()
+        .await
+        .into_iter()
+        .map(|(term, rejected)| json!({ "term": term, "rejected": rejected }))
+        .collect::<Vec<_>>();
+
+    Json(json!({ "terms": terms }))
+}
+
+/// Handler for `PUT /admin/banned/:term`.
+async fn ban_term(Path(term): Path<String>) -> Response {
+    let Some(term) = banned::normalize(&term) else {
+        return (StatusCode::BAD_REQUEST, "invalid term").into_response();
+    };
+
+    match BannedTerms::add(&term).await {
+        Ok(added) => Json(json!({ "term": term, "added": added })).into_response(),
+        Err(e) => {
+            tracing::error!("failed to save banned terms: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to save banned terms",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Handler for `DELETE /admin/banned/:term`.
+async fn unban_term(Path(term): Path<String>) -> Response {
+    let term = term.trim().to_lowercase();
+
+    match BannedTerms::remove(&term).await {
+        Ok(true) => Json(json!({ "term": term, "removed": true })).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "term isn't banned").into_response(),
+        Err(e) => {
+            tracing::error!("failed to save banned terms: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to save banned terms",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Handler for `GET /admin/clients`.
+///
+/// Responds with the registered client tokens, by name, with how many
+/// searches each made and had throttled since the proxy started, and how
+/// many searches were made without a token.
+async fn clients() -> Json<Value> {
+    let (clients, anonymous) = tokens::usage();
+    let limits = &Config::global().server.clients.tokens;
+
+    let clients = clients
+        .into_iter()
+        .map(|(name, stats)| {
+            let limit = limits
+                .get(&name)
+                .and_then(|client| client.searches_per_minute);
+            let last_search = stats
+                .last_search
+                .and_then(|at| at.elapsed().ok())
+                .map(|elapsed| elapsed.as_secs());
+
+            json!({
+                "name": name,
+                "limit": limit,
+                "searches": stats.searches,
+                "throttled": stats.throttled,
+                "last_search_secs_ago": last_search,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(json!({ "clients": clients, "anonymous_searches": anonymous }))
+}
@@ -0,0 +1,109 @@
+//! Tag alias resolution.
+//!
+//! e621 maps many tags onto a canonical tag (e.g. `feline` onto `felid`), but
+//! searching for an alias directly returns no results. Before a query is sent
+//! upstream, each of its tags is looked up in e621's alias list and replaced
+//! with its canonical tag. Lookups are cached, since most searches reuse the
+//! same handful of tags.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use tokio::sync::RwLock;
+
+use crate::api;
+use crate::query::Query;
+
+/// How long a lookup is cached for.
+const TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// The maximum number of cached lookups.
+const MAX_ENTRIES: usize = 10_000;
+
+/// A cache of alias lookups, mapping tags to their canonical tag, if they have
+/// one.
+#[derive(Default)]
+pub struct Aliases {
+    inner: HashMap<Arc<str>, (Option<Arc<str>>, Instant)>,
+}
+
+impl Aliases {
+    /// Get a lock to the global `Aliases` cache.
+    fn get_lock() -> &'static RwLock<Self> {
+        static MAP: OnceLock<RwLock<Aliases>> = OnceLock::new();
+        MAP.get_or_init(Default::default)
+    }
+
+    /// Get the cached lookup for a tag, if it hasn't expired.
+    fn get(&self, tag: &str) -> Option<Option<Arc<str>>> {
+        self.inner
+            .get(tag)
+            .filter(|(_, at)| at.elapsed() < TTL)
+            .map(|(alias, _)| alias.clone())
+    }
+
+    /// Cache the lookup for a tag.
+    fn insert(&mut self, tag: Arc<str>, alias: Option<Arc<str>>) {
+        if self.inner.len() >= MAX_ENTRIES {
+            self.inner.retain(|_, (_, at)| at.elapsed() < TTL);
+        }
+        if self.inner.len() >= MAX_ENTRIES {
+            self.inner.clear();
+        }
+
+        self.inner.insert(tag, (alias, Instant::now()));
+    }
+
+    /// Empty the cache, returning how many lookups it held.
+    pub async fn flush() -> usize {
+        let mut aliases = Self::get_lock().write().await;
+        std::mem::take(&mut aliases.inner).len()
+    }
+
+    /// Resolve a tag to its canonical tag, looking it up upstream if it isn't
+    /// cached. Tags that can't be looked up are returned unchanged.
+    async fn resolve(tag: &str) -> Arc<str> {
+        if let Some(alias) = Self::get_lock().read().await.get(tag) {
+            return alias.unwrap_or_else(|| tag.into());
+        }
+
+        match api::alias(tag).await {
+            Ok(alias) => {
+                let tag: Arc<str> = tag.into();
+                Self::get_lock()
+                    .write()
+                    .await
+                    .insert(tag.clone(), alias.clone());
+                alias.unwrap_or(tag)
+            }
+            Err(e) => {
+                tracing::warn!("failed to look up alias for {tag}: {e}");
+                tag.into()
+            }
+        }
+    }
+
+    /// Replace any aliased tags in a query with their canonical tag.
+    ///
+    /// Meta-tags (like `rating:s`) and wildcard tags are left as-is. Prefixes
+    /// like `-` and `~` are kept on the replaced tag.
+    pub async fn apply(query: &mut Query) {
+        let resolved = query.tags.iter().map(|tag| async move {
+            let name = tag.trim_start_matches(['-', '~']);
+            if name.is_empty() || name.contains([':', '*']) {
+                return tag.clone();
+            }
+
+            let prefix = &tag[..tag.len() - name.len()];
+            let alias = Self::resolve(&name.to_lowercase()).await;
+            if alias.as_ref() != name {
+                tracing::info!("resolved alias: {name} -> {alias}");
+            }
+
+            format!("{prefix}{alias}")
+        });
+
+        query.tags = futures::future::join_all(resolved).await;
+    }
+}
@@ -1,37 +1,540 @@
 //! Manage backend API requests and responses.
 
-use std::sync::{Arc, OnceLock};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use crate::image::Image;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use itertools::Itertools;
+use tokio::sync::RwLock;
+use tracing::Instrument;
 
-/// Hardcoded blacklist
-const EXCLUDES: &str = "-young";
+use crate::config::{Config, Oversized, QueryConfig};
+use crate::image::{FetchError, Image, Progress};
+use crate::lru::ByteLru;
+use crate::metrics::Metrics;
+use crate::query::Query;
+use crate::slow;
 
-/// Query the e621 API with a given query string and page number.
-pub async fn query(query: &str, page: &str) -> Result<Posts, reqwest::Error> {
-    let url = format!("https://e621.net/posts.json?limit=20&page={page}&tags={query}+{EXCLUDES}+-type:webm+-type:gif");
+/// Tags that are excluded from every search. Unlike the configured excludes,
+/// these can't be removed by instance operators.
+const CORE_EXCLUDES: &[&str] = &["young"];
 
-    let posts: Root = HttpClient::global().get(&url).await?.json().await?;
+/// The number of tags e621 accepts in a search from a regular account.
+pub const MAX_TAGS: usize = 40;
 
-    Ok(posts.posts)
+/// The maximum number of cached search responses.
+const MAX_CACHED_RESPONSES: usize = 1_000;
+
+/// The maximum number of image URLs remembered as failed.
+const MAX_CACHED_FAILURES: usize = 10_000;
+
+/// Query the e621 API with a given query.
+///
+/// Responses are cached by their tags and page for the configured TTL.
+pub async fn query(query: &Query) -> Result<Posts, reqwest::Error> {
+    let url = "https://e621.net/posts.json";
+    let key = (tags(query), query.page);
+
+    if let Some(posts) = ResponseCache::get_lock().read().await.get(&key) {
+        tracing::info!("cached query: {}", key.0);
+        return Ok(drop_oversized(posts));
+    }
+
+    let params = [
+        ("limit", "20"),
+        ("page", &query.page.to_string()),
+        ("tags", &key.0),
+    ];
+
+    let posts: Root = HttpClient::global()
+        .get_query(url, &params)
+        .await?
+        .json()
+        .await?;
+
+    ResponseCache::get_lock()
+        .write()
+        .await
+        .insert(key, posts.posts.clone());
+
+    Ok(drop_oversized(posts.posts))
+}
+
+/// A cache of search responses, keyed by the tags and page sent upstream.
+#[derive(Default)]
+struct ResponseCache {
+    inner: HashMap<(String, u32), (Posts, Instant)>,
+}
+
+impl ResponseCache {
+    /// Get a lock to the global `ResponseCache`.
+    fn get_lock() -> &'static RwLock<Self> {
+        static CACHE: OnceLock<RwLock<ResponseCache>> = OnceLock::new();
+        CACHE.get_or_init(Default::default)
+    }
+
+    /// How long a response is cached for.
+    fn ttl() -> Duration {
+        Duration::from_secs(Config::global().api.cache_ttl)
+    }
+
+    /// Get the cached response for a search, if it hasn't expired.
+    fn get(&self, key: &(String, u32)) -> Option<Posts> {
+        self.inner
+            .get(key)
+            .filter(|(_, at)| at.elapsed() < Self::ttl())
+            .map(|(posts, _)| posts.clone())
+    }
+
+    /// Cache the response for a search, unless caching is disabled.
+    fn insert(&mut self, key: (String, u32), posts: Posts) {
+        if Self::ttl().is_zero() {
+            return;
+        }
+
+        if self.inner.len() >= MAX_CACHED_RESPONSES {
+            self.inner.retain(|_, (_, at)| at.elapsed() < Self::ttl());
+        }
+        if self.inner.len() >= MAX_CACHED_RESPONSES {
+            self.inner.clear();
+        }
+
+        self.inner.insert(key, (posts, Instant::now()));
+    }
+}
+
+/// Query the e621 API for a single random post matching a query.
+pub async fn random(query: &Query) -> Result<Posts, reqwest::Error> {
+    let url = "https://e621.net/posts.json";
+    let params = [("limit", "1"), ("tags", &(tags(query) + " order:random"))];
+
+    let posts: Root = HttpClient::global()
+        .get_query(url, &params)
+        .await?
+        .json()
+        .await?;
+
+    Ok(drop_oversized(posts.posts))
+}
+
+/// Remove posts over the configured maximum file size, if the instance is
+/// configured to drop them.
+fn drop_oversized(posts: Posts) -> Posts {
+    if Config::global().image.oversized != Oversized::Drop {
+        return posts;
+    }
+
+    posts
+        .iter()
+        .filter(|post| !post.is_oversized())
+        .cloned()
+        .collect()
+}
+
+/// Query the e621 API for the total number of posts matching a query.
+///
+/// The same exclusions as `query` are applied, so the count matches what a
+/// search would page through.
+pub async fn count(query: &Query) -> Result<u64, reqwest::Error> {
+    let url = "https://e621.net/counts/posts.json";
+    let params = [("tags", tags(query))];
+
+    let counts: CountsRoot = HttpClient::global()
+        .get_query(url, &params)
+        .await?
+        .json()
+        .await?;
+
+    Ok(counts.counts.posts)
+}
+
+/// Build the tag string sent upstream for a given query.
+///
+/// The tags are space separated; they are percent-encoded when the request
+/// URL is built.
+fn tags(query: &Query) -> String {
+    let added = added_tags(&Config::global().query, query.animated);
+    format!("{} {}", query.tags(), added.join(" "))
+}
+
+/// The tags appended to every query sent upstream: the core excludes, the
+/// instance's configured excludes, and excludes for any file types that
+/// can't be served.
+pub fn added_tags(config: &QueryConfig, animated: bool) -> Vec<String> {
+    let configured = config
+        .excludes
+        .iter()
+        .map(|tag| tag.trim_start_matches('-'));
+    let excludes = CORE_EXCLUDES
+        .iter()
+        .copied()
+        .chain(configured)
+        .unique()
+        .map(|tag| format!("-{tag}"));
+
+    let types: &[&str] = if animated {
+        &["-type:swf"]
+    } else {
+        &["-type:swf", "-type:webm", "-type:gif"]
+    };
+
+    excludes
+        .chain(types.iter().map(|&tag| tag.to_owned()))
+        .collect()
+}
+
+/// Query the e621 API for the tag that a given tag is an alias of, if any.
+pub async fn alias(tag: &str) -> Result<Option<Arc<str>>, reqwest::Error> {
+    let url = "https://e621.net/tag_aliases.json";
+    let params = [
+        ("search[antecedent_name]", tag),
+        ("search[status]", "active"),
+    ];
+
+    let aliases: AliasesRoot = HttpClient::global()
+        .get_query(url, &params)
+        .await?
+        .json()
+        .await?;
+
+    let alias = aliases
+        .into_aliases()
+        .iter()
+        .find(|alias| &*alias.antecedent_name == tag)
+        .map(|alias| alias.consequent_name.clone());
+
+    Ok(alias)
+}
+
+/// Query the e621 API for a page of comments on a given post.
+pub async fn comments(post_id: u64, page: u32) -> Result<Comments, reqwest::Error> {
+    let url = format!("https://e621.net/comments.json?group_by=comment&limit=20&page={page}&search[post_id]={post_id}");
+
+    let comments: CommentsRoot = HttpClient::global().get(&url).await?.json().await?;
+
+    Ok(comments.into_comments())
+}
+
+/// Check that the e621 API answers, with the cheapest request it serves.
+pub async fn probe() -> Result<(), reqwest::Error> {
+    let url = "https://e621.net/tags.json";
+    let params = [("limit", "1")];
+
+    HttpClient::global()
+        .get_query(url, &params)
+        .await?
+        .error_for_status()?;
+
+    Ok(())
 }
 
 /// Get an image from a URL, and return it as the crate `Image` type.
-pub async fn get_image(url: Arc<str>) -> Result<Image, reqwest::Error> {
-    log::info!("getting image: {url}");
+///
+/// Error statuses from the host are returned as errors, rather than serving
+/// the body of the error page as an image. The bytes received are reported
+/// to `progress` as they arrive.
+///
+/// Downloaded images are kept in the `ImageCache`, so that links to the same
+/// file, like the posts of overlapping or refreshed searches, share a single
+/// download. URLs that failed in a way that won't change, like taken down
+/// posts, are kept in the `FailureCache`, and fail again without a request.
+pub async fn get_image(url: Arc<str>, progress: &Progress) -> Result<Image, FetchError> {
+    let cached = ImageCache::get_lock().lock().expect("poisoned").get(&url);
+    if let Some(image) = cached {
+        tracing::info!("cached image: {url}");
+
+        progress.start(Some(image.data.len() as u64));
+        progress.receive(image.data.len());
+        return Ok(image);
+    }
+
+    let failed = FailureCache::get_lock().lock().expect("poisoned").get(&url);
+    if let Some(e) = failed {
+        tracing::info!("known failed image: {url}");
+        return Err(e);
+    }
+
+    tracing::info!("getting image: {url}");
+
+    let download = download_image(&url, progress)
+        .instrument(tracing::info_span!("image.download", url = &*url));
+    let threshold = Config::global().server.slow.image;
+    let image = slow::warn_if_slow("image download", threshold, &url, download)
+        .await
+        .map_err(|e| {
+            let e = FetchError::from(e);
+            FailureCache::get_lock()
+                .lock()
+                .expect("poisoned")
+                .insert(url.clone(), e);
+            e
+        })?;
+
+    ImageCache::get_lock()
+        .lock()
+        .expect("poisoned")
+        .insert(url, image.clone());
+
+    Ok(image)
+}
+
+/// Download a file that is only needed once, like a video a frame is
+/// extracted from, without keeping it in the `ImageCache`.
+///
+/// Returns `None` if the download fails, or once the file is known to be
+/// over `max_len` bytes.
+pub async fn get_file(url: &str, progress: &Progress, max_len: usize) -> Option<Vec<u8>> {
+    let mut res = HttpClient::global().get(url).await.ok()?;
+    let res_len = res.content_length();
+    if res.error_for_status_ref().is_err() || res_len.is_some_and(|len| len > max_len as u64) {
+        return None;
+    }
+    progress.start(res_len);
+
+    let mut data = Vec::new();
+    while let Some(chunk) = res.chunk().await.ok()? {
+        progress.receive(chunk.len());
+        data.extend_from_slice(&chunk);
+
+        if data.len() > max_len {
+            return None;
+        }
+    }
+
+    Some(data)
+}
 
-    let res = HttpClient::global().get(&url).await?;
+/// Download an image, as described by `get_image`.
+async fn download_image(url: &str, progress: &Progress) -> Result<Image, reqwest::Error> {
+    let mut res = HttpClient::global().get(url).await?.error_for_status()?;
 
+    let mime_type = mime_type(&res);
+    let len = res.content_length();
+    progress.start(len);
+
+    let mut data = Vec::with_capacity(len.and_then(|n| usize::try_from(n).ok()).unwrap_or(0));
+    while let Some(chunk) = res.chunk().await? {
+        progress.receive(chunk.len());
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(Image::new(data, mime_type))
+}
+
+/// An image from `stream_image`.
+pub enum ImageBody {
+    /// The image was in the `ImageCache`.
+    Cached(Image),
+    /// The body of the image, as it downloads.
+    Stream {
+        mime_type: Arc<str>,
+        len: Option<u64>,
+        body: BoxStream<'static, Result<Bytes, reqwest::Error>>,
+    },
+}
+
+/// Get an image from a URL like `get_image`, but without waiting for the
+/// whole image to download, so that it can be passed on as it arrives.
+///
+/// Once the whole body is streamed, the image is kept in the `ImageCache`, if
+/// its size is known upfront and it fits.
+pub async fn stream_image(url: Arc<str>) -> Result<ImageBody, FetchError> {
+    let cached = ImageCache::get_lock().lock().expect("poisoned").get(&url);
+    if let Some(image) = cached {
+        tracing::info!("cached image: {url}");
+        return Ok(ImageBody::Cached(image));
+    }
+
+    let failed = FailureCache::get_lock().lock().expect("poisoned").get(&url);
+    if let Some(e) = failed {
+        tracing::info!("known failed image: {url}");
+        return Err(e);
+    }
+
+    tracing::info!("streaming image: {url}");
+
+    let res = HttpClient::global()
+        .get(&url)
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let e = FetchError::from(e);
+            FailureCache::get_lock()
+                .lock()
+                .expect("poisoned")
+                .insert(url, e);
+            return Err(e);
+        }
+    };
+
+    let mime_type = mime_type(&res);
+    let len = res.content_length();
+    let fits = len
+        .and_then(|len| usize::try_from(len).ok())
+        .is_some_and(|len| len <= ImageCache::budget());
+
+    let state = Some((res, fits.then(Vec::new)));
+    let cached_as = (url, mime_type.clone());
+    let body = futures::stream::unfold(state, move |state| {
+        let (url, mime_type) = cached_as.clone();
+
+        async move {
+            let (mut res, mut data) = state?;
+
+            match res.chunk().await {
+                Ok(Some(chunk)) => {
+                    if let Some(data) = &mut data {
+                        data.extend_from_slice(&chunk);
+                    }
+                    Some((Ok(chunk), Some((res, data))))
+                }
+                Ok(None) => {
+                    if let Some(data) = data {
+                        ImageCache::get_lock()
+                            .lock()
+                            .expect("poisoned")
+                            .insert(url, Image::new(data, mime_type));
+                    }
+                    None
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        }
+    });
+
+    Ok(ImageBody::Stream {
+        mime_type,
+        len,
+        body: body.boxed(),
+    })
+}
+
+/// The mime type of a response, as sent by the host.
+fn mime_type(res: &reqwest::Response) -> Arc<str> {
     let mime_type = res
         .headers()
         .get(reqwest::header::CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
         .unwrap_or("application/octet-stream");
-    let mime_type = Arc::from(mime_type);
 
-    let data = res.bytes().await?.to_vec().into_boxed_slice();
+    Arc::from(mime_type)
+}
 
-    Ok(Image::new(data, mime_type))
+/// Image URLs that failed to download in a way that won't change by trying
+/// again soon, like a 404 for a taken down post, and why they failed.
+#[derive(Default)]
+struct FailureCache {
+    inner: HashMap<Arc<str>, (FetchError, Instant)>,
+}
+
+impl FailureCache {
+    /// Get a lock to the global `FailureCache`.
+    fn get_lock() -> &'static Mutex<Self> {
+        static CACHE: OnceLock<Mutex<FailureCache>> = OnceLock::new();
+        CACHE.get_or_init(Default::default)
+    }
+
+    /// How long a failure is cached for.
+    fn ttl() -> Duration {
+        Duration::from_secs(Config::global().image.failed_url_ttl)
+    }
+
+    /// Get the cached failure of a URL, if it hasn't expired.
+    fn get(&self, url: &str) -> Option<FetchError> {
+        self.inner
+            .get(url)
+            .filter(|(_, at)| at.elapsed() < Self::ttl())
+            .map(|(e, _)| *e)
+    }
+
+    /// Cache the failure of a URL, if the host answered with an error status
+    /// that isn't temporary, unless caching is disabled.
+    fn insert(&mut self, url: Arc<str>, e: FetchError) {
+        if Self::ttl().is_zero() || e.is_retryable() || !matches!(e, FetchError::Status(_)) {
+            return;
+        }
+
+        if self.inner.len() >= MAX_CACHED_FAILURES {
+            self.inner.retain(|_, (_, at)| at.elapsed() < Self::ttl());
+        }
+        if self.inner.len() >= MAX_CACHED_FAILURES {
+            self.inner.clear();
+        }
+
+        self.inner.insert(url, (e, Instant::now()));
+    }
+}
+
+/// Empty the response, failure and image caches, returning how many entries
+/// each held.
+pub async fn flush_caches() -> [(&'static str, usize); 3] {
+    let responses = std::mem::take(&mut ResponseCache::get_lock().write().await.inner);
+    let failures = std::mem::take(&mut FailureCache::get_lock().lock().expect("poisoned").inner);
+    let images = std::mem::take(&mut *ImageCache::get_lock().lock().expect("poisoned"));
+
+    [
+        ("responses", responses.len()),
+        ("failures", failures.len()),
+        ("images", images.inner.len()),
+    ]
+}
+
+/// The total size in bytes of the images in the `ImageCache`.
+pub fn cached_bytes() -> usize {
+    ImageCache::get_lock()
+        .lock()
+        .expect("poisoned")
+        .inner
+        .used()
+}
+
+/// A cache of downloaded images, keyed by their URL, that holds at most the
+/// configured number of bytes. Past that, the least recently used images are
+/// dropped.
+#[derive(Default)]
+struct ImageCache {
+    inner: ByteLru<Arc<str>, Image>,
+}
+
+impl ImageCache {
+    /// Get a lock to the global `ImageCache`.
+    fn get_lock() -> &'static Mutex<Self> {
+        static CACHE: OnceLock<Mutex<ImageCache>> = OnceLock::new();
+        CACHE.get_or_init(Default::default)
+    }
+
+    /// The maximum size in bytes of the cached images.
+    fn budget() -> usize {
+        let budget = Config::global().image.cache_size;
+        usize::try_from(budget).unwrap_or(usize::MAX)
+    }
+
+    /// Get the cached image of a URL, marking it as recently used.
+    fn get(&mut self, url: &str) -> Option<Image> {
+        let image = self.inner.get(url).cloned();
+        Metrics::global().image_cache(image.is_some());
+
+        image
+    }
+
+    /// Cache the image of a URL, unless it is larger than the whole cache,
+    /// dropping the least recently used images until it fits.
+    fn insert(&mut self, url: Arc<str>, image: Image) {
+        let size = image.data.len();
+        let budget = Self::budget();
+        if size > budget {
+            return;
+        }
+
+        self.inner.insert(url, image, size);
+        self.inner.evict(budget, None);
+    }
 }
 
 /// An HTTP client for the e621 API, with authorization headers.
@@ -64,8 +567,36 @@ impl HttpClient {
 
     /// Perform a GET request.
     async fn get(&self, url: &str) -> Result<reqwest::Response, reqwest::Error> {
-        self.client.get(url).send().await
+        timed(url, self.client.get(url).send()).await
     }
+
+    /// Perform a GET request, with the given query parameters percent-encoded
+    /// into the URL.
+    async fn get_query<Q>(&self, url: &str, query: &Q) -> Result<reqwest::Response, reqwest::Error>
+    where
+        Q: serde::Serialize + ?Sized,
+    {
+        timed(url, self.client.get(url).query(query).send()).await
+    }
+}
+
+/// Wait for the response to a request, counting it in the `Metrics` by the
+/// host of its URL.
+async fn timed<F>(url: &str, send: F) -> Result<reqwest::Response, reqwest::Error>
+where
+    F: Future<Output = Result<reqwest::Response, reqwest::Error>>,
+{
+    let start = Instant::now();
+    let res = send.await;
+
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+    let failed = res.as_ref().map_or(true, |res| !res.status().is_success());
+    Metrics::global().upstream(&host, start.elapsed(), failed);
+
+    res
 }
 
 //////////////////////////////////////////////////////////
@@ -79,11 +610,56 @@ struct Root {
     posts: Arc<[Post]>,
 }
 
+// used to deserialize the json response, immediately turned into an alias
+//
+// like comments, an empty list is returned as `{"tag_aliases": []}`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+#[doc(hidden)]
+enum AliasesRoot {
+    Aliases(Vec<Alias>),
+    Empty { tag_aliases: Vec<Alias> },
+}
+
+impl AliasesRoot {
+    fn into_aliases(self) -> Vec<Alias> {
+        match self {
+            Self::Aliases(aliases)
+            | Self::Empty {
+                tag_aliases: aliases,
+            } => aliases,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[doc(hidden)]
+struct Alias {
+    antecedent_name: Arc<str>,
+    consequent_name: Arc<str>,
+}
+
+// used to deserialize the json response, immediately turned into a `u64`
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[doc(hidden)]
+struct CountsRoot {
+    counts: Counts,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[doc(hidden)]
+struct Counts {
+    posts: u64,
+}
+
 /// A list of posts from the e621 API.
 pub type Posts = Arc<[Post]>;
 
 /// A post from the e621 API.
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Post {
     pub id: u64,
@@ -92,10 +668,63 @@ pub struct Post {
     pub sample: Sample,
     pub score: Score,
     pub rating: String,
+    #[serde(default)]
+    pub tags: Tags,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+impl Post {
+    /// Whether this post is animated, rather than a still image.
+    pub fn is_animated(&self) -> bool {
+        matches!(&*self.file.ext, "webm" | "mp4" | "gif")
+    }
+
+    /// Whether this post is a video, rather than an image.
+    pub fn is_video(&self) -> bool {
+        matches!(&*self.file.ext, "webm" | "mp4")
+    }
+
+    /// Whether this post's file is larger than the configured maximum size.
+    pub fn is_oversized(&self) -> bool {
+        Config::global()
+            .image
+            .max_file_size
+            .is_some_and(|max| self.file.size.unsigned_abs() > max)
+    }
+
+    /// Whether this post is a GIF.
+    pub fn is_gif(&self) -> bool {
+        &*self.file.ext == "gif"
+    }
+
+    /// The URL of a still image for this post, used for its `Image` link.
+    ///
+    /// This is the sample image, unless the post is animated and the sample
+    /// isn't a still image, in which case the preview is used instead.
+    pub fn still_url(&self) -> Arc<str> {
+        self.sample_url()
+            .unwrap_or_else(|| self.preview.url.clone())
+    }
+
+    /// The URL of this post's sample image, unless the post is animated and
+    /// the sample isn't a still image.
+    pub fn sample_url(&self) -> Option<Arc<str>> {
+        let is_still = [".jpg", ".jpeg", ".png", ".webp"]
+            .iter()
+            .any(|ext| self.sample.url.ends_with(ext));
+
+        (!self.is_animated() || is_still).then(|| self.sample.url.clone())
+    }
+
+    /// The URL of this post's original file, unless the post is animated or
+    /// over the configured maximum file size.
+    pub fn original_url(&self) -> Option<Arc<str>> {
+        (!self.is_animated() && !self.is_oversized()).then(|| self.file.url.clone())
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
+#[allow(dead_code)] // mirrors the API response, not every field is used yet
 pub struct File {
     pub width: i64,
     pub height: i64,
@@ -105,7 +734,7 @@ pub struct File {
     pub url: Arc<str>,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Preview {
     pub width: i64,
@@ -113,8 +742,9 @@ pub struct Preview {
     pub url: Arc<str>,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
+#[allow(dead_code)] // mirrors the API response, not every field is used yet
 pub struct Sample {
     pub has: bool,
     pub height: i64,
@@ -122,9 +752,53 @@ pub struct Sample {
     pub url: Arc<str>,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
+/// The tags of a post, by category. Only the categories used by the proxy
+/// are deserialized.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Tags {
+    pub artist: Vec<Arc<str>>,
+    pub character: Vec<Arc<str>>,
+    pub species: Vec<Arc<str>>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Score {
     pub up: i64,
     pub down: i64,
 }
+
+// used to deserialize the json response, immediately turned into `Comments`
+//
+// e621 returns a bare array when there are comments, but `{"comments": []}`
+// when there are none.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+#[doc(hidden)]
+enum CommentsRoot {
+    Comments(Comments),
+    Empty { comments: Comments },
+}
+
+impl CommentsRoot {
+    fn into_comments(self) -> Comments {
+        match self {
+            Self::Comments(comments) | Self::Empty { comments } => comments,
+        }
+    }
+}
+
+/// A list of comments from the e621 API.
+pub type Comments = Arc<[Comment]>;
+
+/// A comment on a post from the e621 API.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Comment {
+    pub creator_name: Arc<str>,
+    pub score: i64,
+    pub body: Arc<str>,
+    #[serde(default)]
+    pub is_hidden: bool,
+}
@@ -0,0 +1,221 @@
+//! Banned search terms.
+//!
+//! On top of the instance blacklist, which silently filters posts out of
+//! every search, operators may ban search terms through the admin API.
+//! Searches for a banned term are rejected with a policy message instead,
+//! and logged, so that moderators can see who searches for what. Terms are
+//! kept in memory, and written to the configured file, `./banned_terms.json`
+//! by default, whenever they change, so they survive restarts.
+//!
+//! A term matches a tag the query searches for, ignoring case. A `*` in a
+//! term, or in a searched tag, stands for any run of characters, so `gore*`
+//! bans every tag starting with `gore`, and searching for `gor*` is rejected
+//! since e621 would expand it to banned tags. Excluding a banned term with `-`
+//! is allowed.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use tokio::sync::RwLock;
+
+use crate::config::Config;
+use crate::query::{Query, QueryError};
+
+/// The maximum length of a banned term.
+const MAX_TERM_LEN: usize = 128;
+
+/// The banned terms of the instance, with how many searches they rejected
+/// since the proxy started.
+#[derive(Default)]
+pub struct BannedTerms {
+    inner: BTreeMap<String, u64>,
+}
+
+impl BannedTerms {
+    /// Get a lock to the global `BannedTerms`, loading them from disk the
+    /// first time they are used.
+    fn get_lock() -> &'static RwLock<Self> {
+        static TERMS: OnceLock<RwLock<BannedTerms>> = OnceLock::new();
+        TERMS.get_or_init(|| RwLock::new(Self::load()))
+    }
+
+    /// The path banned terms are saved to.
+    ///
+    /// The path is read once, since the terms were loaded from it.
+    fn path() -> &'static Path {
+        static PATH: OnceLock<PathBuf> = OnceLock::new();
+        PATH.get_or_init(|| Config::global().server.banned_terms.clone())
+    }
+
+    /// Load the banned terms from disk, if they were saved previously.
+    fn load() -> Self {
+        let Ok(file) = std::fs::read_to_string(Self::path()) else {
+            return Self::default();
+        };
+
+        match serde_json::from_str::<Vec<String>>(&file) {
+            Ok(terms) => Self {
+                inner: terms.into_iter().map(|term| (term, 0)).collect(),
+            },
+            Err(e) => {
+                tracing::error!("invalid banned terms file, starting empty: {e}");
+                Self::default()
+            }
+        }
+    }
+
+    /// Save the banned terms to disk, through a temporary file, so that a
+    /// crash while saving doesn't lose them.
+    async fn save(&self) -> io::Result<()> {
+        let terms = self.inner.keys().collect::<Vec<_>>();
+        let json = serde_json::to_vec(&terms).map_err(io::Error::other)?;
+
+        let path = Self::path();
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, json).await?;
+        tokio::fs::rename(&temp, path).await
+    }
+
+    /// The banned terms, with how many searches each rejected.
+    pub async fn list() -> Vec<(String, u64)> {
+        let terms = Self::get_lock().read().await;
+        terms
+            .inner
+            .iter()
+            .map(|(term, n)| (term.clone(), *n))
+            .collect()
+    }
+
+    /// Ban a term. Returns whether it wasn't banned already.
+    pub async fn add(term: &str) -> io::Result<bool> {
+        let mut terms = Self::get_lock().write().await;
+
+        if terms.inner.contains_key(term) {
+            return Ok(false);
+        }
+        terms.inner.insert(term.to_owned(), 0);
+        if let Err(e) = terms.save().await {
+            terms.inner.remove(term);
+            return Err(e);
+        }
+
+        tracing::info!("banned search term: {term}");
+        Ok(true)
+    }
+
+    /// Lift the ban on a term. Returns whether it was banned.
+    pub async fn remove(term: &str) -> io::Result<bool> {
+        let mut terms = Self::get_lock().write().await;
+
+        let Some(n) = terms.inner.remove(term) else {
+            return Ok(false);
+        };
+        if let Err(e) = terms.save().await {
+            terms.inner.insert(term.to_owned(), n);
+            return Err(e);
+        }
+
+        tracing::info!("unbanned search term: {term}");
+        Ok(true)
+    }
+
+    /// Reject a query that searches for a banned term, logging the match.
+    pub async fn check(query: &Query) -> Result<(), QueryError> {
+        let term = {
+            let terms = Self::get_lock().read().await;
+            if terms.inner.is_empty() {
+                return Ok(());
+            }
+
+            let banned = query.tags.iter().find_map(|tag| {
+                if tag.starts_with('-') {
+                    return None;
+                }
+
+                let tag = tag.trim_start_matches('~').to_lowercase();
+                terms.inner.keys().find(|term| matches(term, &tag)).cloned()
+            });
+            match banned {
+                Some(term) => term,
+                None => return Ok(()),
+            }
+        };
+
+        if let Some(n) = Self::get_lock().write().await.inner.get_mut(&term) {
+            *n += 1;
+        }
+        tracing::warn!(
+            banned_term = term.as_str(),
+            query = query.tags().as_str(),
+            "rejected search for banned term {term}: {}",
+            query.tags()
+        );
+
+        Err(QueryError::BannedTerm)
+    }
+}
+
+/// Whether a banned term matches a lowercase tag, that is whether some tag
+/// matches both, with `*` standing for any run of characters in either.
+fn matches(term: &str, tag: &str) -> bool {
+    let (term, tag) = (term.as_bytes(), tag.as_bytes());
+
+    // overlaps[i][j]: whether the rests of the term from `i` and of the tag
+    // from `j` match some tag in common
+    let mut overlaps = vec![vec![false; tag.len() + 1]; term.len() + 1];
+    for i in (0..=term.len()).rev() {
+        for j in (0..=tag.len()).rev() {
+            overlaps[i][j] = match (term.get(i), tag.get(j)) {
+                (None, None) => true,
+                // the star matches nothing, or takes the other's next char
+                (Some(b'*'), next) => overlaps[i + 1][j] || (next.is_some() && overlaps[i][j + 1]),
+                (next, Some(b'*')) => overlaps[i][j + 1] || (next.is_some() && overlaps[i + 1][j]),
+                (Some(a), Some(b)) => a == b && overlaps[i + 1][j + 1],
+                _ => false,
+            };
+        }
+    }
+
+    overlaps[0][0]
+}
+
+/// Normalize a term pushed through the admin API, if it may be banned.
+pub fn normalize(term: &str) -> Option<String> {
+    let term = term.trim().to_lowercase();
+    let valid = (1..=MAX_TERM_LEN).contains(&term.len())
+        && term != "*"
+        && !term.starts_with(['-', '~'])
+        && !term.contains(char::is_whitespace);
+
+    valid.then_some(term)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, normalize};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("gore", "gore"));
+        assert!(!matches("gore", "gored"));
+        assert!(matches("gore*", "gored"));
+        assert!(!matches("gore*", "blood"));
+
+        // wildcard searches that e621 would expand to a banned tag
+        assert!(matches("gore", "gor*"));
+        assert!(matches("gore", "*ore"));
+        assert!(matches("gore*", "gor*"));
+        assert!(matches("gore*", "gore_and_*"));
+        assert!(matches("gore*", "*"));
+        assert!(!matches("gore", "gored*"));
+        assert!(!matches("gore*", "blo*"));
+        assert!(!matches("gore", "*x"));
+
+        assert_eq!(normalize(" Gore "), Some("gore".into()));
+        assert_eq!(normalize("*"), None);
+        assert_eq!(normalize("-gore"), None);
+        assert_eq!(normalize("two words"), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! Per-client blacklists.
+//!
+//! A client may register a blacklist against a token of its choosing through
+//! the `/bl/` endpoints, and then apply it to any search by adding `bl:token`
+//! to the query. This spares VRChat users from retyping their exclusions for
+//! every search. Blacklists are kept in memory, and written to
+//! `./blacklists.json` whenever one changes, so they survive restarts.
+//!
+//! The tags of a blacklist count towards the tags of the searches using it,
+//! so a blacklist may only have as many tags as a search, minus one for the
+//! search itself. The number of blacklists is limited as well, since anyone
+//! may register one.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use tokio::sync::RwLock;
+
+use crate::config::Config;
+use crate::query::{self, Query};
+
+/// The maximum number of blacklists kept.
+const MAX_BLACKLISTS: usize = 10_000;
+
+/// The maximum length of a blacklist token.
+const MAX_TOKEN_LEN: usize = 64;
+
+/// A map of client tokens to their blacklisted tags.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Blacklists {
+    inner: HashMap<String, Vec<String>>,
+}
+
+impl Blacklists {
+    /// Get a lock to the global `Blacklists`, loading it from disk the first
+    /// time it is used.
+    fn get_lock() -> &'static RwLock<Self> {
+        static MAP: OnceLock<RwLock<Blacklists>> = OnceLock::new();
+        MAP.get_or_init(|| RwLock::new(Self::load()))
+    }
+
+    /// The path blacklists are saved to.
+    fn path() -> PathBuf {
+        PathBuf::from("./").join("blacklists.json")
+    }
+
+    /// Load the blacklists from disk, if they were saved previously.
+    fn load() -> Self {
+        Self::load_from(&Self::path())
+    }
+
+    /// Load the blacklists from the given path, if they were saved there.
+    fn load_from(path: &Path) -> Self {
+        let Ok(file) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+
+        serde_json::from_str(&file).unwrap_or_else(|e| {
+            tracing::error!("invalid blacklists file, starting empty: {e}");
+            Self::default()
+        })
+    }
+
+    /// Save the blacklists to disk.
+    async fn save(&self) -> io::Result<()> {
+        self.save_to(&Self::path()).await
+    }
+
+    /// Save the blacklists to the given path, through a temporary file, so
+    /// that a crash while saving doesn't lose them.
+    async fn save_to(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec(self).map_err(io::Error::other)?;
+
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, json).await?;
+        tokio::fs::rename(&temp, path).await
+    }
+
+    /// Set the tags blacklisted for a token, unless it is new and there are
+    /// too many blacklists already. Returns the tags it replaced, if any.
+    fn insert(&mut self, token: &str, tags: Vec<String>) -> Result<Option<Vec<String>>, Full> {
+        if !self.inner.contains_key(token) && self.inner.len() >= MAX_BLACKLISTS {
+            return Err(Full);
+        }
+
+        Ok(self.inner.insert(token.to_owned(), tags))
+    }
+
+    /// Exclude the tags of the blacklist selected by a query, if there is
+    /// one.
+    fn exclude(&self, query: &mut Query) {
+        let Some(token) = &query.blacklist else {
+            return;
+        };
+
+        match self.inner.get(token) {
+            Some(tags) => query.exclude(tags),
+            None => tracing::info!("unknown blacklist: {token}"),
+        }
+    }
+
+    /// Get the tags blacklisted for a token.
+    pub async fn get(token: &str) -> Option<Vec<String>> {
+        Self::get_lock().read().await.inner.get(token).cloned()
+    }
+
+    /// Set the tags blacklisted for a token, replacing any previous ones.
+    /// Returns `Ok(false)`, without setting them, if the token is new and
+    /// there are too many blacklists already.
+    ///
+    /// If the blacklists can't be saved, the previous tags are restored.
+    pub async fn set(token: &str, tags: Vec<String>) -> io::Result<bool> {
+        let mut map = Self::get_lock().write().await;
+
+        let Ok(previous) = map.insert(token, tags) else {
+            return Ok(false);
+        };
+        if let Err(e) = map.save().await {
+            match previous {
+                Some(previous) => map.inner.insert(token.to_owned(), previous),
+                None => map.inner.remove(token),
+            };
+            return Err(e);
+        }
+
+        Ok(true)
+    }
+
+    /// Remove the blacklist for a token.
+    ///
+    /// If the blacklists can't be saved, the blacklist is restored.
+    pub async fn clear(token: &str) -> io::Result<()> {
+        let mut map = Self::get_lock().write().await;
+
+        if let Some(previous) = map.inner.remove(token) {
+            if let Err(e) = map.save().await {
+                map.inner.insert(token.to_owned(), previous);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the blacklist selected by a query, if there is one.
+    pub async fn apply(query: &mut Query) {
+        Self::get_lock().read().await.exclude(query);
+    }
+}
+
+/// The maximum number of blacklists is reached.
+#[derive(Debug, PartialEq, Eq)]
+struct Full;
+
+/// The maximum number of tags a blacklist may have, leaving room for a tag
+/// to search for in every search using it.
+pub fn max_tags() -> usize {
+    query::tag_limit(&Config::global().query, false).saturating_sub(1)
+}
+
+/// Whether a string may be used as a blacklist token.
+pub fn is_valid_token(token: &str) -> bool {
+    (1..=MAX_TOKEN_LEN).contains(&token.len())
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::QueryConfig;
+    use crate::query::Query;
+
+    use super::{Blacklists, Full, MAX_BLACKLISTS};
+
+    #[test]
+    fn test_set_get_clear() {
+        let mut map = Blacklists::default();
+
+        assert_eq!(map.insert("abc", vec!["gore".into()]), Ok(None));
+        assert_eq!(
+            map.insert("abc", vec!["feral".into()]),
+            Ok(Some(vec!["gore".into()]))
+        );
+        assert_eq!(map.inner["abc"], ["feral"]);
+        assert!(map.inner.remove("abc").is_some());
+        assert!(map.inner.is_empty());
+
+        for n in 0..MAX_BLACKLISTS {
+            map.insert(&n.to_string(), Vec::new()).unwrap();
+        }
+        assert_eq!(map.insert("new", Vec::new()), Err(Full));
+        // existing blacklists can still be changed
+        assert!(map.insert("0", vec!["gore".into()]).is_ok());
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = QueryConfig::default();
+        let mut map = Blacklists::default();
+        map.insert("abc", vec!["gore".into(), "-feral".into()])
+            .unwrap();
+
+        let mut query = Query::parse("fox -gore bl:abc", &config).unwrap();
+        map.exclude(&mut query);
+        assert_eq!(query.tags, ["fox", "-gore", "-feral"]);
+
+        let mut query = Query::parse("fox bl:unknown", &config).unwrap();
+        map.exclude(&mut query);
+        assert_eq!(query.tags, ["fox"]);
+    }
+
+    #[tokio::test]
+    async fn test_save() {
+        let path = std::env::temp_dir().join(format!("blacklists-{}.json", std::process::id()));
+        let mut map = Blacklists::default();
+        map.insert("abc", vec!["gore".into()]).unwrap();
+
+        map.save_to(&path).await.unwrap();
+        let loaded = Blacklists::load_from(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.inner, map.inner);
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
@@ -0,0 +1,444 @@
+//! Memory budget for the images held by links.
+//!
+//! Every image served through a `Previews` or `Image` link is kept in memory
+//! for as long as the link lives, so that repeated requests don't reach e621.
+//! An `ImageSlot` holds one such image, and reports its size to the global
+//! `ImageBudget` once it is loaded. When the loaded images go over the
+//! configured budget, the least recently accessed ones are dropped from their
+//! slots. The slots themselves are kept, and load their image again the next
+//! time it is requested.
+//!
+//! If an image fails to load in a way that may be temporary, like a timeout or
+//! a server error from e621, it is loaded again when it is next requested,
+//! after a backoff and up to the configured number of retries. Other failures,
+//! and failures past the last retry, are kept for the configured failure TTL,
+//! during which the placeholder is served without reaching e621. After that,
+//! the image is loaded again, with a fresh set of retries.
+//!
+//! Downscaled variants of an image are kept in slots of their own, created by
+//! their parent slot on demand, and loaded from the parent's image.
+//!
+//! When a link is torn down, its slot is cancelled, which stops any download
+//! of its image that is still running and drops the image if it is loaded.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+use crate::image::{self, FetchError, Image, Progress};
+use crate::lru::ByteLru;
+use crate::promise::{LazyPromise, Promise};
+
+/// A function that creates the future that loads the image of a slot, which
+/// reports its downloads to the given `Progress`.
+type Loader = Box<dyn Fn(Progress) -> BoxFuture<'static, Result<Image, FetchError>> + Send + Sync>;
+
+/// The promise of an `ImageSlot`.
+#[derive(Clone)]
+enum Source {
+    /// The image started loading when the slot was created.
+    Eager(Promise<Result<Image, FetchError>>),
+    /// The image starts loading when it is first requested.
+    Lazy(LazyPromise<Result<Image, FetchError>>),
+}
+
+impl Source {
+    /// Get the image of this source, loading it if needed.
+    async fn get(&self) -> Result<Image, FetchError> {
+        match self {
+            Self::Eager(promise) => promise.get().await.clone(),
+            Self::Lazy(promise) => promise.get().await.clone(),
+        }
+    }
+
+    /// Get the image of this source, if it is done loading.
+    fn try_get(&self) -> Option<&Result<Image, FetchError>> {
+        match self {
+            Self::Eager(promise) => promise.try_get(),
+            Self::Lazy(promise) => promise.try_get(),
+        }
+    }
+}
+
+/// How far along the image of an `ImageSlot` is.
+pub enum LoadStatus {
+    /// The image is loading, or will load when it is requested. The bytes
+    /// received so far are given, and the total if it is known.
+    Loading { received: u64, total: Option<u64> },
+    /// The image is loaded, and has the given size in bytes.
+    Loaded(usize),
+    /// The image failed to load.
+    Failed(FetchError),
+}
+
+/// A shared slot for an image, which may be unloaded to stay within the
+/// `ImageBudget`.
+#[derive(Clone)]
+pub struct ImageSlot {
+    state: Arc<SlotState>,
+}
+
+struct SlotState {
+    /// The identifier of this slot in the `ImageBudget`.
+    id: u64,
+    /// The current promise, and how many times the slot has been unloaded.
+    source: Mutex<(u64, Source)>,
+    load: Loader,
+    /// Cancelled when the link of this slot is torn down.
+    cancel: CancellationToken,
+    retry: Mutex<Retry>,
+    /// The progress of the current load.
+    progress: Progress,
+    /// The downscaled variants of the image, by maximum dimension.
+    variants: Mutex<HashMap<u32, ImageSlot>>,
+}
+
+/// The retries of a slot whose image failed to load.
+#[derive(Default)]
+struct Retry {
+    /// How many retries were scheduled since the failure TTL last ran out.
+    attempts: u32,
+    /// The generation that failed, and when the image may be loaded again.
+    due: Option<(u64, Instant)>,
+}
+
+impl ImageSlot {
+    /// Create a slot whose image starts loading immediately.
+    pub fn eager<F>(load: F) -> Self
+    where
+        F: Fn(Progress) -> BoxFuture<'static, Result<Image, FetchError>> + Send + Sync + 'static,
+    {
+        let (cancel, progress) = (CancellationToken::new(), Progress::default());
+        let source = Source::Eager(Promise::new(cancellable(&cancel, load(progress.clone()))));
+        Self::new(source, Box::new(load), cancel, progress)
+    }
+
+    /// Create a slot whose image starts loading when it is first requested.
+    pub fn lazy<F>(load: F) -> Self
+    where
+        F: Fn(Progress) -> BoxFuture<'static, Result<Image, FetchError>> + Send + Sync + 'static,
+    {
+        let (cancel, progress) = (CancellationToken::new(), Progress::default());
+        let source = Source::Lazy(LazyPromise::new(cancellable(
+            &cancel,
+            load(progress.clone()),
+        )));
+        Self::new(source, Box::new(load), cancel, progress)
+    }
+
+    fn new(source: Source, load: Loader, cancel: CancellationToken, progress: Progress) -> Self {
+        static IDS: AtomicU64 = AtomicU64::new(0);
+
+        let state = SlotState {
+            id: IDS.fetch_add(1, Ordering::Relaxed),
+            source: Mutex::new((0, source)),
+            load,
+            cancel,
+            retry: Mutex::default(),
+            progress,
+            variants: Mutex::default(),
+        };
+
+        Self {
+            state: Arc::new(state),
+        }
+    }
+
+    /// Get the image of this slot, loading it if it isn't loaded.
+    ///
+    /// Failures are kept like loaded images, until the slot is unloaded or the
+    /// image is retried.
+    pub async fn get(&self) -> Result<Image, FetchError> {
+        self.state.retry_if_due();
+
+        let (generation, source) = self.state.source.lock().expect("poisoned").clone();
+        let image = match source.get().await {
+            Ok(image) => image,
+            Err(e) => {
+                self.state.failed(generation, e);
+                return Err(e);
+            }
+        };
+
+        let evicted = ImageBudget::global().lock().expect("poisoned").touch(
+            &self.state,
+            generation,
+            image.data.len(),
+        );
+
+        // unloading (and possibly dropping) slots locks the budget, so it's
+        // done once the budget is released
+        for slot in evicted {
+            slot.unload();
+        }
+
+        Ok(image)
+    }
+
+    /// Get how far along the image of this slot is, without loading it.
+    pub fn status(&self) -> LoadStatus {
+        let source = self.state.source.lock().expect("poisoned");
+
+        match source.1.try_get() {
+            Some(Ok(image)) => LoadStatus::Loaded(image.data.len()),
+            Some(Err(e)) => LoadStatus::Failed(*e),
+            None => {
+                let (received, total) = self.state.progress.get();
+                LoadStatus::Loading { received, total }
+            }
+        }
+    }
+
+    /// Get the slot of a variant of this slot's image, downscaled so that it
+    /// is at most `max` pixels wide and tall. The variant is loaded from this
+    /// slot's image the first time it is requested.
+    ///
+    /// Sizes are rounded up to a power of two, from 64 to 4096, so that
+    /// clients can't fill memory with a variant for every size.
+    pub fn variant(&self, max: u32) -> ImageSlot {
+        let max = max.clamp(64, 4096).next_power_of_two();
+        let mut variants = self.state.variants.lock().expect("poisoned");
+
+        let variant = variants
+            .entry(max)
+            .or_insert_with(|| self.derive(move |image| Box::pin(image::fit(image, max))));
+
+        variant.clone()
+    }
+
+    /// Create a slot whose image is made from this slot's image by `make`,
+    /// the first time it is requested.
+    pub fn derive<F>(&self, make: F) -> ImageSlot
+    where
+        F: Fn(Image) -> BoxFuture<'static, Result<Image, FetchError>> + Send + Sync + 'static,
+    {
+        // the parent holds its variants, so they can't hold it back
+        let parent = Arc::downgrade(&self.state);
+        let make = Arc::new(make);
+
+        ImageSlot::lazy(move |_| {
+            let (parent, make) = (parent.clone(), make.clone());
+
+            Box::pin(async move {
+                let state = parent.upgrade().ok_or(FetchError::Cancelled)?;
+                let image = ImageSlot { state }.get().await?;
+                make(image).await
+            })
+        })
+    }
+
+    /// Stop loading the image of this slot and drop it, for when its link is
+    /// torn down. Requests still waiting on the image get
+    /// `FetchError::Cancelled`.
+    pub fn cancel(&self) {
+        self.state.cancel.cancel();
+
+        let variants = std::mem::take(&mut *self.state.variants.lock().expect("poisoned"));
+        for variant in variants.into_values() {
+            variant.cancel();
+        }
+
+        {
+            let mut source = self.state.source.lock().expect("poisoned");
+            let cancelled = LazyPromise::new(futures::future::ready(Err(FetchError::Cancelled)));
+            *source = (source.0 + 1, Source::Lazy(cancelled));
+        }
+
+        ImageBudget::global()
+            .lock()
+            .expect("poisoned")
+            .remove(self.state.id);
+    }
+}
+
+/// Wrap the future that loads an image, so that it is dropped, along with
+/// any download it is running, once the token is cancelled.
+fn cancellable(
+    cancel: &CancellationToken,
+    load: BoxFuture<'static, Result<Image, FetchError>>,
+) -> BoxFuture<'static, Result<Image, FetchError>> {
+    let cancel = cancel.clone();
+
+    Box::pin(async move {
+        tokio::select! {
+            () = cancel.cancelled() => Err(FetchError::Cancelled),
+            image = load => image,
+        }
+    })
+}
+
+impl SlotState {
+    /// Create the future that loads the image of this slot again.
+    fn reload(&self) -> BoxFuture<'static, Result<Image, FetchError>> {
+        self.progress.reset();
+        cancellable(&self.cancel, (self.load)(self.progress.clone()))
+    }
+
+    /// Drop the image of this slot, so that it is loaded again the next time
+    /// it is requested.
+    fn unload(&self) {
+        let load = self.reload();
+
+        let mut source = self.source.lock().expect("poisoned");
+        *source = (source.0 + 1, Source::Lazy(LazyPromise::new(load)));
+    }
+
+    /// Record that the image of the given generation failed to load, and
+    /// schedule another attempt: after a backoff if the failure may be
+    /// temporary, or once the failure TTL is over otherwise.
+    fn failed(&self, generation: u64, e: FetchError) {
+        // the link is gone, so the image is never needed again
+        if e == FetchError::Cancelled {
+            return;
+        }
+
+        let config = &Config::global().image;
+        let mut retry = self.retry.lock().expect("poisoned");
+
+        // every request waiting on the failed image reports it
+        if retry.due.is_some_and(|(due, _)| due == generation) {
+            return;
+        }
+
+        let wait = if e.is_retryable() && retry.attempts < config.max_retries {
+            let backoff = config.retry_backoff << retry.attempts.min(16);
+            retry.attempts += 1;
+            backoff
+        } else {
+            let Some(ttl) = config.failure_ttl else {
+                return;
+            };
+            retry.attempts = 0;
+            ttl
+        };
+
+        retry.due = Some((generation, Instant::now() + Duration::from_secs(wait)));
+    }
+
+    /// Load the image again if it failed to load, and its backoff or failure
+    /// TTL is over.
+    fn retry_if_due(&self) {
+        let mut retry = self.retry.lock().expect("poisoned");
+
+        let Some((generation, at)) = retry.due else {
+            return;
+        };
+        if Instant::now() < at {
+            return;
+        }
+        retry.due = None;
+
+        let load = self.reload();
+
+        // the slot was unloaded or cancelled since the failure
+        let mut source = self.source.lock().expect("poisoned");
+        if source.0 != generation {
+            return;
+        }
+
+        tracing::info!("retrying image of slot {}", self.id);
+        *source = (source.0 + 1, Source::Lazy(LazyPromise::new(load)));
+    }
+}
+
+impl Drop for SlotState {
+    fn drop(&mut self) {
+        ImageBudget::global()
+            .lock()
+            .expect("poisoned")
+            .remove(self.id);
+    }
+}
+
+/// The total size in bytes of the images held by every `ImageSlot`.
+pub fn used_bytes() -> usize {
+    ImageBudget::global().lock().expect("poisoned").slots.used()
+}
+
+/// The loaded images of every `ImageSlot`, and their total size.
+#[derive(Default)]
+struct ImageBudget {
+    slots: ByteLru<u64, Tracked>,
+}
+
+/// A loaded image in the `ImageBudget`.
+struct Tracked {
+    /// The generation of the slot the image was loaded in.
+    generation: u64,
+    slot: Weak<SlotState>,
+}
+
+impl ImageBudget {
+    /// Get the global `ImageBudget`.
+    fn global() -> &'static Mutex<Self> {
+        static BUDGET: OnceLock<Mutex<ImageBudget>> = OnceLock::new();
+        BUDGET.get_or_init(Default::default)
+    }
+
+    /// Record an access to the image of a slot, accounting for its size if
+    /// it was just loaded. If the budget is exceeded, the slots whose images
+    /// should be unloaded are returned.
+    fn touch(
+        &mut self,
+        slot: &Arc<SlotState>,
+        generation: u64,
+        size: usize,
+    ) -> Vec<Arc<SlotState>> {
+        let tracked = self.slots.get(&slot.id);
+        if tracked.is_some_and(|tracked| tracked.generation == generation) {
+            return Vec::new();
+        }
+
+        // the slot was unloaded while this image was loading
+        if slot.source.lock().expect("poisoned").0 != generation {
+            return Vec::new();
+        }
+
+        let tracked = Tracked {
+            generation,
+            slot: Arc::downgrade(slot),
+        };
+        self.slots.insert(slot.id, tracked, size);
+
+        self.evict(slot.id)
+    }
+
+    /// Stop accounting for the image of a slot.
+    fn remove(&mut self, id: u64) {
+        self.slots.remove(&id);
+    }
+
+    /// Stop accounting for the least recently accessed images until the
+    /// budget is met, keeping the image of the given slot. The slots of the
+    /// images are returned, to be unloaded.
+    fn evict(&mut self, keep: u64) -> Vec<Arc<SlotState>> {
+        let Some(budget) = Config::global().image.memory_budget else {
+            return Vec::new();
+        };
+        let budget = usize::try_from(budget).unwrap_or(usize::MAX);
+
+        if self.slots.used() <= budget {
+            return Vec::new();
+        }
+
+        let evicted = self
+            .slots
+            .evict(budget, Some(&keep))
+            .into_iter()
+            .filter_map(|tracked| tracked.slot.upgrade())
+            .collect::<Vec<_>>();
+
+        tracing::info!(
+            "unloading {} images, {} bytes in use",
+            evicted.len(),
+            self.slots.used()
+        );
+
+        evicted
+    }
+}
@@ -0,0 +1,181 @@
+//! Recovering the addresses of clients behind reverse proxies.
+//!
+//! When the proxy is deployed behind nginx or Cloudflare, every connection
+//! comes from the reverse proxy. If it is trusted, the client's address is
+//! taken from the `Forwarded` or `X-Forwarded-For` header it adds instead,
+//! so that the access list and logs see real clients. Forwarding headers
+//! from untrusted connections are ignored, since anyone can send them.
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::access::Cidr;
+use crate::config::Config;
+
+/// The address of the client that made a request, as an extension of the
+/// request.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+tokio::task_local! {
+    static CURRENT: IpAddr;
+}
+
+/// Middleware that adds the `ClientIp` of a request to its extensions, and
+/// answers the request with it as the `current` client.
+pub async fn resolve(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let trusted = &Config::global().server.trusted_proxies;
+    let ip = client_ip(addr.ip(), req.headers(), trusted);
+    req.extensions_mut().insert(ClientIp(ip));
+
+    CURRENT.scope(ip, next.run(req)).await
+}
+
+/// The address of the client of the request being answered, if any.
+pub fn current() -> Option<IpAddr> {
+    CURRENT.try_with(|ip| *ip).ok()
+}
+
+/// Find the address of a client, by walking the forwarding headers back from
+/// the connection, for as long as the addresses are trusted proxies.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+
+    let mut client = peer.to_canonical();
+    if !is_trusted(client) {
+        return client;
+    }
+
+    for hop in forwarded_for(headers).into_iter().rev() {
+        // an unknown or obfuscated hop can't be followed any further
+        let Some(ip) = hop else {
+            break;
+        };
+
+        client = ip;
+        if !is_trusted(client) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Whether a request was made from the same host, and not forwarded by a
+/// reverse proxy on it.
+///
+/// This goes by the connection rather than the `ClientIp`, since a reverse
+/// proxy on the same host connects from a loopback address for every client
+/// unless it is trusted. Requests with forwarding headers are never local,
+/// whether they were sent by a reverse proxy or by a client spoofing one.
+pub fn is_local(peer: IpAddr, headers: &HeaderMap) -> bool {
+    let forwarded = [header::FORWARDED.as_str(), "x-forwarded-for", "x-real-ip"]
+        .into_iter()
+        .any(|name| headers.contains_key(name));
+
+    peer.to_canonical().is_loopback() && !forwarded
+}
+
+/// The addresses a request was forwarded for, from the client to the last
+/// proxy, going by the `Forwarded` header, or `X-Forwarded-For` if there is
+/// none. Hops that aren't addresses are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+
+    if headers.contains_key(header::FORWARDED) {
+        values(header::FORWARDED)
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect()
+    } else {
+        values(header::HeaderName::from_static("x-forwarded-for"))
+            .map(parse_node)
+            .collect()
+    }
+}
+
+/// Parse a forwarded node, like `192.0.2.1`, `"192.0.2.1:4711"` or
+/// `"[2001:db8::1]:4711"`, ignoring its port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    let ip = if let Some(rest) = node.strip_prefix('[') {
+        rest.split_once(']')?.0.parse().ok()?
+    } else if let Ok(ip) = node.parse() {
+        ip
+    } else {
+        let (addr, _port) = node.rsplit_once(':')?;
+        IpAddr::V4(addr.parse().ok()?)
+    };
+
+    Some(ip.to_canonical())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{client_ip, is_local};
+
+    #[test]
+    fn test_client_ip() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let headers = |name, value| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let proxy = ip("10.0.0.1");
+
+        let xff = headers("x-forwarded-for", "1.2.3.4, 5.6.7.8, 10.0.0.2");
+        assert_eq!(client_ip(proxy, &xff, &trusted), ip("5.6.7.8"));
+        // forwarding headers from untrusted connections are ignored
+        assert_eq!(client_ip(ip("9.9.9.9"), &xff, &trusted), ip("9.9.9.9"));
+
+        let forwarded = headers("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#);
+        assert_eq!(client_ip(proxy, &forwarded, &trusted), ip("2001:db8::1"));
+        let forwarded = headers("forwarded", "for=1.2.3.4:80, for=unknown");
+        assert_eq!(client_ip(proxy, &forwarded, &trusted), proxy);
+
+        assert_eq!(client_ip(proxy, &HeaderMap::new(), &trusted), proxy);
+    }
+
+    #[test]
+    fn test_is_local() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+
+        assert!(is_local(localhost, &headers));
+        assert!(is_local("::ffff:127.0.0.1".parse().unwrap(), &headers));
+        assert!(!is_local("1.2.3.4".parse().unwrap(), &headers));
+
+        // a reverse proxy on the same host
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4"));
+        assert!(!is_local(localhost, &headers));
+        // a client claiming to be local
+        headers.insert("x-forwarded-for", HeaderValue::from_static("127.0.0.1"));
+        assert!(!is_local(localhost, &headers));
+    }
+}
@@ -0,0 +1,186 @@
+//! Compression of text responses.
+//!
+//! `SearchMap`s, especially with tag columns, are large and compress very
+//! well. Text responses are compressed with brotli, or gzip, for clients that
+//! accept either, preferring brotli, which compresses them better; images are
+//! left untouched, since they are already compressed.
+//!
+//! Text responses are buffered to be compressed, so responses that are
+//! streamed, or bigger than a few MiB, are sent as they are.
+
+use std::io::Write;
+
+use axum::body::{Body, HttpBody};
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Responses smaller than this are sent as they are, since compressing them
+/// saves less than it costs.
+const MIN_LEN: usize = 1024;
+
+/// Responses bigger than this are sent as they are, so that compressing them
+/// doesn't buffer them in full.
+const MAX_LEN: usize = 4 * 1024 * 1024;
+
+/// The brotli quality text responses are compressed with. Higher qualities
+/// compress little better, for much more time.
+const BROTLI_QUALITY: u32 = 5;
+
+/// The base 2 logarithm of the brotli window size, which is the default.
+const BROTLI_WINDOW: u32 = 22;
+
+/// An encoding responses are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding`.
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut encoder =
+                    CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(body)?;
+                // the stream is finished once the encoder is taken apart
+                Ok(encoder.into_inner())
+            }
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Middleware that compresses text responses, if the client accepts brotli
+/// or gzip.
+pub async fn compress(req: Request, next: Next) -> Response {
+    let encoding = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|encodings| encodings.to_str().ok())
+        .and_then(negotiate);
+
+    let res = next.run(req).await;
+    let Some(encoding) = encoding else {
+        return res;
+    };
+    if !is_text(&res) || res.headers().contains_key(header::CONTENT_ENCODING) {
+        return res;
+    }
+
+    let len = res.body().size_hint().upper();
+    if len.is_none_or(|len| len > MAX_LEN as u64) {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    // the body is known to fit, and text bodies are already in memory
+    let Ok(body) = axum::body::to_bytes(body, MAX_LEN).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    if body.len() < MIN_LEN {
+        return Response::from_parts(parts, Body::from(body));
+    }
+
+    let Ok(compressed) = encoding.compress(&body) else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+
+    let headers = &mut parts.headers;
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    Response::from_parts(parts, Body::from(compressed))
+}
+
+/// Whether a response is text, like a `SearchMap` or its JSON document.
+fn is_text(res: &Response) -> bool {
+    res.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type.starts_with("text/") || content_type.starts_with("application/json")
+        })
+}
+
+/// The encoding to compress a response with, given the `Accept-Encoding`
+/// header of its request, preferring brotli.
+fn negotiate(encodings: &str) -> Option<Encoding> {
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .find(|encoding| accepts(encodings, encoding.name()))
+}
+
+/// Whether an `Accept-Encoding` header accepts an encoding, going by its name
+/// or a wildcard without a zero quality.
+fn accepts(encodings: &str, accepted: &str) -> bool {
+    encodings.split(',').any(|encoding| {
+        let mut params = encoding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+
+        (name == accepted || name == "*") && !refused
+    })
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_negotiate() {
+        use super::{negotiate, Encoding};
+
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br, gzip;q=0.8, deflate"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate("deflate"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("identity"), None);
+    }
+
+    #[test]
+    fn test_brotli_round_trip() {
+        use std::io::Read;
+
+        use super::Encoding;
+
+        let body = "a searchmap row\n".repeat(100);
+        let compressed = Encoding::Brotli.compress(body.as_bytes()).unwrap();
+        assert!(compressed.len() < body.len());
+
+        let mut decompressed = String::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+}
@@ -0,0 +1,851 @@
+//! Instance configuration.
+//!
+//! The configuration is read from `./config.json`, next to the `https_certs`
+//! directory. Every field has a default, so the file may be missing entirely,
+//! or only override the values an operator cares about.
+//!
+//! The file is read again on `SIGHUP`, or through the admin API, without
+//! restarting, so that blacklists, TTLs and limits can be changed without
+//! losing the links of the instance.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::access::Cidr;
+use crate::query::Rating;
+
+/// The configuration for this instance of the proxy.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct Config {
+    pub query: QueryConfig,
+    pub image: ImageConfig,
+    pub api: ApiConfig,
+    pub links: LinksConfig,
+    pub server: ServerConfig,
+    pub log: LogConfig,
+}
+
+/// Configuration for where logs are written.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct LogConfig {
+    /// Where logs are written. Logs go to stderr if this output isn't
+    /// available, like journald on a host without systemd.
+    pub output: LogOutput,
+    /// The file logs are appended to, for the `json_file` output.
+    pub path: PathBuf,
+    /// The size in bytes past which the log file is rotated.
+    pub max_size: Option<u64>,
+    /// How long in seconds logs are appended to the same file before it is
+    /// rotated.
+    pub rotate_interval: Option<u64>,
+    /// How many rotated log files are kept, as `path.1` (the newest) to
+    /// `path.N`. Older ones are deleted.
+    pub max_files: usize,
+}
+
+/// Where logs are written.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOutput {
+    /// The systemd journal, with the fields of each record as journal fields.
+    #[default]
+    Journald,
+    /// Standard error, as lines of text, for containers and development.
+    Stderr,
+    /// A file of JSON objects, one per record.
+    JsonFile,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            output: LogOutput::default(),
+            path: PathBuf::from("./").join("roli_proxy.log"),
+            max_size: Some(64 * 1024 * 1024),
+            rotate_interval: Some(24 * 60 * 60),
+            max_files: 7,
+        }
+    }
+}
+
+/// Configuration for parsing client search queries.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct QueryConfig {
+    /// Treat a trailing number in a query as its page number, if no explicit
+    /// `page:N` is given. Older clients paginate this way, so instances
+    /// serving old worlds may enable it, but it is off by default, since it
+    /// makes searches ending with a number tag, like `luigi 64`, search
+    /// another page instead.
+    pub trailing_page_number: bool,
+    /// Tags that are excluded from every search made through this instance,
+    /// on top of the core excludes that are always applied.
+    pub excludes: Vec<String>,
+    /// The maximum number of tags a query may contain, including the tags of
+    /// the client blacklist it selects. Queries are also limited to the 40
+    /// tags e621 allows a regular account, including the excludes added by
+    /// the instance, so configured excludes may lower this.
+    pub max_tags: usize,
+    /// Allow queries without any tags, which return the latest posts.
+    pub allow_empty: bool,
+    /// The minimum score of posts returned by searches that don't filter by
+    /// score themselves.
+    pub min_score: Option<i64>,
+    /// The highest rating of posts returned by searches, regardless of what
+    /// the client asks for.
+    pub max_rating: Option<Rating>,
+    /// Include animated posts in searches that don't ask for them with
+    /// `anim:yes` or `anim:no`.
+    pub allow_animated: bool,
+    /// Replace aliased tags in queries with their canonical tag, looking the
+    /// aliases up from e621.
+    pub resolve_aliases: bool,
+    /// Named blacklists that clients can enable with `!name`. Each one maps
+    /// to a list of tags that are excluded from the search.
+    pub presets: HashMap<String, Vec<String>>,
+    /// Named query strings that clients can use with `@name`, e.g. a
+    /// `canines` macro of `canine -feral score:>=50`.
+    pub macros: HashMap<String, String>,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        let presets = [
+            ("nogore", &["gore", "death", "torture"][..]),
+            ("noferal", &["feral"]),
+            ("sfwish", &["rating:q", "rating:e"]),
+        ];
+
+        Self {
+            trailing_page_number: false,
+            excludes: Vec::new(),
+            max_tags: 36,
+            allow_empty: true,
+            min_score: None,
+            max_rating: None,
+            allow_animated: false,
+            resolve_aliases: true,
+            presets: presets
+                .into_iter()
+                .map(|(name, tags)| {
+                    let tags = tags.iter().map(|&tag| tag.to_owned()).collect();
+                    (name.to_owned(), tags)
+                })
+                .collect(),
+            macros: HashMap::new(),
+        }
+    }
+}
+
+/// Configuration for requests to the e621 API.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ApiConfig {
+    /// How long search responses are cached for, in seconds, so that repeated
+    /// searches don't reach e621. Caching is disabled if this is 0.
+    pub cache_ttl: u64,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self { cache_ttl: 60 }
+    }
+}
+
+/// Configuration for how the proxy answers HTTP requests.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ServerConfig {
+    /// Answer errors, like expired links, bad queries and e621 failures, with
+    /// a matching HTTP status, instead of a `200` like the original proxy.
+    /// Generic HTTP clients and caches need this, but old VRChat worlds may
+    /// not show the error message without a `200`.
+    pub strict_status: bool,
+    /// The maximum number of searches that query e621 at once. Shared
+    /// searches don't count, since they don't query e621.
+    pub max_searches: Option<usize>,
+    /// How long in seconds a search waits for another to finish, once the
+    /// maximum is reached, before the client is told to try again later.
+    pub search_queue_timeout: u64,
+    /// How long in seconds a request to `/link/` may take, before it is
+    /// answered with a timeout error. Image links answer on their own after
+    /// the image load timeout, so this should be longer.
+    pub link_timeout: Option<u64>,
+    /// How long in seconds a request to any other endpoint, like a search,
+    /// may take, before it is answered with a timeout error.
+    pub search_timeout: Option<u64>,
+    /// A file listing the address ranges allowed and denied to use the
+    /// proxy. It is read again whenever it changes. Every address is allowed
+    /// if this is unset. See the `access` module for its format.
+    pub access_list: Option<PathBuf>,
+    /// The TLS versions, cipher suites and client certificates the listener
+    /// accepts.
+    pub tls: TlsConfig,
+    /// Also answer HTTP/3 over QUIC, on UDP port 443, and advertise it to
+    /// clients with an `Alt-Svc` header. QUIC only runs over TLS 1.3, and
+    /// needs `TLS13_AES_128_GCM_SHA256` among the cipher suites.
+    pub http3: bool,
+    /// The address ranges of reverse proxies in front of the instance, like
+    /// nginx or Cloudflare. Connections from them are attributed to the
+    /// client named by their `Forwarded` or `X-Forwarded-For` header.
+    pub trusted_proxies: Vec<Cidr>,
+    /// How every request is logged once it is answered. Requests aren't
+    /// logged if this is unset.
+    pub access_log: Option<AccessLogFormat>,
+    /// An address to serve `/metrics` on over plain HTTP, like
+    /// `127.0.0.1:9100`, instead of the public listener, so that only
+    /// internal monitoring can read it.
+    pub metrics_addr: Option<SocketAddr>,
+    /// The OTLP/HTTP endpoint traces are exported to, like
+    /// `http://localhost:4318/v1/traces`. Requests aren't traced if this is
+    /// unset.
+    pub otlp_endpoint: Option<String>,
+    /// How long work may take, in milliseconds, before it is logged as slow.
+    pub slow: SlowThresholds,
+    /// Who may use the `/admin/` API.
+    pub admin: AdminConfig,
+    /// Answer new searches with a message asking clients to try again later,
+    /// while still serving existing links, e.g. during an upgrade.
+    pub maintenance: bool,
+    /// How much each address may use the proxy per day.
+    pub quota: QuotaConfig,
+    /// When addresses are banned automatically for abuse.
+    pub abuse: AbuseConfig,
+    /// Where the search terms banned through the admin API are saved, so
+    /// that they survive restarts.
+    pub banned_terms: PathBuf,
+    /// The tokens of the worlds and relays using the instance.
+    pub clients: ClientsConfig,
+}
+
+/// Thresholds past which an address is banned automatically, for a while.
+/// Nothing is banned for unset thresholds, which is the default.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct AbuseConfig {
+    /// The number of different searches an address may make in a minute.
+    pub max_unique_queries: Option<usize>,
+    /// The number of links that never existed an address may fetch in a
+    /// minute, before it is assumed to be guessing link ids.
+    pub max_missing_links: Option<u32>,
+    /// How long the first, second and later bans of an address last, in
+    /// seconds. Past the end of the list, the last duration is used.
+    pub ban_durations: Vec<u64>,
+}
+
+impl Default for AbuseConfig {
+    fn default() -> Self {
+        Self {
+            max_unique_queries: None,
+            max_missing_links: None,
+            ban_durations: vec![10 * 60, 60 * 60, 24 * 60 * 60],
+        }
+    }
+}
+
+/// Access tokens of the worlds and relays using the instance, so that they
+/// can be told apart and throttled. Searches don't need a token by default.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ClientsConfig {
+    /// Refuse searches without a registered token.
+    pub required: bool,
+    /// The registered tokens, by the name of their client.
+    pub tokens: HashMap<String, ClientToken>,
+}
+
+/// The token of a single world or relay.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ClientToken {
+    /// The token the client sends, as a `/t/token/` prefix of the path, or in
+    /// the `X-Proxy-Token` header.
+    pub token: Secret,
+    /// The number of searches the client may make per minute. Unlimited if
+    /// unset.
+    pub searches_per_minute: Option<u32>,
+}
+
+/// Daily quotas per client address, which reset at midnight UTC. Addresses
+/// are unlimited by default.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct QuotaConfig {
+    /// The number of searches an address may make per day.
+    pub daily_searches: Option<u64>,
+    /// The number of bytes that may be served to an address per day.
+    pub daily_bytes: Option<u64>,
+    /// Where usage is saved, so that it survives restarts.
+    pub path: PathBuf,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            daily_searches: None,
+            daily_bytes: None,
+            path: PathBuf::from("./").join("quotas.json"),
+        }
+    }
+}
+
+/// Configuration for the `/admin/` API, which lists and purges links,
+/// flushes caches and shows the configuration. It is disabled unless a token
+/// is set.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct AdminConfig {
+    /// The bearer token requests to the API must be authorized with.
+    pub token: Option<Secret>,
+    /// Only answer clients on the same host. Requests forwarded by a reverse
+    /// proxy are refused, even one on the same host.
+    pub local_only: bool,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            local_only: true,
+        }
+    }
+}
+
+/// How long work may take, in milliseconds, before it is logged as a warning
+/// with what it was working on. Nothing is logged for unset thresholds.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct SlowThresholds {
+    /// Searches, from querying e621 to creating their links.
+    pub search: Option<u64>,
+    /// Downloading and stitching the preview grid of a search.
+    pub preview: Option<u64>,
+    /// Downloading a single image.
+    pub image: Option<u64>,
+}
+
+impl Default for SlowThresholds {
+    fn default() -> Self {
+        Self {
+            search: Some(5000),
+            preview: Some(10_000),
+            image: Some(5000),
+        }
+    }
+}
+
+/// The format of the access log.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// A short message, with the details of the request as journald fields.
+    Journald,
+    /// A JSON object per request, as the message.
+    Json,
+}
+
+/// Configuration for the TLS policy of the listener. Any client that
+/// supports TLS 1.2 or 1.3 may connect by default.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct TlsConfig {
+    /// The oldest TLS version clients may connect with.
+    pub min_version: TlsVersion,
+    /// The names of the cipher suites clients may connect with, like
+    /// `TLS13_AES_256_GCM_SHA384`. rustls' defaults are used if this is
+    /// empty.
+    pub cipher_suites: Vec<String>,
+    /// A PEM file of CA certificates. If set, clients must present a
+    /// certificate signed by one of them, so that only known relays can
+    /// connect to a private instance.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// A TLS protocol version.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            strict_status: false,
+            max_searches: Some(64),
+            search_queue_timeout: 5,
+            link_timeout: Some(30),
+            search_timeout: Some(60),
+            access_list: None,
+            tls: TlsConfig::default(),
+            http3: false,
+            trusted_proxies: Vec::new(),
+            access_log: None,
+            metrics_addr: None,
+            otlp_endpoint: None,
+            slow: SlowThresholds::default(),
+            admin: AdminConfig::default(),
+            maintenance: false,
+            quota: QuotaConfig::default(),
+            abuse: AbuseConfig::default(),
+            banned_terms: PathBuf::from("./").join("banned_terms.json"),
+            clients: ClientsConfig::default(),
+        }
+    }
+}
+
+/// Configuration for the links handed out to clients.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct LinksConfig {
+    /// The maximum number of links held at once. Past this, the least
+    /// recently used searches and images are evicted.
+    pub max_entries: usize,
+    /// How long the links of a search live without being refreshed, in
+    /// seconds. Refreshing a search also refreshes its posts.
+    pub search_ttl: u64,
+    /// How long the links of a post live without being refreshed, in seconds.
+    pub post_ttl: u64,
+    /// The maximum time added at random to the lifetimes of links, in
+    /// seconds, so that links created together aren't all torn down at once.
+    pub teardown_jitter: u64,
+    /// Where links are saved, so that they survive restarts. Links aren't
+    /// saved if this is unset.
+    pub snapshot: Option<PathBuf>,
+    /// How often links are saved, in seconds.
+    pub snapshot_interval: u64,
+    /// The URL of a Redis instance that links are shared through, so that
+    /// several instances of the proxy can serve the same links. Links are
+    /// only held by the instance that created them if this is unset.
+    pub redis: Option<Secret>,
+    /// Which kinds of links are refreshed whenever they are fetched.
+    pub sliding_expiry: SlidingExpiry,
+    /// The maximum number of times a search or post can be refreshed.
+    pub max_refreshes: Option<u32>,
+    /// The maximum time a search or post can live, in seconds, however often
+    /// it is refreshed.
+    pub max_lifetime: Option<u64>,
+    /// A key to sign link ids with, so that `/link/` only accepts ids handed
+    /// out by this instance. Instances sharing links through Redis must use
+    /// the same key. Link ids are plain numbers if this is unset.
+    pub signing_key: Option<Secret>,
+}
+
+/// Which kinds of links are refreshed whenever they are fetched, as if their
+/// refresh link had been called. Links are only refreshed through their
+/// refresh links by default.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct SlidingExpiry {
+    /// `SearchMap`s and their chunks, which refresh their whole search.
+    pub search_maps: bool,
+    /// Preview grids, which refresh their whole search.
+    pub previews: bool,
+    /// Post images, which refresh their post.
+    pub images: bool,
+    /// Video redirects, which refresh their post.
+    pub videos: bool,
+}
+
+impl Default for LinksConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            search_ttl: 600,
+            post_ttl: 1200,
+            teardown_jitter: 30,
+            snapshot: None,
+            snapshot_interval: 60,
+            redis: None,
+            sliding_expiry: SlidingExpiry::default(),
+            max_refreshes: None,
+            max_lifetime: None,
+            signing_key: None,
+        }
+    }
+}
+
+/// Configuration for the image pipeline.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct ImageConfig {
+    /// Serve video posts as their first frame, extracted with `ffmpeg`.
+    pub video_frames: bool,
+    /// The `ffmpeg` executable used to extract video frames.
+    pub ffmpeg: String,
+    /// How GIF posts are served.
+    pub gif: GifMode,
+    /// The maximum size in bytes of a post's original file, for it to be
+    /// downloaded by the proxy.
+    pub max_file_size: Option<u64>,
+    /// What to do with posts over the maximum file size.
+    pub oversized: Oversized,
+    /// The maximum size in bytes of the images held in memory by links. Past
+    /// this, the least recently requested images are dropped, and downloaded
+    /// again if they are requested later.
+    pub memory_budget: Option<u64>,
+    /// The maximum size in bytes of the downloaded images cached in memory,
+    /// shared by every link that serves the same file. Set to 0 to disable
+    /// the cache.
+    pub cache_size: u64,
+    /// How long in seconds an image URL that the host answered with an error
+    /// status for, like a 404 for a taken down post, fails without a request.
+    /// Set to 0 to disable.
+    pub failed_url_ttl: u64,
+    /// How long in seconds a request for an image waits for it to load.
+    /// Past this, the placeholder is served and the client is told to try
+    /// again, while the image keeps loading.
+    pub load_timeout: Option<u64>,
+    /// How many times an image that failed to load, in a way that may be
+    /// temporary, is loaded again when it is next requested.
+    pub max_retries: u32,
+    /// How long in seconds after a failure the image is loaded again. The
+    /// wait doubles with every retry.
+    pub retry_backoff: u64,
+    /// How long in seconds a failure is kept once it is past the last retry,
+    /// or if it isn't temporary, before the image is loaded again. If unset,
+    /// such failures are kept until the link is torn down.
+    pub failure_ttl: Option<u64>,
+    /// The format the preview grid of a search is encoded in.
+    pub preview_format: PreviewFormat,
+    /// The quality of JPEG preview grids, from 1 to 100.
+    pub preview_quality: u8,
+    /// How many preview images may be downloaded at once, across every
+    /// search, to go easy on e621's static hosts.
+    pub preview_downloads: usize,
+    /// Images served in place of the images that can't be served.
+    pub placeholders: PlaceholderConfig,
+    /// The maximum width and height in pixels of the images served by `Image`
+    /// links. Larger images are downscaled to fit, since VRChat rejects
+    /// textures over 2048 pixels.
+    pub max_dimension: Option<u32>,
+    /// Re-encode the JPEG and PNG images served by `Image` links, so that
+    /// their metadata, like EXIF tags, isn't forwarded to clients. This costs
+    /// a decode and an encode per image.
+    pub strip_metadata: bool,
+    /// Re-encode the images served by `Image` links that VRChat may not load,
+    /// like WebP images, progressive JPEGs and 16-bit or interlaced PNGs, as
+    /// baseline JPEGs or 8-bit PNGs.
+    pub transcode: bool,
+    /// The maximum width and height in pixels of the Quest variants of post
+    /// images, which are always JPEGs.
+    pub quest_dimension: u32,
+    /// Stream the original files of posts to clients as they download from
+    /// e621, instead of waiting for the whole file. Streamed files are served
+    /// as they are, so they aren't downscaled, transcoded or stripped of
+    /// their metadata.
+    pub stream_originals: bool,
+}
+
+/// Image files served in place of the images that can't be served, read once
+/// at startup. The proxy's own placeholder is served for any that is unset.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, rename_all = "snake_case")]
+pub struct PlaceholderConfig {
+    /// Served for any of the cases below that has no image of its own.
+    pub default: Option<PathBuf>,
+    /// Served while an image is still loading.
+    pub loading: Option<PathBuf>,
+    /// Served when an image failed to load.
+    pub failed: Option<PathBuf>,
+    /// Served when the link of an image was torn down while it loaded.
+    pub expired: Option<PathBuf>,
+}
+
+/// The format of the preview grid of a search.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewFormat {
+    /// Lossless, and supported by every client.
+    #[default]
+    Png,
+    /// Lossy, with the configured quality. The grid has no transparency, so
+    /// empty tiles are black.
+    Jpeg,
+    /// Lossless, but smaller than PNG. Clients must support WebP.
+    Webp,
+}
+
+/// What to do with posts whose original file is over the maximum size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Oversized {
+    /// Remove them from search results.
+    Drop,
+    /// Serve their still sample image, instead of anything derived from the
+    /// original file.
+    #[default]
+    Sample,
+}
+
+/// How GIF posts are served by their `Image` link.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GifMode {
+    /// Serve the GIF untouched. VRChat only shows its first frame.
+    #[default]
+    Passthrough,
+    /// Decode the first frame of the GIF, and serve it as a PNG.
+    FirstFrame,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            video_frames: true,
+            ffmpeg: "ffmpeg".into(),
+            gif: GifMode::default(),
+            max_file_size: None,
+            oversized: Oversized::default(),
+            memory_budget: Some(512 * 1024 * 1024),
+            cache_size: 256 * 1024 * 1024,
+            failed_url_ttl: 120,
+            load_timeout: Some(20),
+            max_retries: 3,
+            retry_backoff: 5,
+            failure_ttl: Some(300),
+            preview_format: PreviewFormat::default(),
+            preview_quality: 85,
+            preview_downloads: 8,
+            placeholders: PlaceholderConfig::default(),
+            max_dimension: Some(2048),
+            strip_metadata: true,
+            transcode: true,
+            quest_dimension: 1024,
+            stream_originals: false,
+        }
+    }
+}
+
+/// A configured password or token, which is left out of the `Debug` output
+/// of the configuration, since the configuration is shown through the admin
+/// API.
+#[derive(Clone, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// The secret itself.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// The current configuration, once it is loaded.
+///
+/// Configurations replaced by a reload are leaked, since references to them
+/// may be held by anything that read them, like the links they configured.
+/// They are small, and reloads are rare.
+static CURRENT: RwLock<Option<&'static Config>> = RwLock::new(None);
+
+impl Config {
+    /// Get the current configuration, loading it the first time.
+    pub fn global() -> &'static Self {
+        if let Some(config) = *CURRENT.read().expect("poisoned") {
+            return config;
+        }
+
+        let mut current = CURRENT.write().expect("poisoned");
+        current.get_or_insert_with(|| Box::leak(Box::new(Self::load())))
+    }
+
+    /// Read the configuration from disk again, replacing the current one.
+    ///
+    /// Everything that reads the configuration from now on, like new
+    /// searches, sees the new one, while existing links keep the TTLs and
+    /// limits they were created with. If the file is invalid, the current
+    /// configuration is kept. Settings only read at startup, like the TLS
+    /// policy, are logged if they changed, since they need a restart.
+    pub fn reload() -> io::Result<()> {
+        let config = Self::read()?;
+
+        // nothing is logged while the lock is held, since loggers may read
+        // the configuration
+        let changes = {
+            let mut current = CURRENT.write().expect("poisoned");
+            let changes = current.map(|old| old.restart_only_changes(&config));
+            *current = Some(Box::leak(Box::new(config)));
+            changes.unwrap_or_default()
+        };
+
+        for setting in changes {
+            tracing::warn!("{setting} changed, but only takes effect after a restart");
+        }
+        tracing::info!("reloaded config");
+        Ok(())
+    }
+
+    /// Spawn a task that reloads the configuration whenever the process
+    /// receives `SIGHUP`.
+    #[cfg(unix)]
+    pub fn spawn_reloader() -> io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                if let Err(e) = Self::reload() {
+                    tracing::error!("failed to reload config, keeping the current one: {e}");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Signals aren't available, so the configuration is only reloaded
+    /// through the admin API.
+    #[cfg(not(unix))]
+    pub fn spawn_reloader() -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The path of the configuration file.
+    fn path() -> PathBuf {
+        PathBuf::from("./").join("config.json")
+    }
+
+    /// Load the configuration from disk, falling back to the defaults.
+    fn load() -> Self {
+        Self::read().unwrap_or_else(|e| {
+            tracing::error!(
+                "invalid config at {}, using defaults: {e}",
+                Self::path().display()
+            );
+            Self::default()
+        })
+    }
+
+    /// Read the configuration from disk, using the defaults if there is no
+    /// configuration file.
+    fn read() -> io::Result<Self> {
+        let path = Self::path();
+
+        let file = match std::fs::read_to_string(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                tracing::info!("no config at {}, using defaults", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(serde_json::from_str(&file)?)
+    }
+
+    /// The settings that are only read at startup, and differ in another
+    /// configuration.
+    fn restart_only_changes(&self, other: &Self) -> Vec<&'static str> {
+        let differ = |a: &dyn fmt::Debug, b: &dyn fmt::Debug| format!("{a:?}") != format!("{b:?}");
+
+        let settings = [
+            ("server.tls", differ(&self.server.tls, &other.server.tls)),
+            ("server.http3", self.server.http3 != other.server.http3),
+            (
+                "server.access_list",
+                self.server.access_list != other.server.access_list,
+            ),
+            (
+                "server.metrics_addr",
+                self.server.metrics_addr != other.server.metrics_addr,
+            ),
+            (
+                "server.otlp_endpoint",
+                self.server.otlp_endpoint != other.server.otlp_endpoint,
+            ),
+            (
+                "server.admin.token",
+                self.server.admin.token.is_some() != other.server.admin.token.is_some(),
+            ),
+            (
+                "links.redis",
+                self.links.redis.as_ref().map(Secret::expose)
+                    != other.links.redis.as_ref().map(Secret::expose),
+            ),
+            (
+                "links.signing_key",
+                self.links.signing_key.as_ref().map(Secret::expose)
+                    != other.links.signing_key.as_ref().map(Secret::expose),
+            ),
+            (
+                "links.snapshot",
+                self.links.snapshot != other.links.snapshot,
+            ),
+            (
+                "links.snapshot_interval",
+                self.links.snapshot_interval != other.links.snapshot_interval,
+            ),
+            (
+                "server.quota.path",
+                self.server.quota.path != other.server.quota.path,
+            ),
+            (
+                "server.banned_terms",
+                self.server.banned_terms != other.server.banned_terms,
+            ),
+            (
+                "image.placeholders",
+                differ(&self.image.placeholders, &other.image.placeholders),
+            ),
+            (
+                "image.preview_downloads",
+                self.image.preview_downloads != other.image.preview_downloads,
+            ),
+            ("log.output", differ(&self.log.output, &other.log.output)),
+            ("log.path", self.log.path != other.log.path),
+        ];
+
+        settings
+            .into_iter()
+            .filter_map(|(setting, changed)| changed.then_some(setting))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::{Layer, Registry};
+
+    use super::Config;
+
+    /// An output that reads the configuration, like the JSON file output.
+    struct ConfigReader;
+
+    impl<S: Subscriber> Layer<S> for ConfigReader {
+        fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+            Config::global();
+        }
+    }
+
+    #[test]
+    fn test_reload_logs_without_lock() {
+        let subscriber = Registry::default().with(ConfigReader);
+        tracing::subscriber::set_global_default(subscriber).expect("subscriber already installed");
+        Config::global();
+
+        // a deadlock would never send
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || tx.send(Config::reload().is_ok()));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+    }
+}
@@ -0,0 +1,123 @@
+//! Minimal DText handling for user-written e621 text.
+//!
+//! DText is the markup language e621 uses for comments, descriptions and wiki
+//! pages. VRChat has no way to render it, so the proxy strips it down to plain
+//! text before handing it to clients.
+
+/// Strip DText markup from a string, leaving plain text on a single line.
+///
+/// Quoted text (`[quote]...[/quote]`) is dropped entirely, since it repeats
+/// other comments. Links keep their label, and any other tags are removed
+/// while keeping their contents.
+pub fn strip(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    let mut quote_depth = 0_usize;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '[' {
+            if let Some((tag, after)) = split_tag(rest) {
+                match tag.to_ascii_lowercase().as_str() {
+                    "quote" => quote_depth += 1,
+                    "/quote" => quote_depth = quote_depth.saturating_sub(1),
+                    _ => (),
+                }
+                rest = after;
+                continue;
+            }
+        }
+
+        if quote_depth > 0 {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        if let Some((label, after)) = split_wiki_link(rest) {
+            out.push_str(label);
+            rest = after;
+        } else if let Some((label, after)) = split_link(rest) {
+            out.push_str(label);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{{") {
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            rest = after;
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    out.lines()
+        .map(strip_header)
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Split a `[tag]`, `[/tag]` or `[tag=value]` off the front of a string.
+///
+/// Returns the tag name (with its leading `/`, if any) and the rest of the
+/// string. `[[` is not a tag, but the start of a wiki link.
+fn split_tag(s: &str) -> Option<(&str, &str)> {
+    let inner = s.strip_prefix('[')?;
+    let end = inner.find(']')?;
+    let tag = &inner[..end];
+    let name = tag.split('=').next().unwrap_or_default();
+
+    let is_tag = !name.is_empty()
+        && name
+            .trim_start_matches('/')
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric());
+
+    is_tag.then(|| (name, &inner[end + 1..]))
+}
+
+/// Split a `[[page]]` or `[[page|label]]` wiki link off the front of a
+/// string, returning the text that should be displayed for it.
+fn split_wiki_link(s: &str) -> Option<(&str, &str)> {
+    let inner = s.strip_prefix("[[")?;
+    let end = inner.find("]]")?;
+    let link = &inner[..end];
+    let label = link.rsplit('|').next().unwrap_or(link);
+
+    Some((label, &inner[end + 2..]))
+}
+
+/// Split a `"label":url` link off the front of a string, returning its label.
+fn split_link(s: &str) -> Option<(&str, &str)> {
+    let inner = s.strip_prefix('"')?;
+    let end = inner.find('"')?;
+    let label = &inner[..end];
+    let after = inner[end + 1..].strip_prefix(':')?;
+
+    let url_len = match after.strip_prefix('[') {
+        Some(bracketed) => bracketed.find(']')? + 2,
+        None => after.find(char::is_whitespace).unwrap_or(after.len()),
+    };
+
+    (url_len > 0).then(|| (label, &after[url_len..]))
+}
+
+/// Strip a `h1.` through `h6.` header marker from the start of a line.
+fn strip_header(line: &str) -> &str {
+    let trimmed = line.trim_start();
+
+    match trimmed.as_bytes() {
+        [b'h', b'1'..=b'6', b'.', ..] => &trimmed[3..],
+        _ => line,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_strip() {
+        let body = "[quote]\"someone\":/users/1 said:\nbad take[/quote]\n\
+                    h4.[b]Good[/b] take, see [[canine|dogs]] and \"this\":https://e621.net/posts/1 \
+                    or {{ fox }}";
+
+        assert_eq!(super::strip(body), "Good take, see dogs and this or fox");
+    }
+}
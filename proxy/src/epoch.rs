@@ -0,0 +1,62 @@
+//! Time in whole periods since the unix epoch.
+//!
+//! Rate limits and quotas count usage per minute or per day, and start over
+//! when the period changes, at the same moment for every client.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The length of a minute, in seconds.
+pub const MINUTE: u64 = 60;
+
+/// The length of a day, in seconds.
+pub const DAY: u64 = 24 * 60 * 60;
+
+/// The time since the epoch.
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// The number of periods of the given length, in seconds, since the epoch,
+/// and the time left until the next one, at a given time since the epoch.
+fn split(since: Duration, period: u64) -> (u64, Duration) {
+    let secs = since.as_secs();
+    (secs / period, Duration::from_secs(period - secs % period))
+}
+
+/// The current minute, in minutes since the epoch.
+pub fn minute() -> u64 {
+    split(now(), MINUTE).0
+}
+
+/// The current day, in days since the epoch. Days start at midnight UTC.
+pub fn day() -> u64 {
+    split(now(), DAY).0
+}
+
+/// The time left until the current period of the given length, in seconds,
+/// ends.
+pub fn until_next(period: u64) -> Duration {
+    split(now(), period).1
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{split, DAY, MINUTE};
+
+    #[test]
+    fn test_split() {
+        let at = Duration::from_secs(3 * DAY + 90);
+
+        assert_eq!(
+            split(at, MINUTE),
+            (3 * 24 * 60 + 1, Duration::from_secs(30))
+        );
+        assert_eq!(split(at, DAY), (3, Duration::from_secs(DAY - 90)));
+        // a new period has all of it left
+        assert_eq!(split(Duration::ZERO, MINUTE), (0, Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,139 @@
+//! Deep health checks, for load balancers.
+//!
+//! Unlike `/status`, `/healthz` checks that the proxy can actually serve
+//! searches: that e621 answers, that the TLS certificate isn't about to
+//! expire, and that the runtime still runs tasks and can read the `LinkMap`.
+//! The instance is `degraded`, but still answered with a `200`, if e621 is
+//! unreachable or the certificate expires soon, since links keep working. It
+//! is `unhealthy`, with a `503`, if the runtime is stuck or the certificate
+//! has expired.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::http::StatusCode;
+use serde_json::{json, Value};
+
+use crate::links::LinkMap;
+use crate::{api, tls};
+
+/// How long the result of probing e621 is reused, so that health checks
+/// don't become a load on e621.
+const PROBE_TTL: Duration = Duration::from_secs(30);
+
+/// How long the runtime may take to run a task before it is unhealthy.
+const RUNTIME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long before the certificate expires the instance is degraded.
+const CERT_WARNING: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// The health of the instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Health {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+impl Health {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// Check the health of the instance, returning the status to answer with and
+/// the results of each check.
+pub async fn check() -> (StatusCode, Value) {
+    let (e621, e621_health) = check_e621().await;
+    let (cert, cert_health) = check_cert();
+    let (runtime, runtime_health) = check_runtime().await;
+
+    let health = e621_health.max(cert_health).max(runtime_health);
+    let status = match health {
+        Health::Unhealthy => StatusCode::SERVICE_UNAVAILABLE,
+        Health::Healthy | Health::Degraded => StatusCode::OK,
+    };
+
+    let document = json!({
+        "status": health.name(),
+        "checks": {
+            "e621": e621,
+            "tls": cert,
+            "runtime": runtime,
+        },
+    });
+
+    (status, document)
+}
+
+/// Check that e621 answers, reusing the last probe for a while.
+async fn check_e621() -> (Value, Health) {
+    static LAST_PROBE: Mutex<Option<(Instant, Result<(), String>)>> = Mutex::new(None);
+
+    let last = LAST_PROBE.lock().expect("poisoned").clone();
+    let result = match last {
+        Some((at, result)) if at.elapsed() < PROBE_TTL => result,
+        _ => {
+            let result = api::probe().await.map_err(|e| e.to_string());
+            *LAST_PROBE.lock().expect("poisoned") = Some((Instant::now(), result.clone()));
+            result
+        }
+    };
+
+    match result {
+        Ok(()) => (json!({ "ok": true }), Health::Healthy),
+        Err(e) => (json!({ "ok": false, "error": e }), Health::Degraded),
+    }
+}
+
+/// Check how long the TLS certificate has left.
+fn check_cert() -> (Value, Health) {
+    let Some(expiry) = tls::expiry() else {
+        return (
+            json!({ "ok": true, "expires_in_secs": null }),
+            Health::Healthy,
+        );
+    };
+
+    let Ok(left) = expiry.duration_since(SystemTime::now()) else {
+        return (
+            json!({ "ok": false, "expires_in_secs": 0 }),
+            Health::Unhealthy,
+        );
+    };
+
+    let health = if left < CERT_WARNING {
+        Health::Degraded
+    } else {
+        Health::Healthy
+    };
+
+    let document = json!({
+        "ok": health == Health::Healthy,
+        "expires_in_secs": left.as_secs(),
+    });
+    (document, health)
+}
+
+/// Check that the runtime runs a new task, which reads the `LinkMap`, in
+/// time.
+async fn check_runtime() -> (Value, Health) {
+    let start = Instant::now();
+    let task = tokio::spawn(async { LinkMap::global().count() });
+
+    match tokio::time::timeout(RUNTIME_TIMEOUT, task).await {
+        Ok(Ok(links)) => {
+            let document = json!({
+                "ok": true,
+                "latency_ms": start.elapsed().as_millis() as u64,
+                "links": links,
+            });
+            (document, Health::Healthy)
+        }
+        _ => (json!({ "ok": false }), Health::Unhealthy),
+    }
+}
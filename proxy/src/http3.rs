@@ -0,0 +1,168 @@
+//! The HTTP/3 listener.
+//!
+//! Instances may also answer HTTP/3 over QUIC, on the UDP port of the TLS
+//! listener, with the same certificate and routes. QUIC recovers from lost
+//! packets without stalling every transfer on the connection, which helps
+//! clients on flaky networks download large images. Clients learn about the
+//! listener from the `Alt-Svc` header of every response, and keep using
+//! HTTP/1.1 or HTTP/2 if they can't reach it.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::extract::ConnectInfo;
+use axum::http::{self, header, HeaderValue, Request};
+use axum::response::Response;
+use axum::{BoxError, Router};
+use bytes::Buf;
+use futures::StreamExt;
+use h3::error::Code;
+use h3::server::RequestStream;
+use quinn::crypto::rustls::QuicServerConfig;
+use tower::ServiceExt;
+
+/// The `Alt-Svc` header advertising the listener, once it is listening.
+static ALT_SVC: OnceLock<HeaderValue> = OnceLock::new();
+
+/// How long clients may remember the listener, in seconds.
+const ALT_SVC_MAX_AGE: u64 = 24 * 60 * 60;
+
+/// Listen for HTTP/3 connections on the given address, answering them with
+/// the given app. The listener stops once the returned endpoint is closed.
+pub fn listen(
+    app: Router,
+    addr: SocketAddr,
+    tls: quic_rustls::ServerConfig,
+) -> io::Result<quinn::Endpoint> {
+    let crypto = QuicServerConfig::try_from(tls).map_err(io::Error::other)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let endpoint = quinn::Endpoint::server(config, addr)?;
+
+    let alt_svc = format!("h3=\":{}\"; ma={ALT_SVC_MAX_AGE}", addr.port());
+    let _ = ALT_SVC.set(HeaderValue::from_str(&alt_svc).expect("valid header"));
+
+    let accepting = endpoint.clone();
+    tokio::spawn(async move {
+        while let Some(incoming) = accepting.accept().await {
+            tokio::spawn(serve_connection(app.clone(), incoming));
+        }
+    });
+
+    Ok(endpoint)
+}
+
+/// Stop accepting connections, and close the open ones once they are done,
+/// or once the given time has passed.
+pub async fn close(endpoint: quinn::Endpoint, grace: Duration) {
+    endpoint.set_server_config(None);
+    let _ = tokio::time::timeout(grace, endpoint.wait_idle()).await;
+
+    let code = quinn::VarInt::from_u64(Code::H3_NO_ERROR.value()).expect("valid code");
+    endpoint.close(code, b"shutting down");
+}
+
+/// Middleware that advertises the listener to clients, once it is listening.
+pub async fn alt_svc(mut res: Response) -> Response {
+    if let Some(alt_svc) = ALT_SVC.get() {
+        res.headers_mut().insert(header::ALT_SVC, alt_svc.clone());
+    }
+
+    res
+}
+
+/// Answer the requests of a connection, each in a task of its own.
+async fn serve_connection(app: Router, incoming: quinn::Incoming) {
+    let conn = match incoming.await {
+        Ok(conn) => conn,
+        Err(e) => {
+            tracing::info!("http/3 handshake failed: {e}");
+            return;
+        }
+    };
+    let peer = conn.remote_address();
+
+    let mut conn = match h3::server::Connection::new(h3_quinn::Connection::new(conn)).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            tracing::info!("http/3 connection from {peer} failed: {e}");
+            return;
+        }
+    };
+
+    loop {
+        let resolver = match conn.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => break,
+            Err(e) => {
+                if !e.is_h3_no_error() {
+                    tracing::info!("http/3 connection from {peer} failed: {e}");
+                }
+                break;
+            }
+        };
+
+        let app = app.clone();
+        tokio::spawn(async move {
+            let result = match resolver.resolve_request().await {
+                Ok((req, stream)) => serve_request(app, peer, req, stream).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                tracing::info!("http/3 request from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Answer a request with the app, streaming its body both ways.
+///
+/// The client's address is passed on to the app like the TLS listener does,
+/// so that access lists, quotas and the admin API see the same client.
+async fn serve_request(
+    app: Router,
+    peer: SocketAddr,
+    req: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> Result<(), BoxError> {
+    let (mut send, recv) = stream.split();
+
+    let body = futures::stream::unfold(Some(recv), |recv| async move {
+        let mut recv = recv?;
+
+        match recv.recv_data().await {
+            Ok(Some(mut data)) => Some((Ok(data.copy_to_bytes(data.remaining())), Some(recv))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+
+    let (parts, ()) = req.into_parts();
+    let mut req = Request::from_parts(parts, Body::from_stream(body));
+    req.extensions_mut().insert(ConnectInfo(peer));
+
+    let res = match app.oneshot(req).await {
+        Ok(res) => res,
+        Err(e) => match e {},
+    };
+    let (parts, body) = res.into_parts();
+    send.send_response(http::Response::from_parts(parts, ()))
+        .await?;
+
+    let mut body = body.into_data_stream();
+    while let Some(data) = body.next().await {
+        match data {
+            Ok(data) => send.send_data(data).await?,
+            Err(e) => {
+                // the response is cut short, rather than ended as if complete
+                send.stop_stream(Code::H3_INTERNAL_ERROR);
+                return Err(e.into());
+            }
+        }
+    }
+
+    send.finish().await?;
+    Ok(())
+}
@@ -1,31 +1,200 @@
 //! Image handling utilities
 
-use std::sync::Arc;
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
-use axum::http::header;
+use axum::body::Bytes;
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
-use image::{GenericImage, ImageBuffer, ImageFormat, Rgba};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImage, ImageBuffer, ImageFormat, Rgba, RgbaImage};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
 
 use crate::api;
+use crate::config::{Config, GifMode, PreviewFormat};
+use crate::slow;
+
+/// The maximum size of a video a frame is extracted from.
+const MAX_VIDEO_LEN: usize = 64 * 1024 * 1024;
+
+/// How long extracting a frame from a video may take, downloading it
+/// included.
+const VIDEO_FRAME_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Helper struct that manages a byte buffer for an image and its mime type.
+///
+/// The buffer is reference counted, so that an image can be held by several
+/// links and the image cache, and served, without being copied.
 #[derive(Clone)]
 pub struct Image {
-    pub data: Box<[u8]>,
+    pub data: Bytes,
     pub mime_type: Arc<str>,
 }
 
 impl Image {
     /// Create a new `Image` from a byte buffer and its mime type.
-    pub fn new(data: Box<[u8]>, mime_type: Arc<str>) -> Self {
-        Self { data, mime_type }
+    pub fn new(data: impl Into<Bytes>, mime_type: Arc<str>) -> Self {
+        Self {
+            data: data.into(),
+            mime_type,
+        }
+    }
+
+    /// Clone the placeholder image for the given case.
+    pub fn placeholder(kind: Placeholder) -> Self {
+        let placeholders = Placeholders::global();
+
+        match kind {
+            Placeholder::Loading => placeholders.loading.clone(),
+            Placeholder::Failed => placeholders.failed.clone(),
+            Placeholder::Expired => placeholders.expired.clone(),
+        }
     }
 
-    /// Clone the placeholder image.
-    pub fn placeholder() -> Self {
+    /// Clone the proxy's own placeholder image.
+    fn missing() -> Self {
         let bytes = include_bytes!("../../assets/missing.png");
 
-        Self::new(bytes.to_vec().into_boxed_slice(), "image/png".into())
+        Self::new(Bytes::from_static(bytes), "image/png".into())
+    }
+}
+
+/// What a placeholder image is served in place of.
+#[derive(Debug, Clone, Copy)]
+pub enum Placeholder {
+    /// An image that is still loading.
+    Loading,
+    /// An image that failed to load.
+    Failed,
+    /// An image whose link was torn down while it loaded.
+    Expired,
+}
+
+/// The placeholder images of this instance.
+pub struct Placeholders {
+    loading: Image,
+    failed: Image,
+    expired: Image,
+}
+
+impl Placeholders {
+    /// Get the placeholder images, reading them from disk the first time.
+    pub fn global() -> &'static Self {
+        static PLACEHOLDERS: OnceLock<Placeholders> = OnceLock::new();
+        PLACEHOLDERS.get_or_init(Self::load)
+    }
+
+    /// Read the configured placeholder images, falling back to the proxy's
+    /// own for any that is unset or can't be read.
+    fn load() -> Self {
+        let config = &Config::global().image.placeholders;
+
+        let default = read_placeholder(config.default.as_deref()).unwrap_or_else(Image::missing);
+        let read = |path: &Option<_>| read_placeholder(path.as_deref()).unwrap_or(default.clone());
+
+        Self {
+            loading: read(&config.loading),
+            failed: read(&config.failed),
+            expired: read(&config.expired),
+        }
+    }
+}
+
+/// Read a placeholder image from disk, guessing its mime type from its
+/// contents.
+fn read_placeholder(path: Option<&Path>) -> Option<Image> {
+    let path = path?;
+
+    let data = std::fs::read(path)
+        .inspect_err(|e| tracing::error!("failed to read placeholder {}: {e}", path.display()))
+        .ok()?;
+
+    let Ok(format) = image::guess_format(&data) else {
+        tracing::error!("placeholder {} isn't a known image format", path.display());
+        return None;
+    };
+
+    Some(Image::new(data, format.to_mime_type().into()))
+}
+
+impl Image {
+    /// Respond with the part of this image asked for by a `Range` header, or
+    /// with the whole image if there is no header, or it can't be served.
+    ///
+    /// Only single byte ranges are supported; clients asking for several
+    /// ranges get the whole image, which HTTP allows.
+    pub fn into_range_response(self, range: Option<&HeaderValue>) -> Response {
+        let len = self.data.len();
+        let range = range
+            .and_then(|range| range.to_str().ok())
+            .map_or(ByteRange::Whole, |range| byte_range(range, len));
+
+        match range {
+            ByteRange::Whole => ([(header::ACCEPT_RANGES, "bytes")], self).into_response(),
+            ByteRange::Part(start, end) => (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (header::CONTENT_TYPE, self.mime_type.to_string()),
+                    (header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}")),
+                ],
+                self.data.slice(start..=end),
+            )
+                .into_response(),
+            ByteRange::Unsatisfiable => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// The part of a body asked for by a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// The header is missing, malformed, or asks for several ranges.
+    Whole,
+    /// The bytes from the first to the second index, inclusive.
+    Part(usize, usize),
+    /// The range starts past the end of the body.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header, for a body of `len` bytes.
+fn byte_range(range: &str, len: usize) -> ByteRange {
+    let Some((start, end)) = range
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.trim().split_once('-'))
+    else {
+        return ByteRange::Whole;
+    };
+
+    let last = len.saturating_sub(1);
+    let (start, end) = match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(last)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, last),
+        // the last `suffix` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), last)
+        }
+        (Err(_), Ok(0)) if start.is_empty() => return ByteRange::Unsatisfiable,
+        _ => return ByteRange::Whole,
+    };
+
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Part(start, end)
     }
 }
 
@@ -39,41 +208,694 @@ impl IntoResponse for Image {
     }
 }
 
+/// The reason an image could not be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchError {
+    /// The image host answered with an error status.
+    Status(StatusCode),
+    /// The request failed before the whole image was received.
+    Network,
+    /// The image could not be decoded, or the result could not be encoded.
+    Decode,
+    /// The link of the image was torn down while it was loading.
+    Cancelled,
+}
+
+impl FetchError {
+    /// Whether loading the image again may succeed, as opposed to failing
+    /// the same way.
+    pub fn is_retryable(self) -> bool {
+        match self {
+            Self::Status(status) => {
+                status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            Self::Network => true,
+            Self::Decode | Self::Cancelled => false,
+        }
+    }
+}
+
+impl FetchError {
+    /// A short, stable identifier for this kind of error.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::Status(_) => "image_unavailable",
+            Self::Network => "download_failed",
+            Self::Decode => "decode_failed",
+            Self::Cancelled => "link_removed",
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => Self::Status(status),
+            None => Self::Network,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error,{},", self.code())?;
+
+        match self {
+            Self::Status(status) => write!(f, "e621 answered {status} for this image."),
+            Self::Network => write!(f, "The image couldn't be downloaded. Try again later."),
+            Self::Decode => write!(f, "The image couldn't be decoded."),
+            Self::Cancelled => write!(f, "This link was removed while its image loaded."),
+        }
+    }
+}
+
+/// The progress of the downloads of an image, shared between the load of the
+/// image and anyone watching it.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    inner: Arc<ProgressInner>,
+}
+
+#[derive(Debug, Default)]
+struct ProgressInner {
+    received: AtomicU64,
+    /// The sum of the sizes of the downloads that reported one.
+    total: AtomicU64,
+    /// The number of downloads that didn't report their size.
+    unsized_downloads: AtomicU64,
+}
+
+impl Progress {
+    /// Account for a download starting, with its size if it is known.
+    pub fn start(&self, len: Option<u64>) {
+        match len {
+            Some(len) => self.inner.total.fetch_add(len, Ordering::Relaxed),
+            None => self.inner.unsized_downloads.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Account for bytes received by a download.
+    pub fn receive(&self, len: usize) {
+        let len = u64::try_from(len).unwrap_or(u64::MAX);
+        self.inner.received.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Forget every download, for when the image is loaded again.
+    pub fn reset(&self) {
+        self.inner.received.store(0, Ordering::Relaxed);
+        self.inner.total.store(0, Ordering::Relaxed);
+        self.inner.unsized_downloads.store(0, Ordering::Relaxed);
+    }
+
+    /// The bytes received, and the total to receive if every download
+    /// reported its size.
+    pub fn get(&self) -> (u64, Option<u64>) {
+        let received = self.inner.received.load(Ordering::Relaxed);
+        let total = (self.inner.unsized_downloads.load(Ordering::Relaxed) == 0)
+            .then(|| self.inner.total.load(Ordering::Relaxed));
+
+        (received, total)
+    }
+}
+
+/// The size in pixels of a tile in a preview grid.
+const TILE_SIZE: u32 = 150;
+
+/// The number of tiles in each row of a preview grid.
+const GRID_COLUMNS: u32 = 10;
+
+/// The size in pixels of a preview grid image, which is always square.
+const GRID_SIZE: u32 = TILE_SIZE * GRID_COLUMNS;
+
+/// The area of a preview grid image covered by a post's preview, as texture
+/// coordinates.
+///
+/// Coordinates are normalized to the size of the grid, with the origin in the
+/// bottom-left corner, as Unity expects them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Get the top-left pixel position of a post's preview in a preview grid,
+/// given its index in the search results and its size.
+///
+/// Previews are centered in their tile.
+fn tile_position(index: u32, width: u32, height: u32) -> (u32, u32) {
+    let x = (index % GRID_COLUMNS) * TILE_SIZE + TILE_SIZE.saturating_sub(width) / 2;
+    let y = (index / GRID_COLUMNS) * TILE_SIZE + TILE_SIZE.saturating_sub(height) / 2;
+
+    (x, y)
+}
+
+/// Get the size in pixels of a post's preview in a preview grid, as reported
+/// by the API.
+fn tile_size(post: &api::Post) -> (u32, u32) {
+    let size = |n: i64| u32::try_from(n).unwrap_or(0).min(TILE_SIZE);
+    (size(post.preview.width), size(post.preview.height))
+}
+
+/// Get the area of a preview grid image covered by a post's preview, given
+/// its index in the search results.
+pub fn tile_rect(index: u32, post: &api::Post) -> TileRect {
+    let (width, height) = tile_size(post);
+    let (x, y) = tile_position(index, width, height);
+
+    let grid = f64::from(GRID_SIZE);
+
+    TileRect {
+        x: f64::from(x) / grid,
+        y: 1.0 - f64::from(y + height) / grid,
+        width: f64::from(width) / grid,
+        height: f64::from(height) / grid,
+    }
+}
+
+/// The quality of JPEG images re-encoded by the proxy, other than preview
+/// grids.
+const JPEG_QUALITY: u8 = 90;
+
+/// The color of the tiles of a preview grid whose preview failed to load.
+const MISSING_TILE: Rgba<u8> = Rgba([128, 128, 128, 255]);
+
 /// Generate a composite "preview" image from an api response.
-pub async fn make_preview(posts: api::Posts) -> Option<Image> {
-    log::info!("generating preview...");
+///
+/// Posts whose preview fails to load get a gray tile, and the rest of the
+/// grid is still served. Only if every preview fails does the grid fail.
+pub async fn make_preview(posts: api::Posts, progress: Progress) -> Result<Image, FetchError> {
+    let threshold = Config::global().server.slow.preview;
+    let ids = posts
+        .iter()
+        .map(|post| post.id.to_string())
+        .collect::<Vec<_>>();
+    let context = format!("posts {}", ids.join(","));
+
+    slow::warn_if_slow(
+        "preview",
+        threshold,
+        context,
+        stitch_preview(posts, progress),
+    )
+    .await
+}
+
+/// Download the previews of posts and stitch them together, as described by
+/// `make_preview`.
+async fn stitch_preview(posts: api::Posts, progress: Progress) -> Result<Image, FetchError> {
+    tracing::info!("generating preview...");
 
     let urls = posts
         .iter()
         .map(|post| post.preview.url.clone())
-        .map(api::get_image);
+        .map(|url| async {
+            let _permit = preview_downloads().acquire().await.expect("never closed");
+            api::get_image(url, &progress).await
+        });
+
+    let previews = futures::future::join_all(urls)
+        .instrument(tracing::info_span!("preview.download"))
+        .await;
+
+    // a grid of only gray tiles is no better than the placeholder
+    if let Some(&Err(e)) = previews.first() {
+        if previews.iter().all(Result::is_err) {
+            return Err(e);
+        }
+    }
+
+    let stitch = tokio::task::spawn_blocking(move || {
+        let mut pic: ImageBuffer<Rgba<u8>, _> = ImageBuffer::new(GRID_SIZE, GRID_SIZE);
+
+        for ((image, post), i) in previews.into_iter().zip(posts.iter()).zip(0_u32..) {
+            let placed = image
+                .ok()
+                .and_then(|image| preview_tile(&image, post))
+                .and_then(|tile| {
+                    let (x, y) = tile_position(i, tile.width(), tile.height());
+                    pic.copy_from(&tile, x, y).ok()
+                });
+
+            if placed.is_none() {
+                tracing::warn!("failed to load the preview of post {}", post.id);
+                draw_missing_tile(&mut pic, i, post);
+            }
+        }
+
+        encode_preview(&DynamicImage::from(pic)).ok_or(FetchError::Decode)
+    });
+    let preview = stitch
+        .instrument(tracing::info_span!("preview.stitch"))
+        .await;
+
+    tracing::info!("finished generating preview");
+
+    preview.unwrap_or(Err(FetchError::Decode))
+}
 
-    let previews = futures::future::try_join_all(urls).await.ok()?;
+/// Get the semaphore limiting how many preview images are downloaded at once.
+fn preview_downloads() -> &'static Semaphore {
+    static DOWNLOADS: OnceLock<Semaphore> = OnceLock::new();
+    DOWNLOADS.get_or_init(|| Semaphore::new(Config::global().image.preview_downloads.max(1)))
+}
+
+/// Decode the preview of a post, marking it with a play indicator if the post
+/// is animated.
+fn preview_tile(image: &Image, post: &api::Post) -> Option<DynamicImage> {
+    let tile = image::load_from_memory(&image.data).ok()?;
+
+    if !post.is_animated() {
+        return Some(tile);
+    }
+
+    let mut rgba = tile.into_rgba8();
+    draw_play_indicator(&mut rgba);
+    Some(rgba.into())
+}
+
+/// Fill the tile of a post whose preview failed to load, over the area its
+/// preview would have covered.
+fn draw_missing_tile(pic: &mut RgbaImage, index: u32, post: &api::Post) {
+    let (width, height) = tile_size(post);
+    let (x, y) = tile_position(index, width, height);
+
+    for dy in 0..height {
+        for dx in 0..width {
+            if let Some(pixel) = pic.get_pixel_mut_checked(x + dx, y + dy) {
+                *pixel = MISSING_TILE;
+            }
+        }
+    }
+}
+
+/// Get the image served by a post's `Image` link.
+///
+/// Video posts are served as their first frame, if frame extraction is
+/// enabled. GIF posts are either passed through untouched, or served as their
+/// first frame, depending on the configured `GifMode`. Otherwise, if the post
+/// is over the configured maximum file size, or if extraction fails, the
+/// post's still image is served instead.
+///
+/// Images over the configured maximum dimension are downscaled to fit, and
+/// images are transcoded or stripped of their metadata if configured.
+pub async fn post_image(post: api::Post, progress: Progress) -> Result<Image, FetchError> {
+    let image = fetch_post_image(post, progress).await?;
+
+    process(image, Reencode::configured()).await
+}
+
+/// Get one of the files of a post, like its preview or original file, as
+/// served by its file links.
+///
+/// The file is downscaled, transcoded or stripped of its metadata like the
+/// image of `post_image`.
+pub async fn post_file(url: Arc<str>, progress: Progress) -> Result<Image, FetchError> {
+    let image = api::get_image(url, &progress).await?;
+
+    process(image, Reencode::configured()).await
+}
+
+/// Downscale an image so that neither its width nor its height is over `max`,
+/// as described by `reencode`.
+pub async fn fit(image: Image, max: u32) -> Result<Image, FetchError> {
+    let ops = Reencode {
+        max: Some(max),
+        ..Reencode::default()
+    };
+
+    process(image, ops).await
+}
+
+/// Make the Quest variant of a post's image: a JPEG that fits within the
+/// configured Quest dimension.
+pub async fn quest(image: Image) -> Result<Image, FetchError> {
+    let ops = Reencode {
+        max: Some(Config::global().image.quest_dimension),
+        jpeg: true,
+        ..Reencode::default()
+    };
+
+    process(image, ops).await
+}
+
+/// Run `reencode` on the blocking thread pool.
+async fn process(image: Image, ops: Reencode) -> Result<Image, FetchError> {
+    tokio::task::spawn_blocking(move || reencode(image, ops))
+        .await
+        .map_err(|_| FetchError::Decode)
+}
+
+/// Download (and possibly extract a frame from) the file of a post's `Image`
+/// link, as described by `post_image`.
+async fn fetch_post_image(post: api::Post, progress: Progress) -> Result<Image, FetchError> {
+    let config = &Config::global().image;
 
-    let preview = tokio::task::spawn_blocking(move || {
-        let mut pic: ImageBuffer<Rgba<u8>, _> = ImageBuffer::new(1500, 1500);
+    let image = if post.is_oversized() {
+        return api::get_image(post.still_url(), &progress).await;
+    } else if post.is_video() && config.video_frames {
+        video_frame(post.file.url.clone(), &progress).await
+    } else if post.is_gif() {
+        match config.gif {
+            GifMode::Passthrough => api::get_image(post.file.url.clone(), &progress).await.ok(),
+            GifMode::FirstFrame => gif_frame(post.file.url.clone(), &progress).await,
+        }
+    } else {
+        return api::get_image(post.still_url(), &progress).await;
+    };
+
+    match image {
+        Some(image) => Ok(image),
+        None => {
+            tracing::warn!("falling back to still image for post {}", post.id);
+            api::get_image(post.still_url(), &progress).await
+        }
+    }
+}
+
+/// What `reencode` does to an image.
+#[derive(Debug, Clone, Copy, Default)]
+struct Reencode {
+    /// Downscale the image so that neither its width nor its height is over
+    /// this, keeping its aspect ratio.
+    max: Option<u32>,
+    /// Re-encode JPEG and PNG images even if they fit, to drop their metadata.
+    strip: bool,
+    /// Re-encode images that VRChat may not load, even if they fit.
+    transcode: bool,
+    /// Encode the image as a JPEG, whatever its format.
+    jpeg: bool,
+}
+
+impl Reencode {
+    /// What is done to every image of a post, as configured.
+    fn configured() -> Self {
+        let config = &Config::global().image;
+
+        Self {
+            max: config.max_dimension,
+            strip: config.strip_metadata,
+            transcode: config.transcode,
+            jpeg: false,
+        }
+    }
+}
+
+/// Re-encode an image as described by `ops`, if there is anything to do.
+///
+/// JPEG images are encoded as baseline JPEGs again, and everything else as
+/// 8-bit PNGs, unless `jpeg` is set. Images that can't be decoded are
+/// returned untouched.
+fn reencode(image: Image, ops: Reencode) -> Image {
+    let Ok(format) = image::guess_format(&image.data) else {
+        return image;
+    };
 
-        for (image, i) in previews.into_iter().zip(0_u32..) {
-            let mem = image::load_from_memory(&image.data).ok()?;
+    // other formats would be turned into PNG, which isn't worth it for their
+    // metadata alone
+    let strip = ops.strip && matches!(format, ImageFormat::Jpeg | ImageFormat::Png);
+    let transcode = ops.transcode && !loads_in_vrchat(format, &image.data)
+        || ops.jpeg && format != ImageFormat::Jpeg;
+    if ops.max.is_none() && !strip && !transcode {
+        return image;
+    }
+
+    let Ok(decoded) = image::load_from_memory_with_format(&image.data, format) else {
+        return image;
+    };
+
+    let (width, height) = (decoded.width(), decoded.height());
+    let decoded = match ops.max.filter(|&max| width > max || height > max) {
+        Some(max) => {
+            tracing::info!("downscaling {width}x{height} image to fit {max}px");
+            decoded.resize(max, max, FilterType::Triangle)
+        }
+        None if strip || transcode => decoded,
+        None => return image,
+    };
+
+    if transcode {
+        tracing::info!("transcoding {format:?} image");
+    }
+
+    let encoded = if ops.jpeg || format == ImageFormat::Jpeg {
+        encode_jpeg(&decoded, JPEG_QUALITY)
+    } else {
+        encode_png(&to_8bit(decoded))
+    };
+
+    encoded.unwrap_or(image)
+}
+
+/// Whether VRChat's image loader is known to load an image: baseline JPEGs,
+/// non-interlaced PNGs of up to 8 bits per channel, and GIFs.
+fn loads_in_vrchat(format: ImageFormat, data: &[u8]) -> bool {
+    match format {
+        ImageFormat::Jpeg => !is_progressive_jpeg(data),
+        // the bit depth and interlace method of the IHDR chunk, which always
+        // comes first
+        ImageFormat::Png => {
+            data.get(24).is_some_and(|&depth| depth <= 8) && data.get(28) == Some(&0)
+        }
+        ImageFormat::Gif => true,
+        _ => false,
+    }
+}
 
-            let x = (i % 10) * 150 + (150 - mem.width()) / 2;
-            let y = (i / 10) * 150 + (150 - mem.height()) / 2;
+/// Whether a JPEG is progressive, going by its start of frame marker.
+fn is_progressive_jpeg(data: &[u8]) -> bool {
+    // skip the start of image marker
+    let mut i = 2;
 
-            pic.copy_from(&mem, x, y).ok()?;
+    while let Some(&[0xFF, marker, ..]) = data.get(i..) {
+        match marker {
+            0xC2 | 0xC6 | 0xCA | 0xCE => return true,
+            // a non-progressive start of frame, or the start of the scan
+            0xC0 | 0xC1 | 0xC3 | 0xC5 | 0xC7 | 0xC9 | 0xCB | 0xCD | 0xCF | 0xDA => return false,
+            // padding
+            0xFF => i += 1,
+            _ => {
+                let Some(&[hi, lo]) = data.get(i + 2..i + 4) else {
+                    return false;
+                };
+                i += 2 + usize::from(u16::from_be_bytes([hi, lo]));
+            }
         }
+    }
 
-        // todo: benchmark this
-        let mut buf = std::io::Cursor::new(Vec::new());
-        pic.write_to(&mut buf, ImageFormat::Png).ok()?;
+    false
+}
 
-        Some(Image::new(
-            buf.into_inner().into_boxed_slice(),
-            "image/png".into(),
-        ))
+/// Convert an image with more than 8 bits per channel to 8 bits per channel,
+/// which every decoder supports.
+fn to_8bit(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => image,
+        _ if image.color().has_alpha() => image.to_rgba8().into(),
+        _ => image.to_rgb8().into(),
+    }
+}
+
+/// Decode the first frame of a GIF, marked with a play indicator.
+async fn gif_frame(url: Arc<str>, progress: &Progress) -> Option<Image> {
+    let gif = api::get_image(url, progress).await.ok()?;
+
+    tokio::task::spawn_blocking(move || mark_still(&gif.data))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Extract the first frame of a video, marked with a play indicator.
+///
+/// The video is downloaded through the API client, without caching it, then
+/// decoded by piping it through `ffmpeg`. Videos over `MAX_VIDEO_LEN`, and
+/// extractions taking longer than `VIDEO_FRAME_TIMEOUT`, download included,
+/// are given up on.
+async fn video_frame(url: Arc<str>, progress: &Progress) -> Option<Image> {
+    let extract = async {
+        let Some(video) = api::get_file(&url, progress, MAX_VIDEO_LEN).await else {
+            tracing::warn!("failed to download video, or it is too large: {url}");
+            return None;
+        };
+        extract_frame(video).await
+    };
+
+    // ffmpeg is killed once its future is dropped
+    let frame = tokio::time::timeout(VIDEO_FRAME_TIMEOUT, extract).await;
+    frame.unwrap_or_else(|_| {
+        tracing::warn!("extracting a frame took too long: {url}");
+        None
     })
-    .await;
+}
+
+/// Extract the first frame of a video with `ffmpeg`, and mark it with a play
+/// indicator.
+async fn extract_frame(video: Vec<u8>) -> Option<Image> {
+    let mut child = Command::new(&Config::global().image.ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0"])
+        .args([
+            "-frames:v",
+            "1",
+            "-f",
+            "image2pipe",
+            "-c:v",
+            "png",
+            "pipe:1",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .inspect_err(|e| tracing::error!("failed to run ffmpeg: {e}"))
+        .ok()?;
+
+    // ffmpeg stops reading once it has a frame, so write errors are expected
+    let mut stdin = child.stdin.take()?;
+    let writer = tokio::spawn(async move {
+        let _ = stdin.write_all(&video).await;
+    });
+
+    let output = child.wait_with_output().await.ok()?;
+    writer.abort();
+
+    tokio::task::spawn_blocking(move || mark_still(&output.stdout))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Decode the (first frame of an) image, mark it with a play indicator, and
+/// encode it as a PNG.
+fn mark_still(data: &[u8]) -> Option<Image> {
+    let mut frame = image::load_from_memory(data).ok()?.into_rgba8();
+    draw_play_indicator(&mut frame);
+
+    encode_png(&DynamicImage::from(frame))
+}
+
+/// Draw a "play" indicator in the middle of an image, marking it as a still
+/// frame of an animated post.
+fn draw_play_indicator(image: &mut RgbaImage) {
+    let (width, height) = image.dimensions();
+    let radius = (width.min(height) as f32 / 8.0).max(6.0);
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+        if dx * dx + dy * dy > radius * radius {
+            continue;
+        }
+
+        // a right-pointing triangle, inscribed in half the circle
+        let t = radius / 2.0;
+        let in_triangle = dx >= -t / 2.0 && dy.abs() <= (t - dx) / 1.5;
+
+        let (color, alpha) = if in_triangle {
+            ([255.0, 255.0, 255.0], 0.9)
+        } else {
+            ([0.0, 0.0, 0.0], 0.5)
+        };
+
+        for (channel, color) in pixel.0.iter_mut().zip(color) {
+            *channel = (f32::from(*channel) * (1.0 - alpha) + color * alpha) as u8;
+        }
+        pixel.0[3] = pixel.0[3].max((alpha * 255.0) as u8);
+    }
+}
+
+/// Encode a preview grid in the configured `PreviewFormat`.
+fn encode_preview(image: &DynamicImage) -> Option<Image> {
+    let config = &Config::global().image;
+
+    match config.preview_format {
+        PreviewFormat::Png => encode_png(image),
+        PreviewFormat::Jpeg => encode_jpeg(image, config.preview_quality),
+        PreviewFormat::Webp => encode_webp(image),
+    }
+}
+
+/// Encode an image as a lossless WebP.
+fn encode_webp(image: &DynamicImage) -> Option<Image> {
+    let mut buf = std::io::Cursor::new(Vec::new());
+    image
+        .write_with_encoder(WebPEncoder::new_lossless(&mut buf))
+        .ok()?;
+
+    Some(Image::new(buf.into_inner(), "image/webp".into()))
+}
+
+/// Encode an image as a JPEG, with a quality from 1 to 100.
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Option<Image> {
+    let mut buf = std::io::Cursor::new(Vec::new());
+    let encoder = JpegEncoder::new_with_quality(&mut buf, quality.clamp(1, 100));
+
+    // jpeg has no alpha channel
+    DynamicImage::from(image.to_rgb8())
+        .write_with_encoder(encoder)
+        .ok()?;
+
+    Some(Image::new(buf.into_inner(), "image/jpeg".into()))
+}
+
+/// Encode an image as a PNG.
+fn encode_png(image: &DynamicImage) -> Option<Image> {
+    let mut buf = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut buf, ImageFormat::Png).ok()?;
 
-    log::info!("finished generating preview");
+    Some(Image::new(buf.into_inner(), "image/png".into()))
+}
+
+#[cfg(test)]
+mod test {
+    use image::{DynamicImage, ImageFormat};
+
+    #[test]
+    fn test_loads_in_vrchat() {
+        let image = DynamicImage::new_rgb8(4, 4);
+
+        let jpeg = super::encode_jpeg(&image, 90).unwrap();
+        assert!(super::loads_in_vrchat(ImageFormat::Jpeg, &jpeg.data));
+
+        // an APP0 segment, then a progressive start of frame
+        let progressive = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xC2];
+        assert!(!super::loads_in_vrchat(ImageFormat::Jpeg, &progressive));
+
+        let png = super::encode_png(&image).unwrap();
+        assert!(super::loads_in_vrchat(ImageFormat::Png, &png.data));
+
+        let deep = super::encode_png(&DynamicImage::new_rgba16(4, 4)).unwrap();
+        assert!(!super::loads_in_vrchat(ImageFormat::Png, &deep.data));
+        let png = super::encode_png(&super::to_8bit(DynamicImage::new_rgba16(4, 4))).unwrap();
+        assert!(super::loads_in_vrchat(ImageFormat::Png, &png.data));
 
-    preview.ok().flatten()
+        assert!(!super::loads_in_vrchat(ImageFormat::WebP, &[]));
+    }
+
+    #[test]
+    fn test_byte_range() {
+        use super::{byte_range, ByteRange};
+
+        assert_eq!(byte_range("bytes=0-99", 1000), ByteRange::Part(0, 99));
+        assert_eq!(byte_range("bytes=500-", 1000), ByteRange::Part(500, 999));
+        assert_eq!(byte_range("bytes=-100", 1000), ByteRange::Part(900, 999));
+        assert_eq!(
+            byte_range("bytes=900-5000", 1000),
+            ByteRange::Part(900, 999)
+        );
+        assert_eq!(byte_range("bytes=-5000", 1000), ByteRange::Part(0, 999));
+
+        assert_eq!(byte_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+
+        assert_eq!(byte_range("bytes=0-1,5-6", 1000), ByteRange::Whole);
+        assert_eq!(byte_range("bytes=5-1", 1000), ByteRange::Whole);
+        assert_eq!(byte_range("items=0-1", 1000), ByteRange::Whole);
+    }
 }
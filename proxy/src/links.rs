@@ -1,16 +1,25 @@
 //! Contains a `LinkMap` struct that maps identifiers to `Link` variants.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
+use dashmap::DashMap;
 use futures::FutureExt;
 use itertools::Itertools;
-use tokio::sync::RwLock;
+use rand::Rng;
 
 use crate::api;
-use crate::image::{self, Image};
-use crate::promise::{LazyPromise, Promise};
-use crate::refresh::{RefreshHandler, Refresher};
+use crate::budget::ImageSlot;
+use crate::config::Config;
+use crate::image;
+use crate::metrics::Metrics;
+use crate::query::Query;
+use crate::refresh::{RefreshHandler, Refresher, SavedLimits};
+use crate::signing;
+use crate::store;
 
 /// A map of `Link` variants, with their associated identifiers.
 ///
@@ -25,9 +34,105 @@ use crate::refresh::{RefreshHandler, Refresher};
 /// for a given API response. This initially inserts several `Link` variants,
 /// and spawns tasks that will remove them after a certain period of time.
 /// The task details can be found in the `RefreshHandler` struct.
+///
+/// The map holds at most the configured `max_entries` links. Past that, the
+/// least recently used links are evicted along with the rest of their
+/// search or post, which also cancels their teardown tasks.
+///
+/// The maps are sharded, so that link reads, inserts and teardowns only
+/// contend with each other when they touch the same shard.
 #[derive(Default)]
 pub struct LinkMap {
-    inner: HashMap<usize, Link>,
+    inner: DashMap<LinkId, Entry>,
+    /// A counter that orders link accesses, for LRU eviction.
+    clock: AtomicU64,
+    /// The header identifiers of live searches, by their query, so that
+    /// identical searches can share a `SearchMap`.
+    searches: DashMap<Query, HeaderIds>,
+    /// What each group of links was created from, by group.
+    records: DashMap<LinkId, Record>,
+    /// Why recently removed links were removed, so that clients can be told.
+    tombstones: DashMap<LinkId, Tombstone>,
+}
+
+/// A recently removed `Link`.
+struct Tombstone {
+    removal: Removal,
+    at: Instant,
+}
+
+/// Why a `Link` was removed from the `LinkMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Removal {
+    /// The link wasn't refreshed in time.
+    Expired,
+    /// The link was evicted to make room for newer links.
+    Evicted,
+    /// The link was purged by the operator of the instance.
+    Purged,
+}
+
+impl Removal {
+    /// A short, stable identifier for this kind of removal.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::Expired => "link_expired",
+            Self::Evicted => "link_evicted",
+            Self::Purged => "link_purged",
+        }
+    }
+}
+
+impl fmt::Display for Removal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error,{},", self.code())?;
+
+        match self {
+            Self::Expired => write!(f, "This link wasn't refreshed in time. Search again."),
+            Self::Evicted => write!(f, "This link was evicted to make room. Search again."),
+            Self::Purged => write!(f, "This link was removed by the instance. Search again."),
+        }
+    }
+}
+
+/// What a group of links was created from, kept so that the group can be
+/// saved to a `Snapshot` and restored.
+enum Record {
+    Search(SearchRecord),
+    Post(PostRecord),
+}
+
+impl Record {
+    /// The refresher of the group of links.
+    const fn refresher(&self) -> &Refresher {
+        match self {
+            Self::Search(record) => &record.refresher,
+            Self::Post(record) => &record.refresher,
+        }
+    }
+}
+
+/// The links of a search, minus its posts.
+struct SearchRecord {
+    ids: HeaderIds,
+    query: Query,
+    /// Whether the search can be shared, and has a next page link.
+    shared: bool,
+    posts: api::Posts,
+    post_ids: Vec<PostIds>,
+    search_map: SearchMap,
+    chunks: Vec<(LinkId, SearchMap)>,
+    refresher: Refresher,
+}
+
+/// The links of a post in a search.
+struct PostRecord {
+    ids: PostIds,
+    post: api::Post,
+    /// The `SearchMap` id of the search the post was found by, whose
+    /// refresher also refreshes the post.
+    search: LinkId,
+    refresher: Refresher,
 }
 
 /// A map of `Link` variants.
@@ -36,191 +141,1117 @@ pub struct LinkMap {
 /// or the `link` function for information on the specific variants of `Link`.
 #[derive(Clone)]
 pub enum Link {
-    /// Preview image `Promise`
-    Previews(Promise<Option<Image>>),
-    /// Sample image `LazyPromise`
-    Image(LazyPromise<Option<Image>>),
+    /// Preview image, loaded eagerly
+    Previews(ImageSlot),
+    /// Sample image, loaded lazily
+    Image(ImageSlot),
+    /// (original file url, streamed to the client)
+    File(Arc<str>),
+    /// (direct video url)
+    Video(Arc<str>),
     /// (search query)
     SearchMap(SearchMap),
+    /// (continuation of a search query)
+    Chunk(SearchMap),
+    /// (search query of the current page)
+    NextPage(Query),
     /// (image refresher)
     RefreshImage(Refresher),
     /// (Query, refresher)
     RefreshSearch(Refresher),
 }
 
-/// A reference to a `LinkMap`.
-type Ref<T> = tokio::sync::RwLockReadGuard<'static, T>;
-/// A mutable reference to a `LinkMap`.
-type MutRef<T> = tokio::sync::RwLockWriteGuard<'static, T>;
+impl Link {
+    /// The name of the kind of this link, for logs.
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Previews(_) => "previews",
+            Self::Image(_) => "image",
+            Self::File(_) => "file",
+            Self::Video(_) => "video",
+            Self::SearchMap(_) => "search_map",
+            Self::Chunk(_) => "chunk",
+            Self::NextPage(_) => "next_page",
+            Self::RefreshImage(_) => "refresh_image",
+            Self::RefreshSearch(_) => "refresh_search",
+        }
+    }
+
+    /// Cancel any work still running for this link, once it is torn down.
+    fn cancel(&self) {
+        if let Self::Previews(image) | Self::Image(image) = self {
+            image.cancel();
+        }
+    }
+}
+
+/// A `Link` in a `LinkMap`, with the bookkeeping needed to evict it.
+struct Entry {
+    link: Link,
+    /// The id of the `SearchMap` or image `Link` this link belongs to. Links
+    /// in the same group are evicted together.
+    group: LinkId,
+    /// The `LinkMap` clock at the last access of this link.
+    used: AtomicU64,
+}
+
+/// The identifier of a `Link`.
+pub type LinkId = u64;
+
+/// A live `Link`, as listed through the admin API.
+pub struct LinkInfo {
+    pub id: LinkId,
+    pub kind: &'static str,
+    /// The id of the `SearchMap` or image `Link` this link belongs to.
+    pub group: LinkId,
+    /// The time left before the link is torn down, unless it is refreshed.
+    pub remaining: Option<Duration>,
+}
+
+/// The exclusive upper bound of `Link` identifiers. Identifiers stay below
+/// 2^53, so that they survive JSON parsers that read numbers as doubles.
+const MAX_LINK_ID: LinkId = 1 << 53;
+
+/// How long clients are told why a link was removed, after it is removed.
+const TOMBSTONE_TTL: Duration = Duration::from_secs(3600);
+
+/// An endless iterator of random `Link` identifiers.
+///
+/// Identifiers are random, rather than sequential, so that clients can't
+/// enumerate the links of other clients' searches.
+fn random_ids() -> impl Iterator<Item = LinkId> {
+    let mut rng = rand::thread_rng();
+    std::iter::repeat_with(move || rng.gen_range(1..MAX_LINK_ID))
+}
 
 impl LinkMap {
-    /// Get a lock to the global `LinkMap`.
-    fn get_lock() -> &'static RwLock<Self> {
-        static MAP: OnceLock<RwLock<LinkMap>> = OnceLock::new();
+    /// Get the global `LinkMap`.
+    pub fn global() -> &'static Self {
+        static MAP: OnceLock<LinkMap> = OnceLock::new();
         MAP.get_or_init(Default::default)
     }
 
-    /// Get a reference to the global `LinkMap`.
-    pub async fn get_ref() -> Ref<Self> {
-        Self::get_lock().read().await
+    /// Get an `Link` variant from its identifier, if it exists.
+    pub fn get(&self, id: LinkId) -> Option<Link> {
+        let entry = self.inner.get(&id)?;
+        entry.used.store(self.tick(), Ordering::Relaxed);
+
+        Some(entry.link.clone())
     }
 
-    /// Get a mutable reference to the global `LinkMap`.
-    async fn get_mut_ref() -> MutRef<Self> {
-        Self::get_lock().write().await
+    /// The number of links held by this instance.
+    pub fn count(&self) -> usize {
+        self.inner.len()
     }
 
-    /// Get an `Link` variant from its identifier, if it exists.
-    pub fn get(&self, id: usize) -> Option<Link> {
-        self.inner.get(&id).cloned()
+    /// The number of links held by this instance, by kind.
+    pub fn count_by_kind(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in &self.inner {
+            *counts.entry(entry.link.kind()).or_default() += 1;
+        }
+
+        counts
+    }
+
+    /// List the links held by this instance, ordered by group, up to a
+    /// limit.
+    pub fn list(&self, limit: usize) -> Vec<LinkInfo> {
+        let mut links = self
+            .inner
+            .iter()
+            .map(|entry| (entry.group, *entry.key(), entry.link.kind()))
+            .collect::<Vec<_>>();
+        links.sort_unstable();
+        links.truncate(limit);
+
+        links
+            .into_iter()
+            .map(|(group, id, kind)| LinkInfo {
+                id,
+                kind,
+                group,
+                remaining: self
+                    .records
+                    .get(&group)
+                    .map(|record| record.refresher().remaining()),
+            })
+            .collect()
+    }
+
+    /// Advance the clock, returning its previous value.
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Remove a `Link` from the map, leaving a tombstone for it.
+    fn remove(&self, id: LinkId, removal: Removal) {
+        if let Some((_, entry)) = self.inner.remove(&id) {
+            entry.link.cancel();
+            self.bury([id], removal);
+        }
+    }
+
+    /// Leave tombstones for removed links.
+    ///
+    /// Stale tombstones are swept once there are as many tombstones as the
+    /// map holds links at most. If there are still too many, the links are
+    /// forgotten instead.
+    fn bury(&self, ids: impl IntoIterator<Item = LinkId>, removal: Removal) {
+        let max = Config::global().links.max_entries;
+        if self.tombstones.len() >= max {
+            self.tombstones
+                .retain(|_, tombstone| tombstone.at.elapsed() < TOMBSTONE_TTL);
+        }
+
+        let at = Instant::now();
+        for id in ids {
+            if self.tombstones.len() >= max {
+                break;
+            }
+
+            self.tombstones.insert(id, Tombstone { removal, at });
+        }
+    }
+
+    /// Get the time left before a `Link` is torn down, unless it is
+    /// refreshed.
+    pub fn remaining(&self, id: LinkId) -> Option<Duration> {
+        let group = self.inner.get(&id)?.group;
+        let record = self.records.get(&group)?;
+
+        Some(record.refresher().remaining())
+    }
+
+    /// Get the entity tag of the image of a `Link`, for conditional requests.
+    ///
+    /// Images of a post are tagged with the post's MD5 and which of its
+    /// images they are, so that the same image has the same tag across
+    /// searches. Preview grids are only served by their own link, so they are
+    /// tagged with its id.
+    pub fn etag(&self, id: LinkId) -> Option<String> {
+        let group = self.inner.get(&id)?.group;
+        let record = self.records.get(&group)?;
+
+        match &*record {
+            Record::Search(record) => (record.ids.preview == id).then(|| id.to_string()),
+            Record::Post(record) => {
+                let kind = record.ids.image_kind(id)?;
+                Some(format!("{}-{kind}", record.post.file.md5))
+            }
+        }
+    }
+
+    /// Refresh the group of a `Link` that was just fetched, if the instance
+    /// refreshes that kind of link on access. Returns whether it was
+    /// refreshed.
+    pub fn slide(&self, id: LinkId, link: &Link) -> bool {
+        let sliding = &Config::global().links.sliding_expiry;
+        let enabled = match link {
+            Link::SearchMap(_) | Link::Chunk(_) => sliding.search_maps,
+            Link::Previews(_) => sliding.previews,
+            Link::Image(_) | Link::File(_) => sliding.images,
+            Link::Video(_) => sliding.videos,
+            Link::NextPage(_) | Link::RefreshImage(_) | Link::RefreshSearch(_) => false,
+        };
+        if !enabled {
+            return false;
+        }
+
+        let Some(group) = self.inner.get(&id).map(|entry| entry.group) else {
+            return false;
+        };
+        let Some(record) = self.records.get(&group) else {
+            return false;
+        };
+
+        record.refresher().refresh().is_ok()
+    }
+
+    /// Get why a `Link` was removed, if it was removed recently.
+    pub fn removal(&self, id: LinkId) -> Option<Removal> {
+        let tombstone = self.tombstones.get(&id)?;
+        (tombstone.at.elapsed() < TOMBSTONE_TTL).then_some(tombstone.removal)
+    }
+
+    /// Insert a `Link` into the map, as part of the given group.
+    fn insert(&self, id: LinkId, group: LinkId, link: Link) {
+        let used = AtomicU64::new(self.tick());
+        self.inner.insert(id, Entry { link, group, used });
+        self.tombstones.remove(&id);
+    }
+
+    /// Evict the least recently used groups of links, if the map holds more
+    /// than the configured maximum number of links.
+    ///
+    /// The map is shrunk to 90% of the maximum, so that eviction doesn't run
+    /// on every insert once the map is full. Dropping the evicted refresher
+    /// links cancels the teardown tasks of the evicted groups.
+    fn evict(&self) {
+        let max = Config::global().links.max_entries;
+        if self.inner.len() <= max {
+            return;
+        }
+
+        let mut groups: HashMap<LinkId, (u64, usize)> = HashMap::new();
+        for entry in self.inner.iter() {
+            let group = groups.entry(entry.group).or_default();
+            group.0 = group.0.max(entry.used.load(Ordering::Relaxed));
+            group.1 += 1;
+        }
+
+        let target = max - max / 10;
+        let mut len = self.inner.len();
+        let mut evicted = HashSet::new();
+
+        for (group, (_, count)) in groups.into_iter().sorted_unstable_by_key(|(_, g)| g.0) {
+            if len <= target {
+                break;
+            }
+
+            evicted.insert(group);
+            len -= count;
+        }
+
+        tracing::info!("evicting {} link groups", evicted.len());
+
+        let removed = self.remove_groups(&evicted, Removal::Evicted);
+        Metrics::global().evicted(removed.len());
+    }
+
+    /// Purge the group of a `Link`, like eviction does, returning the ids of
+    /// the removed links. Purging a search also purges the posts it found.
+    pub fn purge(&self, id: LinkId) -> Vec<LinkId> {
+        let Some(group) = self.inner.get(&id).map(|entry| entry.group) else {
+            return Vec::new();
+        };
+
+        let mut groups = HashSet::from([group]);
+        for record in self.records.iter() {
+            if let Record::Post(post) = &*record {
+                if post.search == group {
+                    groups.insert(post.ids.post);
+                }
+            }
+        }
+
+        tracing::info!("purging {} link groups", groups.len());
+        self.remove_groups(&groups, Removal::Purged)
+    }
+
+    /// Purge every link, returning the ids of the removed links.
+    pub fn purge_all(&self) -> Vec<LinkId> {
+        let groups = self
+            .inner
+            .iter()
+            .map(|entry| entry.group)
+            .collect::<HashSet<_>>();
+
+        tracing::info!("purging all {} link groups", groups.len());
+        self.remove_groups(&groups, Removal::Purged)
+    }
+
+    /// Remove whole groups of links, returning the ids of the removed links.
+    ///
+    /// Dropping the removed refresher links cancels the teardown tasks of the
+    /// groups.
+    fn remove_groups(&self, groups: &HashSet<LinkId>, removal: Removal) -> Vec<LinkId> {
+        let mut removed = Vec::new();
+        self.inner.retain(|&id, entry| {
+            let keep = !groups.contains(&entry.group);
+            if !keep {
+                entry.link.cancel();
+                removed.push(id);
+            }
+            keep
+        });
+        self.bury(removed.iter().copied(), removal);
+        self.searches
+            .retain(|_, ids| !groups.contains(&ids.search_map));
+        self.records.retain(|group, _| !groups.contains(group));
+
+        removed
+    }
+
+    /// Get the `SearchMap` of a live search for the same query, if there is
+    /// one, refreshing it as if the client had called its refresher `link`.
+    pub fn shared_search(&self, query: &Query) -> Option<SearchMap> {
+        let ids = *self.searches.get(query)?;
+
+        let (Some(Link::SearchMap(search_map)), Some(Link::RefreshSearch(refresh))) =
+            (self.get(ids.search_map), self.get(ids.refresh))
+        else {
+            return None;
+        };
+
+        // searches that can't be refreshed anymore are searched again
+        refresh.refresh().ok()?;
+        tracing::info!("sharing query: {}", ids.search_map);
+
+        Some(search_map)
     }
 
     /// Get a list of free identifiers that can be used to insert new `Link`
     /// variants.
-    fn get_free_ids(&self, posts: &api::Posts) -> (Vec<(api::Post, PostIds)>, HeaderIds) {
-        let mut ids = (0_usize..).filter(|k| !self.inner.contains_key(k));
+    fn get_free_ids(
+        &self,
+        posts: &api::Posts,
+        query: &Query,
+        next_page: bool,
+    ) -> (Vec<(api::Post, PostIds)>, HeaderIds) {
+        let videos = shows_column(query, "video", 8);
+        let quests = shows_column(query, "quest", 6);
+        let previews = shows_column(query, "preview_link", 7);
+        let samples = shows_column(query, "sample_link", 7);
+        let originals = shows_column(query, "original_link", 7);
+
+        let mut ids = random_ids()
+            .filter(|k| !self.inner.contains_key(k))
+            .unique();
 
-        let id_tups = ids
-            .by_ref()
-            .take(posts.len() * 2)
-            .tuples()
-            .map(PostIds::new);
-        let post_ids = posts.iter().cloned().zip(id_tups).collect();
+        let post_ids = posts
+            .iter()
+            .map(|post| {
+                let (post_id, refresh) = ids.next_tuple().expect("never ending iter ended");
+                let video = (videos && post.is_video()).then(|| ids.next()).flatten();
+                let quest = quests.then(|| ids.next()).flatten();
+                let files = FileIds {
+                    preview: previews.then(|| ids.next()).flatten(),
+                    sample: (samples && post.sample_url().is_some())
+                        .then(|| ids.next())
+                        .flatten(),
+                    original: (originals && post.original_url().is_some())
+                        .then(|| ids.next())
+                        .flatten(),
+                };
+
+                let ids = PostIds::new((post_id, refresh), video, quest, files);
+                (post.clone(), ids)
+            })
+            .collect();
 
         let query_ids = HeaderIds {
             search_map: ids.next().expect("never ending iter ended"),
             preview: ids.next().expect("never ending iter ended"),
             refresh: ids.next().expect("never ending iter ended"),
+            next: next_page.then(|| ids.next()).flatten(),
         };
 
         (post_ids, query_ids)
     }
 
-    /// Insert an image `Link` into the map.
-    fn insert_image(&mut self, ids: PostIds, res: (LazyPromise<Option<Image>>, Refresher)) {
-        log::info!("inserting image: {}", ids.post);
+    /// Get a list of free identifiers for the continuation chunks of a
+    /// `SearchMap`, skipping the identifiers reserved for its header and
+    /// posts, which aren't in the map yet.
+    fn get_free_chunk_ids(
+        &self,
+        count: usize,
+        header: HeaderIds,
+        posts: &[PostIds],
+    ) -> Vec<LinkId> {
+        let reserved = [header.search_map, header.preview, header.refresh]
+            .into_iter()
+            .chain(header.next)
+            .chain(posts.iter().flat_map(|ids| ids.link_ids()))
+            .collect::<HashSet<_>>();
+
+        random_ids()
+            .filter(|k| !self.inner.contains_key(k) && !reserved.contains(k))
+            .unique()
+            .take(count)
+            .collect()
+    }
+
+    /// Insert the image `Link` of a post into the map, along with the `Link`
+    /// of its Quest variant, the `Link`s of its files, and its video `Link`,
+    /// if it has one.
+    ///
+    /// These links share the lifecycle of the image `Link` for the same post.
+    fn insert_post(&self, record: PostRecord, image: ImageSlot) {
+        let ids = record.ids;
+        tracing::info!("inserting image: {}", ids.post);
+
+        if let Some(quest) = ids.quest {
+            self.insert(quest, ids.post, Link::Image(quest_image(&image)));
+        }
+        if let Some(files) = ids.files {
+            let post = &record.post;
+            let urls = [
+                (files.preview, Some(post.preview.url.clone())),
+                (files.sample, post.sample_url()),
+            ];
+
+            for (id, url) in urls {
+                if let (Some(id), Some(url)) = (id, url) {
+                    self.insert(id, ids.post, Link::Image(file_image(url)));
+                }
+            }
+            if let (Some(id), Some(url)) = (files.original, post.original_url()) {
+                self.insert(id, ids.post, original_link(url));
+            }
+        }
+
+        self.insert(ids.post, ids.post, Link::Image(image));
+        self.insert(
+            ids.refresh,
+            ids.post,
+            Link::RefreshImage(record.refresher.clone()),
+        );
+        if let Some(video) = ids.video {
+            tracing::info!("inserting video: {video}");
+
+            let url = record.post.file.url.clone();
+            self.insert(video, ids.post, Link::Video(url));
+        }
 
-        self.inner.insert(ids.post, Link::Image(res.0));
-        self.inner.insert(ids.refresh, Link::RefreshImage(res.1));
+        self.records.insert(ids.post, Record::Post(record));
     }
 
     /// Remove an image `Link` from the map.
     ///
     /// This is called by the `RefreshHandler` after a certain period of time,
     /// unless a client calls its associated refresher `link`.
-    fn remove_image(&mut self, ids: PostIds) {
-        log::info!("removing image: {}", ids.post);
+    fn remove_image(&self, ids: PostIds) {
+        tracing::info!("removing image: {}", ids.post);
 
-        self.inner.remove(&ids.post);
-        self.inner.remove(&ids.refresh);
+        self.remove(ids.post, Removal::Expired);
+        self.remove(ids.refresh, Removal::Expired);
+        if let Some(video) = ids.video {
+            self.remove(video, Removal::Expired);
+        }
+        if let Some(quest) = ids.quest {
+            self.remove(quest, Removal::Expired);
+        }
+        for file in ids.files.iter().flat_map(|files| files.link_ids()) {
+            self.remove(file, Removal::Expired);
+        }
+        self.records.remove(&ids.post);
     }
 
-    /// Insert a preview `Link` into the map.
-    fn insert_preview(&mut self, ids: HeaderIds, res: Promise<Option<Image>>) {
-        log::info!("inserting preview: {}", ids.preview);
+    /// Insert the links of a search into the map: its `SearchMap` and the
+    /// continuation chunks of it, its preview, and its next page, if it has
+    /// one.
+    ///
+    /// Shared searches are also registered for `LinkMap::shared_search`.
+    fn insert_search(&self, record: SearchRecord, preview: ImageSlot) {
+        let ids = record.ids;
+        tracing::info!("inserting query: {}", ids.search_map);
+
+        self.insert(
+            ids.search_map,
+            ids.search_map,
+            Link::SearchMap(record.search_map.clone()),
+        );
+        self.insert(
+            ids.refresh,
+            ids.search_map,
+            Link::RefreshSearch(record.refresher.clone()),
+        );
+
+        for (id, chunk) in &record.chunks {
+            tracing::info!("inserting chunk: {id}");
+
+            self.insert(*id, ids.search_map, Link::Chunk(chunk.clone()));
+        }
+
+        if let Some(next) = ids.next {
+            tracing::info!("inserting next page: {next}");
+
+            let query = Query {
+                page: record.query.page.saturating_add(1),
+                ..record.query.clone()
+            };
+            self.insert(next, ids.search_map, Link::NextPage(query));
+        }
 
-        self.inner.insert(ids.preview, Link::Previews(res));
+        tracing::info!("inserting preview: {}", ids.preview);
+        self.insert(ids.preview, ids.search_map, Link::Previews(preview));
+
+        if record.shared {
+            self.searches.insert(record.query.clone(), ids);
+        }
+
+        self.records.insert(ids.search_map, Record::Search(record));
     }
 
     /// Remove a preview `Link` from the map.
     ///
     /// This is called by the `RefreshHandler` after a certain period of time,
     /// unless a client calls its associated refresher `link`.
-    fn remove_preview(&mut self, ids: HeaderIds) {
-        log::info!("removing preview: {}", ids.preview);
-
-        self.inner.remove(&ids.preview);
-    }
-
-    /// Insert a `SearchMap` `Link` into the map.
-    fn insert_query(&mut self, ids: HeaderIds, res: (SearchMap, Refresher)) {
-        log::info!("inserting query: {}", ids.search_map);
+    fn remove_preview(&self, ids: HeaderIds) {
+        tracing::info!("removing preview: {}", ids.preview);
 
-        self.inner.insert(ids.search_map, Link::SearchMap(res.0));
-        self.inner.insert(ids.refresh, Link::RefreshSearch(res.1));
+        self.remove(ids.preview, Removal::Expired);
     }
 
     /// Remove a `SearchMap` `Link` from the map.
     ///
     /// This is called by the `RefreshHandler` after a certain period of time,
     /// unless a client calls its associated refresher `link`.
-    fn remove_query(&mut self, ids: HeaderIds) {
-        log::info!("removing query: {}", ids.search_map);
+    fn remove_query(&self, ids: HeaderIds) {
+        tracing::info!("removing query: {}", ids.search_map);
 
-        self.inner.remove(&ids.search_map);
-        self.inner.remove(&ids.refresh);
+        self.remove(ids.search_map, Removal::Expired);
+        self.remove(ids.refresh, Removal::Expired);
+        if let Some(next) = ids.next {
+            self.remove(next, Removal::Expired);
+        }
+        self.searches
+            .retain(|_, search| search.search_map != ids.search_map);
+        self.records.remove(&ids.search_map);
+    }
+
+    /// Remove the continuation chunks of a `SearchMap` from the map.
+    fn remove_chunks(&self, ids: &[LinkId]) {
+        for id in ids {
+            tracing::info!("removing chunk: {id}");
+
+            self.remove(*id, Removal::Expired);
+        }
     }
 }
 
 /// From a list of `Posts` returned from the e621 API, create a `SearchMap`
 /// string that informs clients on how to fetch the posts returned by their
 /// search query.
-pub async fn setup_links(posts: api::Posts) -> SearchMap {
-    // obtain a mut LinkMap ref by locking the global struct.
-    let mut map = LinkMap::get_mut_ref().await;
+///
+/// The `SearchMap` is shared with later searches for the same query, which
+/// find it through `LinkMap::shared_search` while it is live, and has a
+/// `NextPage` link for the following page of results.
+pub async fn setup_shared_links(posts: api::Posts, query: &Query) -> SearchMap {
+    setup_links_inner(posts, query, true).await
+}
+
+/// From a list of `Posts` returned from the e621 API, create a `SearchMap`
+/// string that informs clients on how to fetch the posts returned by their
+/// search query.
+///
+/// Unlike `setup_shared_links`, the `SearchMap` is never shared with other
+/// searches and has no next page, which is required for queries with random
+/// results.
+pub async fn setup_links(posts: api::Posts, query: &Query) -> SearchMap {
+    setup_links_inner(posts, query, false).await
+}
+
+/// Create a `SearchMap` and its links.
+///
+/// Everything that needs to be awaited, like starting the preview, is done
+/// before the links are inserted, so that a search only shows up in the
+/// `LinkMap` once all of its links can be served.
+async fn setup_links_inner(posts: api::Posts, query: &Query, shared: bool) -> SearchMap {
+    let map = LinkMap::global();
+    let config = &Config::global().links;
 
     let refresh_handler = RefreshHandler::new();
-    let (post_ids, header_ids) = map.get_free_ids(&posts);
+    let (post_ids, header_ids) = map.get_free_ids(&posts, query, shared);
 
-    let mut builder = SeachMapBuilder::new_with_header(header_ids);
+    let mut builder = SeachMapBuilder::new_with_header(header_ids, query);
+    let mut post_records = Vec::with_capacity(post_ids.len());
 
-    for (post, ids) in post_ids {
-        builder.push_post(&post, ids);
+    for (post, ids) in &post_ids {
+        builder.push_post(post, *ids);
 
-        let refresher = refresh_handler.attach_with_local(1200, async move {
-            LinkMap::get_mut_ref().await.remove_image(ids);
+        post_records.push(PostRecord {
+            ids: *ids,
+            search: header_ids.search_map,
+            refresher: attach_post(&refresh_handler, *ids, config.post_ttl, None),
+            post: post.clone(),
         });
+    }
+
+    let post_ids = post_ids.into_iter().map(|(_, ids)| ids).collect_vec();
+    let (search_map, chunks) =
+        builder.into_query(|count| map.get_free_chunk_ids(count, header_ids, &post_ids));
+    let preview = {
+        let posts = posts.clone();
+        ImageSlot::eager(move |progress| image::make_preview(posts.clone(), progress).boxed())
+    };
 
-        let url = post.sample.url.clone();
-        let image = LazyPromise::new(api::get_image(url).map(Result::ok));
+    attach_search(&refresh_handler, header_ids, &chunks, config.search_ttl);
 
-        map.insert_image(ids, (image, refresher));
+    let record = SearchRecord {
+        ids: header_ids,
+        query: query.clone(),
+        shared,
+        posts,
+        post_ids,
+        search_map: search_map.clone(),
+        chunks,
+        refresher: refresh_handler.into_refresher(),
+    };
+
+    let mut published = Vec::new();
+    if store::enabled() {
+        published.extend(
+            post_records
+                .iter()
+                .map(|post| SavedGroup::Post(post.save())),
+        );
+        published.push(SavedGroup::Search(record.save()));
+    }
+
+    for post in post_records {
+        let image = post_image(&post.post);
+        map.insert_post(post, image);
+    }
+    map.insert_search(record, preview);
+    map.evict();
+
+    store::publish(&published).await;
+
+    search_map
+}
+
+/// Create the lazily loaded image of a post.
+fn post_image(post: &api::Post) -> ImageSlot {
+    let post = post.clone();
+    ImageSlot::lazy(move |progress| image::post_image(post.clone(), progress).boxed())
+}
+
+/// Create the Quest variant of a post's image, made from the image the first
+/// time it is requested.
+fn quest_image(image: &ImageSlot) -> ImageSlot {
+    image.derive(|image| image::quest(image).boxed())
+}
+
+/// Create the lazily loaded image of one of a post's files.
+fn file_image(url: Arc<str>) -> ImageSlot {
+    ImageSlot::lazy(move |progress| image::post_file(url.clone(), progress).boxed())
+}
+
+/// Create the link to a post's original file, which is streamed if the
+/// instance is configured to.
+fn original_link(url: Arc<str>) -> Link {
+    if Config::global().image.stream_originals {
+        Link::File(url)
+    } else {
+        Link::Image(file_image(url))
     }
+}
 
-    let search_map = builder.into_query();
-    let preview = Promise::new(image::make_preview(posts.clone())).await;
+/// Create the lazily loaded preview image of a search.
+fn preview_image(posts: &api::Posts) -> ImageSlot {
+    let posts = posts.clone();
+    ImageSlot::lazy(move |progress| image::make_preview(posts.clone(), progress).boxed())
+}
 
-    refresh_handler.attach(600, async move {
-        let mut map = LinkMap::get_mut_ref().await;
+/// Attach the teardown of a post's links to a search's `RefreshHandler`,
+/// after the given number of seconds, with the post's saved refresh limits if
+/// it is restored.
+fn attach_post(
+    handler: &RefreshHandler,
+    ids: PostIds,
+    len: u64,
+    limits: Option<SavedLimits>,
+) -> Refresher {
+    handler.attach_with_local(len, limits, async move {
+        LinkMap::global().remove_image(ids);
+    })
+}
+
+/// Attach the teardown of a search's links to its `RefreshHandler`, after the
+/// given number of seconds.
+fn attach_search(
+    handler: &RefreshHandler,
+    ids: HeaderIds,
+    chunks: &[(LinkId, SearchMap)],
+    len: u64,
+) {
+    let chunk_ids = chunks.iter().map(|(id, _)| *id).collect_vec();
+
+    handler.attach(len, async move {
+        let map = LinkMap::global();
 
-        map.remove_query(header_ids);
-        map.remove_preview(header_ids);
+        map.remove_query(ids);
+        map.remove_chunks(&chunk_ids);
+        map.remove_preview(ids);
     });
+}
+
+/// A snapshot of the links in the `LinkMap`, which can be saved to disk and
+/// restored after a restart.
+///
+/// Only what's needed to recreate the links is saved; images are downloaded
+/// again when they are first requested.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    searches: Vec<SearchSnapshot>,
+    posts: Vec<PostSnapshot>,
+}
+
+/// A saved group of links, as shared between instances through the link
+/// `store`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum SavedGroup {
+    Search(SearchSnapshot),
+    Post(PostSnapshot),
+}
 
-    map.insert_preview(header_ids, preview);
-    map.insert_query(
-        header_ids,
-        (search_map.clone(), refresh_handler.into_refresher()),
-    );
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SearchSnapshot {
+    ids: HeaderIds,
+    query: Query,
+    shared: bool,
+    posts: api::Posts,
+    #[serde(default)]
+    post_ids: Vec<PostIds>,
+    search_map: SearchMap,
+    chunks: Vec<(LinkId, SearchMap)>,
+    /// The time left before the search is torn down, in seconds.
+    ttl: u64,
+    /// How often the search was refreshed, and when it was created.
+    #[serde(default)]
+    limits: Option<SavedLimits>,
+}
 
-    search_map
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PostSnapshot {
+    ids: PostIds,
+    post: api::Post,
+    search: LinkId,
+    /// The time left before the post is torn down, in seconds.
+    ttl: u64,
+    /// How often the post was refreshed, and when it was created.
+    #[serde(default)]
+    limits: Option<SavedLimits>,
+}
+
+impl SearchRecord {
+    /// Save this search, with the time it has left and its refresh limits.
+    fn save(&self) -> SearchSnapshot {
+        SearchSnapshot {
+            ids: self.ids,
+            query: self.query.clone(),
+            shared: self.shared,
+            posts: self.posts.clone(),
+            post_ids: self.post_ids.clone(),
+            search_map: self.search_map.clone(),
+            chunks: self.chunks.clone(),
+            ttl: self.refresher.remaining().as_secs(),
+            limits: Some(self.refresher.limits()),
+        }
+    }
+}
+
+impl PostRecord {
+    /// Save this post, with the time it has left and its refresh limits.
+    fn save(&self) -> PostSnapshot {
+        PostSnapshot {
+            ids: self.ids,
+            post: self.post.clone(),
+            search: self.search,
+            ttl: self.refresher.remaining().as_secs(),
+            limits: Some(self.refresher.limits()),
+        }
+    }
+}
+
+impl SearchSnapshot {
+    /// The identifiers of every link of this search.
+    fn link_ids(&self) -> Vec<LinkId> {
+        let ids = self.ids;
+        let chunks = self.chunks.iter().map(|(id, _)| *id);
+
+        [ids.search_map, ids.preview, ids.refresh]
+            .into_iter()
+            .chain(ids.next)
+            .chain(chunks)
+            .collect()
+    }
+}
+
+impl PostIds {
+    /// The identifiers of every link of this post.
+    fn link_ids(self) -> Vec<LinkId> {
+        [self.post, self.refresh]
+            .into_iter()
+            .chain(self.video)
+            .chain(self.quest)
+            .chain(self.files.iter().flat_map(|files| files.link_ids()))
+            .collect()
+    }
+}
+
+impl PostIds {
+    /// Which of this post's images the link of an identifier serves, if it
+    /// serves one.
+    fn image_kind(self, id: LinkId) -> Option<&'static str> {
+        let files = self.files;
+
+        if id == self.post {
+            Some("image")
+        } else if self.quest == Some(id) {
+            Some("quest")
+        } else if files.is_some_and(|files| files.preview == Some(id)) {
+            Some("preview")
+        } else if files.is_some_and(|files| files.sample == Some(id)) {
+            Some("sample")
+        } else if files.is_some_and(|files| files.original == Some(id)) {
+            Some("original")
+        } else {
+            None
+        }
+    }
+}
+
+impl FileIds {
+    /// The identifiers of the links of these files.
+    fn link_ids(self) -> impl Iterator<Item = LinkId> {
+        [self.preview, self.sample, self.original]
+            .into_iter()
+            .flatten()
+    }
+}
+
+/// The identifiers of a group of links, and how long refreshing it keeps the
+/// links alive for, in seconds.
+pub struct GroupKeys {
+    pub group: LinkId,
+    pub links: Vec<LinkId>,
+    pub len: u64,
+}
+
+impl SavedGroup {
+    /// The identifiers of the links in this group.
+    pub fn keys(&self) -> GroupKeys {
+        match self {
+            Self::Search(search) => GroupKeys {
+                group: search.ids.search_map,
+                links: search.link_ids(),
+                len: Config::global().links.search_ttl,
+            },
+            Self::Post(post) => GroupKeys {
+                group: post.ids.post,
+                links: post.ids.link_ids(),
+                len: Config::global().links.post_ttl,
+            },
+        }
+    }
+
+    /// The identifiers of the groups that are refreshed along with this one,
+    /// including itself. Refreshing a search also refreshes its posts.
+    pub fn refreshed(&self) -> Vec<GroupKeys> {
+        let mut keys = vec![self.keys()];
+
+        if let Self::Search(search) = self {
+            keys.extend(search.post_ids.iter().map(|ids| GroupKeys {
+                group: ids.post,
+                links: ids.link_ids(),
+                len: Config::global().links.post_ttl,
+            }));
+        }
+
+        keys
+    }
+
+    /// The time left before this group is torn down, in seconds.
+    pub const fn ttl(&self) -> u64 {
+        match self {
+            Self::Search(search) => search.ttl,
+            Self::Post(post) => post.ttl,
+        }
+    }
+
+    /// Set the time left before this group is torn down, in seconds.
+    pub fn set_ttl(&mut self, ttl: u64) {
+        match self {
+            Self::Search(search) => search.ttl = ttl,
+            Self::Post(post) => post.ttl = ttl,
+        }
+    }
+
+    /// Count refreshes of this group made after it was saved.
+    pub fn add_refreshes(&mut self, refreshes: u32) {
+        let limits = match self {
+            Self::Search(search) => &mut search.limits,
+            Self::Post(post) => &mut post.limits,
+        };
+
+        if let Some(limits) = limits {
+            limits.refreshes = limits.refreshes.saturating_add(refreshes);
+        }
+    }
+}
+
+impl LinkMap {
+    /// Take a snapshot of the links in the global `LinkMap`.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot {
+            searches: Vec::new(),
+            posts: Vec::new(),
+        };
+
+        for record in self.records.iter() {
+            match record.value() {
+                Record::Search(record) => snapshot.searches.push(record.save()),
+                Record::Post(record) => snapshot.posts.push(record.save()),
+            }
+        }
+
+        snapshot
+    }
+
+    /// Restore the links in a snapshot into the global `LinkMap`, as if they
+    /// had been created by searches, with the given number of seconds
+    /// elapsed since the snapshot was taken.
+    ///
+    /// Posts are still refreshed by the search they were found by, if it is
+    /// restored too. Refresh limits carry on from where they were when the
+    /// snapshot was taken.
+    pub fn restore(&self, snapshot: Snapshot, elapsed: u64) {
+        let remaining = |ttl: u64| ttl.checked_sub(elapsed).filter(|&ttl| ttl > 0);
+
+        let mut handlers = HashMap::new();
+        for search in &snapshot.searches {
+            handlers.insert(
+                search.ids.search_map,
+                RefreshHandler::restored(search.limits),
+            );
+        }
+
+        let mut restored = 0;
+        for mut post in snapshot.posts {
+            let Some(ttl) = remaining(post.ttl) else {
+                continue;
+            };
+            post.ttl = ttl;
+
+            // posts whose search isn't restored get a handler of their own
+            match handlers.get(&post.search) {
+                Some(handler) => self.restore_post(post, handler),
+                None => self.restore_post(post, &RefreshHandler::new()),
+            }
+            restored += 1;
+        }
+
+        for mut search in snapshot.searches {
+            let (Some(handler), Some(ttl)) = (
+                handlers.remove(&search.ids.search_map),
+                remaining(search.ttl),
+            ) else {
+                continue;
+            };
+            search.ttl = ttl;
+
+            self.restore_search(search, handler);
+            restored += 1;
+        }
+
+        tracing::info!("restored {restored} link groups");
+        self.evict();
+    }
+
+    /// Get a `Link` that isn't in the global `LinkMap` from the link `store`
+    /// shared between instances, restoring its group of links into the map.
+    pub async fn get_stored(&self, id: LinkId) -> Option<Link> {
+        let group = store::fetch(id).await?;
+
+        match group {
+            SavedGroup::Search(search) => {
+                let handler = RefreshHandler::restored(search.limits);
+                self.restore_search(search, handler);
+            }
+            SavedGroup::Post(post) => self.restore_post(post, &RefreshHandler::new()),
+        }
+        self.evict();
+
+        self.get(id)
+    }
+
+    /// Restore the links of a saved post, attached to the given handler.
+    fn restore_post(&self, post: PostSnapshot, handler: &RefreshHandler) {
+        let record = PostRecord {
+            ids: post.ids,
+            search: post.search,
+            refresher: attach_post(handler, post.ids, post.ttl, post.limits),
+            post: post.post,
+        };
+        let image = post_image(&record.post);
+
+        self.insert_post(record, image);
+    }
+
+    /// Restore the links of a saved search. Its preview is made again when
+    /// it is first requested.
+    fn restore_search(&self, search: SearchSnapshot, handler: RefreshHandler) {
+        attach_search(&handler, search.ids, &search.chunks, search.ttl);
+
+        let record = SearchRecord {
+            ids: search.ids,
+            query: search.query,
+            shared: search.shared,
+            posts: search.posts,
+            post_ids: search.post_ids,
+            search_map: search.search_map,
+            chunks: search.chunks,
+            refresher: handler.into_refresher(),
+        };
+        let preview = preview_image(&record.posts);
+
+        self.insert_search(record, preview);
+    }
 }
 
 /// Helper struct that names the identifiers for a `SearchMap` header.
-#[derive(Clone, Copy)]
-struct HeaderIds {
-    search_map: usize,
-    preview: usize,
-    refresh: usize,
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct HeaderIds {
+    search_map: LinkId,
+    preview: LinkId,
+    refresh: LinkId,
+    /// Only searches have a next page link.
+    next: Option<LinkId>,
 }
 
 /// Helper struct that names the identifiers for a `SearchMap` post.
-#[derive(Clone, Copy)]
-struct PostIds {
-    post: usize,
-    refresh: usize,
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct PostIds {
+    post: LinkId,
+    refresh: LinkId,
+    /// Only video posts shown with the `video` column have a video link.
+    video: Option<LinkId>,
+    /// The link of the Quest variant of the image, if the post is shown with
+    /// the `quest` column. Posts saved before Quest variants existed don't
+    /// have one.
+    #[serde(default)]
+    quest: Option<LinkId>,
+    /// The links of the post's files. Posts saved before file links existed
+    /// don't have them.
+    #[serde(default)]
+    files: Option<FileIds>,
 }
 
 impl PostIds {
-    /// Create a new `PostIds` from a pair of identifiers.
-    const fn new(ids: (usize, usize)) -> Self {
+    /// Create a new `PostIds` from a pair of identifiers, the identifiers of
+    /// the video link and the Quest variant link, if the post has them, and
+    /// of the file links.
+    const fn new(
+        ids: (LinkId, LinkId),
+        video: Option<LinkId>,
+        quest: Option<LinkId>,
+        files: FileIds,
+    ) -> Self {
         Self {
             post: ids.0,
             refresh: ids.1,
+            video,
+            quest,
+            files: Some(files),
         }
     }
 }
 
+/// Helper struct that names the identifiers of the links to a post's
+/// preview, sample and original file. Links are only created for the file
+/// columns the post is shown with.
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct FileIds {
+    preview: Option<LinkId>,
+    /// Only posts with a still sample image have a sample link.
+    sample: Option<LinkId>,
+    /// Only still posts under the maximum file size have an original link.
+    original: Option<LinkId>,
+}
+
 /// A `SearchMap` string.
 ///
 /// This is a type alias for an `Arc<str>`, which is a reference-counted string.
@@ -228,57 +1259,434 @@ impl PostIds {
 /// without needing to clone the inner data.
 type SearchMap = Arc<str>;
 
+/// The `SearchMap` format versions supported by `SeachMapBuilder`.
+///
+/// Version 1 is the format of the original proxy. Later versions add columns
+/// to the end of each post row, so that clients can opt into them without
+/// breaking older clients.
+pub const SEARCH_MAP_VERSIONS: std::ops::RangeInclusive<u32> = 1..=8;
+
+/// The post columns that clients can select with `cols:`, in the order they
+/// appear in a `SearchMap` row.
+pub const COLUMNS: &[&str] = &[
+    "link",
+    "id",
+    "sample_width",
+    "sample_height",
+    "preview_width",
+    "preview_height",
+    "score_up",
+    "score_down",
+    "rating",
+    "ext",
+    "refresh",
+    "refresh_ttl",
+    "size",
+    "md5",
+    "artist",
+    "character",
+    "species",
+    "uv_x",
+    "uv_y",
+    "uv_width",
+    "uv_height",
+    "quest",
+    "preview_link",
+    "sample_link",
+    "original_link",
+    "video",
+    "score",
+];
+
+/// The format version of columns that are only emitted when selected by the
+/// client, or in JSON `SearchMap`s.
+const SELECTED_ONLY: u32 = u32::MAX;
+
+/// Whether the `SearchMap` of a query shows a post column from the given
+/// version, because it is a JSON `SearchMap`, its version has the column, or
+/// it selects the column, so that links nobody will see aren't created.
+fn shows_column(query: &Query, name: &str, version: u32) -> bool {
+    match &query.columns {
+        Some(columns) => columns.iter().any(|column| column == name),
+        None => query.format == Format::Json || version <= query.version,
+    }
+}
+
+/// The maximum length of a delimited `SearchMap` string before it is split
+/// into chunks.
+const MAX_CHUNK_LEN: usize = 16 * 1024;
+
+/// The maximum length of a tag column in a `SearchMap` row.
+const MAX_TAG_COLUMN_LEN: usize = 100;
+
+/// The format a `SearchMap` is emitted in.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub enum Format {
+    /// The comma and newline delimited format of the original proxy.
+    #[default]
+    Classic,
+    /// Strict tab-separated values. Every post row has the same columns, in
+    /// the same order, and no field ever contains a tab or a newline.
+    ///
+    /// In both delimited formats, fields are escaped by `escape`.
+    Tsv,
+    /// A JSON document, for consumers other than VRChat worlds. It contains
+    /// every column, regardless of the format version.
+    Json,
+}
+
+impl Format {
+    /// Parse a format from its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "classic" => Some(Self::Classic),
+            "tsv" => Some(Self::Tsv),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A single field of a `SearchMap` header or post row.
+///
+/// Fields are kept as JSON values until the `SearchMap` is emitted, so that
+/// every format is built from the same columns.
+struct Column {
+    /// The name of the column, used as the key in JSON `SearchMap`s.
+    name: &'static str,
+    /// The first format version that has this column.
+    version: u32,
+    value: serde_json::Value,
+}
+
+impl Column {
+    /// Create a new column, present from the given format version onwards.
+    fn new(name: &'static str, version: u32, value: impl Into<serde_json::Value>) -> Self {
+        Self {
+            name,
+            version,
+            value: value.into(),
+        }
+    }
+
+    /// Create a new column holding link ids, signed if the instance signs
+    /// them. Unsigned ids stay numbers in JSON `SearchMap`s.
+    fn link(name: &'static str, version: u32, id: impl Into<Option<LinkId>>) -> Self {
+        let value: serde_json::Value = match id.into() {
+            Some(id) if signing::enabled() => signing::sign(id).into(),
+            id => id.into(),
+        };
+        Self::new(name, version, value)
+    }
+
+    /// Format the value of this column as a field of a delimited `SearchMap`.
+    ///
+    /// Missing values are empty, and lists of tags are joined with spaces by
+    /// `tag_column`.
+    fn field(&self) -> String {
+        match &self.value {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(value) => value.clone(),
+            serde_json::Value::Array(tags) => {
+                let tags = tags.iter().filter_map(|tag| tag.as_str());
+                tag_column(tags)
+            }
+            value => value.to_string(),
+        }
+    }
+}
+
 /// A builder for creating a `SearchMap` string.
 ///
 /// This builder is a helper for creating the string returned by the `e.roli.ga`
-/// `/s/` endpoint. The format is described in the `new_with_header` and
-/// `push_post` methods.
-struct SeachMapBuilder(String);
+/// `/s/` endpoint. The columns are described in the `new_with_header` and
+/// `push_post` methods, and are emitted in the requested `Format` once the
+/// builder is finished.
+struct SeachMapBuilder {
+    version: u32,
+    format: Format,
+    /// The post columns selected by the client, in the order they should be
+    /// emitted, if any.
+    columns: Option<Vec<String>>,
+    header: Vec<Column>,
+    rows: Vec<Vec<Column>>,
+}
 
 impl SeachMapBuilder {
-    /// Construct a new `SearchMapBuilder`.
+    /// Construct a new `SearchMapBuilder` for a given format version.
     ///
-    /// This function builds the headers for the `SearchMap` string.
-    fn new_with_header(ids: HeaderIds) -> Self {
-        let mut this = Self(String::new());
-        this.push_element::<' '>("600000")
-            .push_element::<','>(&ids.search_map.to_string())
-            .push_element::<','>(&ids.preview.to_string())
-            .push_element::<','>(&ids.refresh.to_string());
-        this
-    }
-
-    /// Push `Post` metadata to the inner  `SearchMap` string, along with it's
-    /// `link` ids.
+    /// This function builds the headers for the `SearchMap` string. From
+    /// version 2 onwards, the header ends with the format version, so that
+    /// clients can check that they got the layout they asked for. TSV and
+    /// JSON headers always have the format version. Version 5 adds the id of
+    /// the `NextPage` link after the version, which is empty for `SearchMap`s
+    /// without one.
+    fn new_with_header(ids: HeaderIds, query: &Query) -> Self {
+        let (version, format) = (query.version, query.format);
+        let version_column = if format == Format::Classic { 2 } else { 1 };
+        let config = &Config::global().links;
+
+        let header = vec![
+            Column::new("ttl", 1, config.search_ttl * 1000),
+            Column::link("search_map", 1, ids.search_map),
+            Column::link("preview", 1, ids.preview),
+            Column::link("refresh", 1, ids.refresh),
+            Column::new("version", version_column, version),
+            Column::link("next", 5, ids.next),
+        ];
+
+        Self {
+            version,
+            format,
+            columns: query.columns.clone(),
+            header,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Push `Post` metadata to the `SearchMap`, along with it's `link` ids.
+    ///
+    /// Version 2 adds the size of the post's original file in bytes, and its
+    /// MD5 hash, which clients can use to cache images across sessions. Version
+    /// 3 adds the post's artist, character and species tags, as space-separated
+    /// lists. Version 4 adds the area of the preview grid covered by the post's
+    /// preview, as a UV rect of x, y, width and height; see `image::tile_rect`.
+    /// Version 6 adds the id of the post's Quest `link`, a JPEG variant of its
+    /// image small enough for Quest clients. Version 7 adds the ids of the
+    /// `link`s to the post's preview, sample and original file, so that clients
+    /// can trade fidelity for download size. The sample link is empty for
+    /// animated posts without a still sample, and the original link is empty
+    /// for animated or oversized posts. Version 8 adds the id of the post's
+    /// video `link`, which is empty unless the post is a video.
+    ///
+    /// The `score` column, the post's total score, is only emitted when the
+    /// client selects it. Clients that select columns get exactly the columns
+    /// they asked for, in the order they asked for them, regardless of the
+    /// format version.
     fn push_post(&mut self, post: &api::Post, ids: PostIds) -> &mut Self {
-        self.push_element::<'\n'>(&ids.post.to_string())
-            .push_element::<','>(&post.id.to_string())
-            .push_element::<','>(&post.sample.width.to_string())
-            .push_element::<','>(&post.sample.height.to_string())
-            .push_element::<','>(&post.preview.width.to_string())
-            .push_element::<','>(&post.preview.height.to_string())
-            .push_element::<','>(&post.score.up.to_string())
-            .push_element::<','>(&post.score.down.to_string())
-            .push_element::<','>(&post.rating)
-            .push_element::<','>(&post.file.ext)
-            .push_element::<','>(&ids.refresh.to_string())
-            .push_element::<','>("1200000")
-    }
-
-    /// Push an element to the inner `SearchMap` string.
-    fn push_element<const SEPARATOR: char>(&mut self, element: &str) -> &mut Self {
-        match SEPARATOR {
-            ',' => self.0.push(','),
-            '\n' => self.0.push('\n'),
-            ' ' => (),
-            _ => unreachable!(),
-        }
-        self.0.push_str(element);
+        let index = u32::try_from(self.rows.len()).unwrap_or(u32::MAX);
+        let uv = image::tile_rect(index, post);
+        let files = ids.files;
+        let config = &Config::global().links;
+
+        self.rows.push(vec![
+            Column::link("link", 1, ids.post),
+            Column::new("id", 1, post.id),
+            Column::new("sample_width", 1, post.sample.width),
+            Column::new("sample_height", 1, post.sample.height),
+            Column::new("preview_width", 1, post.preview.width),
+            Column::new("preview_height", 1, post.preview.height),
+            Column::new("score_up", 1, post.score.up),
+            Column::new("score_down", 1, post.score.down),
+            Column::new("rating", 1, &*post.rating),
+            Column::new("ext", 1, &*post.file.ext),
+            Column::link("refresh", 1, ids.refresh),
+            Column::new("refresh_ttl", 1, config.post_ttl * 1000),
+            Column::new("size", 2, post.file.size),
+            Column::new("md5", 2, &*post.file.md5),
+            Column::new("artist", 3, tag_list(&post.tags.artist)),
+            Column::new("character", 3, tag_list(&post.tags.character)),
+            Column::new("species", 3, tag_list(&post.tags.species)),
+            Column::new("uv_x", 4, uv_value(uv.x)),
+            Column::new("uv_y", 4, uv_value(uv.y)),
+            Column::new("uv_width", 4, uv_value(uv.width)),
+            Column::new("uv_height", 4, uv_value(uv.height)),
+            Column::link("quest", 6, ids.quest),
+            Column::link("preview_link", 7, files.and_then(|files| files.preview)),
+            Column::link("sample_link", 7, files.and_then(|files| files.sample)),
+            Column::link("original_link", 7, files.and_then(|files| files.original)),
+            Column::link("video", 8, ids.video),
+            Column::new("score", SELECTED_ONLY, post.score.up + post.score.down),
+        ]);
+
         self
     }
 
+    /// Whether a header column is emitted, given the format and version
+    /// requested. Post columns are chosen like `shows_column`.
+    fn includes(&self, column: &Column) -> bool {
+        self.format == Format::Json || column.version <= self.version
+    }
+
+    /// The columns of a post row that are emitted, in order.
+    fn select<'a>(&self, row: &'a [Column]) -> Vec<&'a Column> {
+        match &self.columns {
+            Some(names) => names
+                .iter()
+                .filter_map(|name| row.iter().find(|column| column.name == name))
+                .collect(),
+            None => row.iter().filter(|column| self.includes(column)).collect(),
+        }
+    }
+
+    /// Emit the lines of a delimited `SearchMap`: the header, followed by one
+    /// line per post row.
+    fn lines(&self, separator: char) -> (String, Vec<String>) {
+        let line = |columns: Vec<&Column>| {
+            columns
+                .into_iter()
+                .map(|column| escape(&column.field(), separator))
+                .join(&separator.to_string())
+        };
+
+        let header = self.header.iter().filter(|column| self.includes(column));
+        let rows = self.rows.iter().map(|row| line(self.select(row)));
+
+        (line(header.collect()), rows.collect())
+    }
+
+    /// Emit a JSON `SearchMap`, with the posts under a `posts` key.
+    fn json(&self) -> String {
+        let object = |columns: Vec<&Column>| {
+            columns
+                .into_iter()
+                .map(|column| (column.name.to_owned(), column.value.clone()))
+                .collect::<serde_json::Map<_, _>>()
+        };
+
+        let mut json = object(self.header.iter().collect());
+        let posts = self
+            .rows
+            .iter()
+            .map(|row| object(self.select(row)).into())
+            .collect();
+        json.insert("posts".into(), serde_json::Value::Array(posts));
+
+        serde_json::Value::Object(json).to_string()
+    }
+
     /// Convert the `SearchMapBuilder` into a `SearchMap`.
-    fn into_query(self) -> SearchMap {
-        Arc::from(self.0.into_boxed_str())
+    ///
+    /// Delimited `SearchMap`s longer than `MAX_CHUNK_LEN` are split between
+    /// rows into chunks. The first chunk is the `SearchMap` itself, whose
+    /// header ends with a `chunks` column: the space-separated `link` ids of
+    /// the remaining chunks, which only contain post rows. Clients append
+    /// each chunk to the `SearchMap` on a new line. The ids are allocated
+    /// with `chunk_ids`, and returned along with their chunks.
+    fn into_query(
+        mut self,
+        chunk_ids: impl FnOnce(usize) -> Vec<LinkId>,
+    ) -> (SearchMap, Vec<(LinkId, SearchMap)>) {
+        let separator = match self.format {
+            Format::Classic => ',',
+            Format::Tsv => '\t',
+            Format::Json => return (Arc::from(self.json().into_boxed_str()), Vec::new()),
+        };
+
+        let (header, rows) = self.lines(separator);
+        let mut chunks = vec![Vec::new()];
+        let mut len = header.len();
+
+        for row in rows {
+            let row_len = row.len() + 1;
+            let chunk = chunks.last_mut().expect("chunks is never empty");
+
+            if len + row_len > MAX_CHUNK_LEN && !chunk.is_empty() {
+                chunks.push(vec![row]);
+                len = row_len;
+            } else {
+                chunk.push(row);
+                len += row_len;
+            }
+        }
+
+        let mut chunks = chunks.into_iter().map(|rows| rows.join("\n"));
+        let first = chunks.next().expect("chunks is never empty");
+        let rest = chunk_ids(chunks.len())
+            .into_iter()
+            .zip(chunks)
+            .collect_vec();
+
+        let header = if rest.is_empty() {
+            header
+        } else {
+            let ids = rest.iter().map(|(id, _)| signing::sign(*id)).join(" ");
+            self.header.push(Column::new("chunks", 1, ids));
+            self.lines(separator).0
+        };
+
+        let search_map = if first.is_empty() {
+            header
+        } else {
+            format!("{header}\n{first}")
+        };
+        let rest = rest
+            .into_iter()
+            .map(|(id, chunk)| (id, Arc::from(chunk.into_boxed_str())))
+            .collect();
+
+        (Arc::from(search_map.into_boxed_str()), rest)
+    }
+}
+
+/// Escape a field of a delimited `SearchMap`, so that it can't be mistaken
+/// for a separator.
+///
+/// The separator, `%` and any control characters (including newlines and
+/// tabs) are percent-encoded, as in URLs. Fields without them, which is
+/// nearly every field e621 returns, are left unchanged.
+fn escape(field: &str, separator: char) -> String {
+    let mut escaped = String::with_capacity(field.len());
+
+    for c in field.chars() {
+        if c == separator || c == '%' || c.is_control() {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{byte:02X}"));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+/// Round a texture coordinate to a precision that is enough for a preview
+/// grid, to keep `SearchMap` rows short.
+fn uv_value(coordinate: f64) -> f64 {
+    (coordinate * 10_000.0).round() / 10_000.0
+}
+
+/// Convert a list of tags into a JSON value.
+fn tag_list(tags: &[Arc<str>]) -> serde_json::Value {
+    tags.iter()
+        .map(|tag| serde_json::Value::from(&**tag))
+        .collect()
+}
+
+/// Join a list of tags into a `SearchMap` column, separated by spaces.
+///
+/// The column is capped at `MAX_TAG_COLUMN_LEN`; tags that would go over the
+/// cap are left out.
+fn tag_column<'a>(tags: impl IntoIterator<Item = &'a str>) -> String {
+    let mut column = String::new();
+
+    for tag in tags {
+        if column.len() + tag.len() + 1 > MAX_TAG_COLUMN_LEN {
+            break;
+        }
+
+        if !column.is_empty() {
+            column.push(' ');
+        }
+        column.push_str(tag);
+    }
+
+    column
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("safe", ','), "safe");
+        assert_eq!(escape("a,b\tc", ','), "a%2Cb%09c");
+        assert_eq!(escape("a,b\tc", '\t'), "a,b%09c");
+        assert_eq!(escape("100%\nfox", ','), "100%25%0Afox");
     }
 }
@@ -0,0 +1,238 @@
+//! Where logs are written.
+//!
+//! Records and spans are collected by a `tracing` subscriber, along with the
+//! records of dependencies that use `log`. Logs go to journald by default, like
+//! they always have, but hosts without it, like macOS, Windows or most
+//! containers, can log to stderr or to a file of JSON lines instead. Records
+//! carry the fields of the spans they are written in, like the ID of the
+//! request they are written for. If traces are exported, the exporter is
+//! another output of the same subscriber, so that spans are traced and logged
+//! alike.
+//!
+//! Log files are rotated once they get too big or too old, keeping a limited
+//! number of old files. Records are written to stderr until the configured
+//! output is set up, and if it can't be, so that nothing logged at startup is
+//! lost.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use tracing_journald::{Priority, PriorityMappings};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
+
+use crate::config::{Config, LogOutput};
+use crate::trace;
+
+/// An output of the subscriber.
+pub type Output = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// The handle that sets the outputs of the installed subscriber.
+static OUTPUTS: OnceLock<reload::Handle<Vec<Output>, Registry>> = OnceLock::new();
+
+/// Install the subscriber, writing to stderr until `init` sets up the
+/// configured output.
+pub fn install() {
+    let (outputs, handle) = reload::Layer::new(vec![stderr()]);
+
+    tracing_subscriber::registry()
+        .with(outputs)
+        .with(LevelFilter::INFO)
+        .try_init()
+        .expect("subscriber already installed");
+    let _ = OUTPUTS.set(handle);
+}
+
+/// Set up the configured output, falling back to stderr if it can't be, and
+/// the trace exporter if one is configured.
+pub fn init() {
+    let config = &Config::global().log;
+
+    let output = match config.output {
+        LogOutput::Journald => journald(),
+        LogOutput::Stderr => Ok(stderr()),
+        LogOutput::JsonFile => JsonFile::open(&config.path).map(json_file),
+    };
+
+    let output = output.unwrap_or_else(|e| {
+        tracing::warn!("can't log to {:?}, logging to stderr: {e}", config.output);
+        stderr()
+    });
+
+    set_outputs(std::iter::once(output).chain(trace::output()).collect());
+}
+
+/// Replace the outputs of the installed subscriber.
+fn set_outputs(outputs: Vec<Output>) {
+    let Some(handle) = OUTPUTS.get() else {
+        return;
+    };
+
+    if let Err(e) = handle.reload(outputs) {
+        tracing::error!("failed to set up logging: {e}");
+    }
+}
+
+/// Logs to the systemd journal, with the fields of each record, and of the
+/// spans it is in, as journal fields.
+fn journald() -> io::Result<Output> {
+    // the priorities `log` records were sent with
+    let mut priorities = PriorityMappings::new();
+    priorities.info = Priority::Informational;
+    priorities.debug = Priority::Debug;
+
+    let journald = tracing_journald::layer()?
+        .with_field_prefix(None)
+        .with_priority_mappings(priorities);
+
+    Ok(journald.boxed())
+}
+
+/// Logs records as lines of text on stderr, after the spans they are in.
+fn stderr() -> Output {
+    fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .boxed()
+}
+
+/// Logs records as JSON objects to a log file, with their fields at the top
+/// level, the span they are in, and the spans around it as a list.
+fn json_file(file: JsonFile) -> Output {
+    let file = Arc::new(file);
+
+    fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_span_list(true)
+        .with_writer(move || JsonWriter(file.clone()))
+        .boxed()
+}
+
+/// A file of JSON lines, rotated as configured.
+struct JsonFile {
+    path: PathBuf,
+    current: Mutex<Current>,
+}
+
+/// The log file being appended to.
+struct Current {
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl JsonFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            current: Mutex::new(Current::open(path)?),
+        })
+    }
+
+    /// Write a line to the log file, rotating it first if it is due.
+    fn write_line(&self, line: &[u8]) -> io::Result<()> {
+        let config = &Config::global().log;
+        let mut current = self.current.lock().expect("poisoned");
+
+        let too_big = config
+            .max_size
+            .is_some_and(|max| current.size > 0 && current.size + line.len() as u64 > max);
+        let too_old = config
+            .rotate_interval
+            .is_some_and(|secs| current.opened.elapsed() >= Duration::from_secs(secs));
+        if too_big || too_old {
+            rotate(&self.path, config.max_files)?;
+            *current = Current::open(&self.path)?;
+        }
+
+        current.file.write_all(line)?;
+        current.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Current {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+}
+
+/// Move a log file to `path.1`, and its rotated files one number up, deleting
+/// the ones past `keep`.
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let rotated = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    };
+
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+
+    match fs::remove_file(rotated(keep)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for n in (1..keep).rev() {
+        match fs::rename(rotated(n), rotated(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    fs::rename(path, rotated(1))
+}
+
+/// A handle to the log file, for the subscriber to write a record through.
+struct JsonWriter(Arc<JsonFile>);
+
+impl Write for JsonWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // records are written whole, a line at a time
+        self.0.write_line(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.current.lock().expect("poisoned").file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("roli_proxy_rotate_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.log");
+        let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+
+        for n in 1..=4 {
+            fs::write(&path, n.to_string()).unwrap();
+            super::rotate(&path, 2).unwrap();
+        }
+
+        assert_eq!(read("proxy.log"), None);
+        assert_eq!(read("proxy.log.1").as_deref(), Some("4"));
+        assert_eq!(read("proxy.log.2").as_deref(), Some("3"));
+        assert_eq!(read("proxy.log.3"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! A map that keeps its values within a budget of bytes.
+//!
+//! Both the images held by links and the cache of downloaded images are kept
+//! within a configured number of bytes, by dropping the least recently used
+//! images past it. `ByteLru` does the accounting for both: every value is
+//! inserted with its size, and the keys are kept in the order they were last
+//! used in, so that evicting doesn't have to sort every entry.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map of values with their sizes, ordered by when they were last used.
+pub struct ByteLru<K, V> {
+    /// The total size of the values, in bytes.
+    used: usize,
+    /// A counter that orders accesses to the values.
+    clock: u64,
+    entries: HashMap<K, Entry<V>>,
+    /// The keys of the entries, by the clock at their last access.
+    order: BTreeMap<u64, K>,
+}
+
+/// A value in a `ByteLru`.
+struct Entry<V> {
+    value: V,
+    size: usize,
+    /// The clock at the last access of the value.
+    used_at: u64,
+}
+
+impl<K, V> Default for ByteLru<K, V> {
+    fn default() -> Self {
+        Self {
+            used: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> ByteLru<K, V> {
+    /// The total size of the values, in bytes.
+    pub const fn used(&self) -> usize {
+        self.used
+    }
+
+    /// The number of values.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get the value of a key, marking it as recently used.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.entries.get_mut(key)?;
+
+        self.clock += 1;
+        let key = self.order.remove(&entry.used_at).expect("entry is ordered");
+        self.order.insert(self.clock, key);
+        entry.used_at = self.clock;
+
+        Some(&entry.value)
+    }
+
+    /// Insert the value of a key with its size, as the most recently used
+    /// value. The value it replaces, if any, is returned.
+    pub fn insert(&mut self, key: K, value: V, size: usize) -> Option<V> {
+        let old = self.remove(&key);
+
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                used_at: self.clock,
+            },
+        );
+        self.used += size;
+
+        old
+    }
+
+    /// Remove the value of a key.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.entries.remove(key)?;
+
+        self.order.remove(&entry.used_at);
+        self.used -= entry.size;
+
+        Some(entry.value)
+    }
+
+    /// Remove the least recently used values until the total size is within
+    /// the budget, keeping the value of the given key. The removed values are
+    /// returned, least recently used first.
+    pub fn evict(&mut self, budget: usize, keep: Option<&K>) -> Vec<V> {
+        let mut evicted = Vec::new();
+        let mut kept = None;
+
+        while self.used > budget {
+            let Some((used_at, key)) = self.order.pop_first() else {
+                break;
+            };
+            if keep == Some(&key) {
+                kept = Some((used_at, key));
+                continue;
+            }
+
+            let entry = self.entries.remove(&key).expect("ordered key has entry");
+            self.used -= entry.size;
+            evicted.push(entry.value);
+        }
+
+        if let Some((used_at, key)) = kept {
+            self.order.insert(used_at, key);
+        }
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ByteLru;
+
+    #[test]
+    fn test_evict() {
+        let mut lru = ByteLru::default();
+        lru.insert("a", 1, 10);
+        lru.insert("b", 2, 10);
+        lru.insert("c", 3, 10);
+        assert_eq!(lru.used(), 30);
+
+        // "a" is now the most recently used
+        assert_eq!(lru.get("a"), Some(&1));
+        assert_eq!(lru.insert("b", 4, 5), Some(2));
+        assert_eq!(lru.used(), 25);
+
+        assert_eq!(lru.evict(15, Some(&"c")), [1]);
+        assert_eq!(lru.used(), 15);
+        assert_eq!(lru.evict(0, None), [3, 4]);
+        assert_eq!(lru.len(), 0);
+        assert_eq!(lru.remove("c"), None);
+    }
+}
@@ -12,7 +12,167 @@
 //! # Features
 //!
 //! - Explicit Results: This calls out to the e621 API instead of the e926 API.
-//! - Pagination: The user may additionally specify a page number.
+//! - Pagination: The user may additionally specify a page number, with
+//!   `page:N` or `p:N`.
+//! - Instance Blacklist: Operators may exclude additional tags from every
+//!   search, on top of the proxy's own non-removable excludes.
+//! - Blacklist Presets: Named blacklists defined by the instance can be
+//!   enabled by adding `!name` to a query, e.g. `!nogore`.
+//! - Client Blacklists: Clients may save their own blacklist against a token
+//!   through `/bl/set/`, and apply it with `bl:token` in a query.
+//! - Score Filter: `min:N` is shorthand for `score:>=N`, and instances may set
+//!   a default minimum score.
+//! - Rating Filter: `safe`, `questionable` and `explicit` limit a search to
+//!   those ratings, and instances may set a maximum rating.
+//! - Animated Posts: Instances may allow animated posts in results, or leave
+//!   it to clients with `anim:yes`/`anim:no`. Animated posts are served as
+//!   still images, and from `SearchMap` version 8, video posts additionally
+//!   get a `Video` link that redirects VRChat video players to the video
+//!   file.
+//! - Shape Filter: `wide`, `tall` and `square` limit a search to posts of
+//!   that shape, for worlds with fixed-shape frames.
+//! - SearchMap Versions: Clients may opt into extra `SearchMap` columns, like
+//!   file sizes, artist tags or the position of each post in the preview
+//!   grid, by searching through `/sN/` or adding `v:N` to a query. Versioned
+//!   `SearchMap` headers end with their version.
+//! - JSON: Searching through `/s.json/`, or adding `fmt:json` to a query,
+//!   returns the `SearchMap` as a JSON document, for consumers other than
+//!   VRChat worlds.
+//! - Next Page: From version 5, the `SearchMap` header has a `link` id that
+//!   runs the same search for the next page.
+//! - Chunking: Long `SearchMap`s are split into chunks, whose `link` ids are
+//!   listed at the end of the header.
+//! - Column Selection: Clients may list the post columns they want with
+//!   `?cols=id,score,rating` or `cols:id,score,rating`, to shrink the
+//!   `SearchMap` for minimal UIs or opt into extra columns.
+//! - TSV: Searching through `/s.tsv/`, or adding `fmt:tsv` to a query,
+//!   returns the `SearchMap` as strict tab-separated values, which are easier
+//!   to parse in-world than the classic format.
+//! - Query Macros: Query strings defined by the instance can be used by
+//!   adding `@name` to a query, e.g. for theme buttons in a world.
+//! - Alias Resolution: Aliased tags are replaced with the tag e621 actually
+//!   files posts under, so searching `feline` finds posts tagged `felid`.
+//! - Shared Searches: Identical searches made while a `SearchMap` is live
+//!   share it, instead of querying e621 and building previews again.
+//! - Memory Budget: Images held by links are dropped, least recently used
+//!   first, once they go over a configurable total size, and downloaded
+//!   again if they are requested later.
+//! - Link Snapshots: Instances may save their links to disk periodically and
+//!   when they shut down, and restore them on startup, so that worlds keep
+//!   working across restarts.
+//!   Images aren't saved, and are downloaded again when first requested.
+//! - Link TTLs: The time left before a link is torn down is available
+//!   through `/link/:id/ttl`, in milliseconds, so that clients can refresh
+//!   links just in time.
+//! - Image Sizes: The image of a `Previews` or `Image` link can be fetched
+//!   downscaled with `?max=N`, for Quest clients and thumbnails.
+//! - Quest Links: From `SearchMap` version 6, every post has a `quest` link
+//!   to a JPEG variant of its image, at most 1024 pixels by default, so that
+//!   cross-platform worlds can pick the quality that suits the platform.
+//! - File Links: From `SearchMap` version 7, every post also has links to its
+//!   preview, sample and original file, so that clients can choose between
+//!   fidelity and download size. Files are downloaded when first requested.
+//! - Load Progress: How far along the image of a `Previews` or `Image` link
+//!   is can be checked through `/link/:id/status`, so that clients can show
+//!   a loading bar while large images download.
+//! - Sliding Expiry: Instances may refresh links whenever they are fetched,
+//!   configurable per kind of link, so that images being viewed don't expire
+//!   mid-session.
+//! - Refresh Limits: Instances may limit how many times a link can be
+//!   refreshed, and how long it can live, so that clients can't keep a
+//!   search alive forever.
+//! - Metrics: Counters of refreshes, expirations and evictions, latencies
+//!   of routes and e621, and the size of links and caches are served through
+//!   `/metrics`, optionally on a separate internal address, so that operators
+//!   can tune link lifetimes and spot slow requests.
+//! - Removal Reasons: Links that were removed recently answer with why they
+//!   were removed, as an `error,code,message` line, instead of the original
+//!   proxy's `Link expired`.
+//! - Shared Links: Instances behind a load balancer may share their links
+//!   through Redis, so that any instance can serve any link.
+//! - Response Cache: Search responses from e621 are cached for a short,
+//!   configurable time, so repeated searches don't reach e621 at all.
+//! - Image Cache: Downloaded images are cached in memory up to a
+//!   configurable size, so overlapping searches and popular posts don't
+//!   download the same file twice.
+//! - HEAD Requests: Every endpoint answers `HEAD` requests with the headers
+//!   of a `GET`, including the `Content-Length`, so that clients and
+//!   monitoring can probe links. Probing a link never refreshes it.
+//! - Strict Status Codes: Instances may answer errors with a matching HTTP
+//!   status, like `410` for expired links or `502` for e621 failures, for
+//!   generic HTTP tooling. By default, errors are sent with a `200`, like the
+//!   original proxy, so that old VRChat worlds keep showing them.
+//! - Search Limit: Instances may limit how many searches query e621 at once.
+//!   Past that, searches wait briefly for a turn, and are then told to try
+//!   again later.
+//! - Access List: Instances may only allow some address ranges, or deny
+//!   abusive addresses, through a file that is read again whenever it
+//!   changes.
+//! - TLS Policy: Instances may raise the minimum TLS version, limit the
+//!   cipher suites, and require clients to present a certificate signed by
+//!   a given CA, so that only their own relays can connect.
+//! - HTTP/3: Instances may also answer over QUIC, advertised with `Alt-Svc`,
+//!   for better large image transfers on flaky networks.
+//! - Reverse Proxies: Instances behind nginx or Cloudflare may trust their
+//!   `Forwarded` and `X-Forwarded-For` headers, so that the access list and
+//!   logs see the real address of clients.
+//! - Access Logs: Instances may log every request, with its status,
+//!   latency, size, client and kind of link, as journald fields or JSON
+//!   lines.
+//! - Request IDs: Every response has an `X-Request-Id` header, and every log
+//!   line written while answering it has the same ID, so that bug reports
+//!   can be matched with logs.
+//! - Log Outputs: Logs go to journald, stderr or a file of JSON lines, so
+//!   that the proxy also runs on hosts without systemd.
+//! - Slow Logs: Searches, previews and image downloads that take longer than
+//!   a configurable threshold are logged as warnings, with what they were
+//!   working on.
+//! - Tracing: Instances may export the spans of requests as traces over OTLP,
+//!   with spans for each step of a search, to see where slow searches spend
+//!   their time.
+//! - Status: What the instance is doing, like its links by kind, cache sizes
+//!   and recent e621 error rate, and a summary of its configuration, are
+//!   served as JSON through `/status.json`. `/status` still answers `OK`.
+//! - Health Checks: `/healthz` checks that e621 answers, that the TLS
+//!   certificate isn't about to expire, and that the runtime is responsive,
+//!   and answers `503` if the instance is unhealthy, for load balancers.
+//! - Admin API: Instances with an admin token may list and purge links,
+//!   flush caches, and see their configuration and most searched queries
+//!   through `/admin/`, from the same host by default.
+//! - Config Reloads: The configuration is read again on `SIGHUP`, or through
+//!   `/admin/config/reload`, and applies to new searches without losing the
+//!   links of the instance.
+//! - Maintenance Mode: Instances under maintenance answer new searches with
+//!   a message asking clients to try again later, while still serving the
+//!   links of existing searches, so that upgrades don't look like failures.
+//! - Banned Terms: Operators may ban search terms through the admin API.
+//!   Searches for them are rejected with a policy message, rather than
+//!   filtered, and logged for moderation.
+//! - Daily Quotas: Instances may limit how many searches each address makes,
+//!   and how many bytes are served to it, per day, so that one world can't
+//!   use up a public instance. Usage survives restarts.
+//! - Abuse Bans: Instances may ban addresses that make too many different
+//!   searches a minute, or fetch too many links that never existed, for a
+//!   while, and longer each time. Bans are listed through the admin API.
+//! - Signed Links: Instances with a signing key append an HMAC to every link
+//!   id they hand out, and refuse ids without a matching one, so that links
+//!   can't be forged or guessed.
+//! - Client Tokens: Instances may register a token for each world or relay
+//!   using them, sent as a `/t/token/` prefix or an `X-Proxy-Token` header,
+//!   to limit its searches per minute and see its usage through the admin
+//!   API. Searches without a registered token may be refused.
+//! - Spans: Requests, searches and link requests run in `tracing` spans,
+//!   whose fields, like the request id, query or link id, are attached to
+//!   every record logged in them, and which are exported as traces if
+//!   tracing is enabled.
+//! - Compression: Text responses, like `SearchMap`s, are compressed with
+//!   brotli or gzip for clients that accept it. Images are sent as they are.
+//! - Random: A single random post matching a query is available through
+//!   `/random/`, returned as a one-post `SearchMap`.
+//! - Counts: The total number of posts matching a query is available through
+//!   `/count/`, without performing a full search.
+//! - Comments: The comments on a post are available as plain text through
+//!   `/comments/`.
 //!
 //! # Client Lifecycle
 //!
@@ -29,79 +189,651 @@
 //! each resource.
 
 use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{net::SocketAddr, path::PathBuf};
 
-use axum::extract::{Path, Request};
-use axum::http::header;
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Path, Query as Params, RawPathParams, Request};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
-use axum::Router;
-use axum_server::tls_rustls::RustlsConfig;
-use log::LevelFilter;
-use systemd_journal_logger::JournalLog;
+use axum::{Extension, Router};
+use itertools::Itertools;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::error::Elapsed;
+use tracing::Instrument;
 
-use crate::image::Image;
-use crate::links::{setup_links, Link, LinkMap};
+use crate::access::AccessList;
+use crate::access_log::LinkKind;
+use crate::alias::Aliases;
+use crate::api::ImageBody;
+use crate::banned::BannedTerms;
+use crate::blacklist::Blacklists;
+use crate::budget::{ImageSlot, LoadStatus};
+use crate::client::ClientIp;
+use crate::config::Config;
+use crate::image::{FetchError, Image, Placeholder, Placeholders};
+use crate::links::{setup_links, setup_shared_links, Format, Link, LinkId, LinkMap};
+use crate::metrics::Metrics;
+use crate::query::{Query, QueryError};
+use crate::refresh::RefreshError;
 
 // utils
+mod abuse;
+mod access;
+mod access_log;
+mod admin;
+mod budget;
+mod client;
+mod compress;
+mod config;
+mod dtext;
+mod epoch;
+mod health;
+mod http3;
+mod logging;
+mod lru;
+mod maintenance;
+mod metrics;
 mod promise;
+mod quota;
 mod refresh;
+mod request_id;
+mod signing;
+mod slow;
+mod snapshot;
+mod status;
+mod store;
+mod tls;
+mod tokens;
+mod trace;
 
 // impl
+mod alias;
 mod api;
+mod banned;
+mod blacklist;
 mod image;
 mod links;
+mod query;
 
 /// Program entry point.
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    JournalLog::new().unwrap().install().unwrap();
-    log::set_max_level(LevelFilter::Info);
+    logging::install();
+
+    // load the config up front, so that any problems with it show up at startup
+    Config::global();
+    Config::spawn_reloader()?;
+    logging::init();
+    status::init();
+    Placeholders::global();
+    access::init().await;
+    quota::init().await;
+
+    store::connect().await.map_err(io::Error::other)?;
+    snapshot::restore().await;
+    snapshot::spawn_saver();
+
+    // metrics are served publicly, unless they have an internal address
+    let metrics_router = Router::new().route("/metrics", get(metrics));
+    let metrics_router = match Config::global().server.metrics_addr {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tracing::info!("serving metrics on {addr}");
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, metrics_router).await {
+                    tracing::error!("metrics listener failed: {e}");
+                }
+            });
+            Router::new()
+        }
+        None => metrics_router,
+    };
 
     let app = Router::new()
         .route("/check_jailbreak", get(|| async { text("jailbreak OK") }))
         .route("/status", get(|| async { text("OK") }))
+        .route("/healthz", get(healthz))
+        .route(
+            "/status.json",
+            get(|| async { json(status::document().to_string()) }),
+        )
+        .merge(metrics_router)
+        .merge(admin::router())
         .route("/link/:id", get(link))
-        .route("/s/", get(|| search(Path(String::new()))))
+        .route("/link/:id/ttl", get(link_ttl))
+        .route("/link/:id/status", get(link_status))
+        .route(
+            "/s/",
+            get(|p: Params<SearchParams>| search(Encoded(String::new()), p)),
+        )
         .route("/s/:query", get(search))
-        .fallback(fallback);
+        .route(
+            "/s.json/",
+            get(|p: Params<SearchParams>| search_format("json", String::new(), p)),
+        )
+        .route(
+            "/s.json/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_format("json", q, p)),
+        )
+        .route(
+            "/s.tsv/",
+            get(|p: Params<SearchParams>| search_format("tsv", String::new(), p)),
+        )
+        .route(
+            "/s.tsv/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_format("tsv", q, p)),
+        )
+        .route(
+            "/s2/",
+            get(|p: Params<SearchParams>| search_versioned(2, String::new(), p)),
+        )
+        .route(
+            "/s2/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_versioned(2, q, p)),
+        )
+        .route(
+            "/s3/",
+            get(|p: Params<SearchParams>| search_versioned(3, String::new(), p)),
+        )
+        .route(
+            "/s3/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_versioned(3, q, p)),
+        )
+        .route(
+            "/s4/",
+            get(|p: Params<SearchParams>| search_versioned(4, String::new(), p)),
+        )
+        .route(
+            "/s4/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_versioned(4, q, p)),
+        )
+        .route(
+            "/s5/",
+            get(|p: Params<SearchParams>| search_versioned(5, String::new(), p)),
+        )
+        .route(
+            "/s5/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_versioned(5, q, p)),
+        )
+        .route(
+            "/s6/",
+            get(|p: Params<SearchParams>| search_versioned(6, String::new(), p)),
+        )
+        .route(
+            "/s6/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_versioned(6, q, p)),
+        )
+        .route(
+            "/s7/",
+            get(|p: Params<SearchParams>| search_versioned(7, String::new(), p)),
+        )
+        .route(
+            "/s7/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_versioned(7, q, p)),
+        )
+        .route(
+            "/s8/",
+            get(|p: Params<SearchParams>| search_versioned(8, String::new(), p)),
+        )
+        .route(
+            "/s8/:query",
+            get(|Encoded(q): Encoded, p: Params<SearchParams>| search_versioned(8, q, p)),
+        )
+        .route("/random/", get(|| random(Encoded(String::new()))))
+        .route("/random/:tags", get(random))
+        .route("/count/:query", get(count))
+        .route("/bl/set/:token/:tags", get(blacklist_set))
+        .route("/bl/get/:token", get(blacklist_get))
+        .route("/bl/clear/:token", get(blacklist_clear))
+        .route(
+            "/comments/:post_id",
+            get(|Path(id): Path<String>| comments(Path((id, "1".into())))),
+        )
+        .route("/comments/:post_id/:page", get(comments))
+        .fallback(fallback)
+        .layer(axum::middleware::from_fn(timeout))
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(trace::root))
+        .layer(axum::middleware::map_response(no_store_by_default))
+        .layer(axum::middleware::map_response(http3::alt_svc))
+        .layer(axum::middleware::from_fn(compress::compress))
+        .layer(axum::middleware::from_fn(check_quota))
+        .layer(axum::middleware::from_fn(check_access))
+        .layer(axum::middleware::from_fn(access_log::log))
+        .layer(axum::middleware::from_fn(client::resolve))
+        .layer(axum::middleware::from_fn(request_id::assign));
 
-    let config = RustlsConfig::from_pem_file(
-        PathBuf::from("./").join("https_certs").join("server.crt"),
-        PathBuf::from("./").join("https_certs").join("server.key"),
-    )
-    .await
-    .map_err(io::Error::other)?;
+    // client tokens are stripped from paths before they are routed
+    let app = Router::new()
+        .fallback_service(app)
+        .layer(axum::middleware::from_fn(tokens::identify));
+
+    let cert = PathBuf::from("./").join("https_certs").join("server.crt");
+    let key = PathBuf::from("./").join("https_certs").join("server.key");
+    let config = tls::config(&cert, &key).await?;
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 443));
-    log::info!("listening on {addr}");
+    let http3 = if Config::global().server.http3 {
+        let endpoint = http3::listen(app.clone(), addr, tls::quic_config(&cert, &key).await?)?;
+        tracing::info!("listening for http/3 on {addr}");
+        Some(endpoint)
+    } else {
+        None
+    };
+
+    let handle = axum_server::Handle::new();
+    tokio::spawn(shutdown_on_signal(handle.clone(), http3));
+
+    tracing::info!("listening on {addr}");
     axum_server::bind_rustls(addr, config)
-        .serve(app.into_make_service())
-        .await
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+
+    // links created by the requests answered while shutting down are saved too
+    snapshot::save_configured().await;
+    tracing::info!("shut down");
+    trace::shutdown();
+    Ok(())
+}
+
+/// How long requests may take to finish once the proxy is asked to stop.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Stop accepting connections once the process is asked to stop, with ctrl-c
+/// or `SIGTERM`, and let the open ones finish.
+async fn shutdown_on_signal(handle: axum_server::Handle, http3: Option<quinn::Endpoint>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("failed to listen for ctrl-c: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+
+    tracing::info!("shutting down");
+    handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+    if let Some(endpoint) = http3 {
+        http3::close(endpoint, SHUTDOWN_GRACE).await;
+    }
+}
+
+/// Handler for the `/healthz` endpoint.
+///
+/// Responds with the results of the health checks as JSON, with a `503` if
+/// the instance is unhealthy, regardless of `strict_status`, since load
+/// balancers go by the status.
+async fn healthz() -> Response {
+    let (status, document) = health::check().await;
+
+    let mut res = json(document.to_string());
+    *res.status_mut() = status;
+    res
+}
+
+/// Handler for the `/metrics` endpoint.
+async fn metrics() -> Response {
+    text(Metrics::global().to_string())
+}
+
+/// URL query parameters accepted by the `/link/:id` endpoint.
+#[derive(Debug, Default, serde::Deserialize)]
+struct LinkParams {
+    /// The maximum width and height of the image of a `Previews` or `Image`
+    /// link, which is downscaled to fit.
+    max: Option<u32>,
+}
+
+/// URL query parameters accepted by the search endpoints.
+#[derive(Debug, Default, serde::Deserialize)]
+struct SearchParams {
+    /// The post columns to include in the `SearchMap`, separated by commas.
+    /// Equivalent to adding `cols:` to the query string.
+    cols: Option<String>,
+}
+
+/// The last path parameter of a request, still percent-encoded.
+///
+/// Query strings are decoded by `query::split` once they are split into
+/// tags, so that an encoded `+` is part of a tag instead of separating tags,
+/// and nothing is decoded twice.
+struct Encoded(String);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Encoded {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params = RawPathParams::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let value = params.iter().last().map(|(_, value)| value);
+        Ok(Self(value.unwrap_or_default().to_owned()))
+    }
 }
 
 /// Handler for the `/s/:query` endpoint.
-/// 
+///
 /// See the crate documentation for more information on the client lifecycle.
-async fn search(Path(query): Path<String>) -> Response {
-    // todo: add features to this query parsing, like pre-built blacklists
-    let mut query = query.trim();
-    let mut page = "1";
+async fn search(Encoded(query): Encoded, Params(params): Params<SearchParams>) -> Response {
+    let input = match params.cols {
+        Some(cols) => format!("{query} cols:{cols}"),
+        None => query,
+    };
+
+    match parse_query(&input).await {
+        Ok(query) => run_search(query).await,
+        Err(e) => error_text(StatusCode::BAD_REQUEST, e.to_string()),
+    }
+}
+
+/// Respond to a parsed search query with its `SearchMap`.
+#[tracing::instrument(name = "search", skip_all, fields(tags = %query.tags(), page = query.page))]
+async fn run_search(query: Query) -> Response {
+    if let Some(refusal) = admit_search(&query) {
+        return refusal;
+    }
+
+    if let Some(shared) = LinkMap::global().shared_search(&query) {
+        tracing::info!("shared query: {} page {}", query.tags(), query.page);
+        Metrics::global().query(&query.tags(), None, false);
+        return search_map(shared, query.format);
+    }
+
+    let Ok(_permit) = search_permit().await else {
+        tracing::warn!("too many searches, refusing: {}", query.tags());
+        return busy();
+    };
+
+    tracing::info!("query: {} page {}", query.tags(), query.page);
+    let tags = query.tags();
+    let search = async {
+        let posts = api::query(&query)
+            .instrument(tracing::info_span!("e621.query", query.tags = %tags))
+            .await?;
+
+        let links = setup_shared_links(posts, &query)
+            .instrument(tracing::info_span!("links.setup"))
+            .await;
+        Ok::<_, reqwest::Error>(links)
+    };
+
+    let threshold = Config::global().server.slow.search;
+    let context = format!("{tags} page {}", query.page);
+    let start = Instant::now();
+    let result = slow::warn_if_slow("search", threshold, context, search).await;
+    Metrics::global().query(&tags, Some(start.elapsed()), result.is_err());
+
+    match result {
+        Ok(map) => search_map(map, query.format),
+        Err(e) => upstream_error(&e),
+    }
+}
+
+/// Check that a search may query e621, counting it towards flood detection,
+/// its client's limit and its address's daily quota, and refusing it under
+/// maintenance. Every endpoint that searches e621 goes through this, so that
+/// none of them bypasses a check.
+///
+/// Returns the response refusing the search, if it is refused.
+fn admit_search(query: &Query) -> Option<Response> {
+    abuse::record_query(&format!("{} page {}", query.tags(), query.page));
+    if maintenance::enabled() {
+        tracing::info!("under maintenance, refusing: {}", query.tags());
+        return Some(under_maintenance());
+    }
+
+    if let Err(e) = tokens::take_search() {
+        tracing::warn!("refusing search, {}: {}", e.code(), query.tags());
+        return Some(token_refused(e));
+    }
+
+    if let Err(e) = quota::take_search() {
+        tracing::warn!("refusing search over quota: {}", query.tags());
+        return Some(quota_exceeded(e));
+    }
+
+    None
+}
+
+/// Wait for a search to be allowed to query e621, if the instance limits how
+/// many searches run at once. Fails if none finishes within the configured
+/// queue timeout.
+///
+/// The semaphore is replaced when a reload changes the limit. Searches
+/// holding a permit of the old one finish normally.
+async fn search_permit() -> Result<Option<OwnedSemaphorePermit>, Elapsed> {
+    static PERMITS: Mutex<Option<(usize, Arc<Semaphore>)>> = Mutex::new(None);
+
+    let config = &Config::global().server;
+    let Some(max) = config.max_searches else {
+        return Ok(None);
+    };
+
+    let permits = {
+        let mut permits = PERMITS.lock().expect("poisoned");
+        match &*permits {
+            Some((limit, semaphore)) if *limit == max => semaphore.clone(),
+            _ => {
+                let semaphore = Arc::new(Semaphore::new(max));
+                *permits = Some((max, semaphore.clone()));
+                semaphore
+            }
+        }
+    };
+
+    let timeout = Duration::from_secs(config.search_queue_timeout);
+    let permit = tokio::time::timeout(timeout, permits.acquire_owned()).await?;
+
+    Ok(Some(permit.expect("never closed")))
+}
+
+/// The response for a search refused because too many are running, asking
+/// the client to try again later, with a `503` if the instance sends strict
+/// status codes.
+fn busy() -> Response {
+    let message = format!("error,busy,The proxy is busy. Try again in {RETRY_AFTER} seconds.");
+    let mut res = error_text(StatusCode::SERVICE_UNAVAILABLE, message);
+    retry_after(&mut res, HeaderValue::from_static(RETRY_AFTER));
+
+    res
+}
+
+/// The response for a search refused because the instance is under
+/// maintenance, asking the client to try again later, with a `503` if the
+/// instance sends strict status codes.
+fn under_maintenance() -> Response {
+    let message = format!(
+        "error,maintenance,This instance is under maintenance. Try again in {MAINTENANCE_RETRY_AFTER} seconds."
+    );
+    let mut res = error_text(StatusCode::SERVICE_UNAVAILABLE, message);
+    retry_after(&mut res, HeaderValue::from_static(MAINTENANCE_RETRY_AFTER));
+
+    res
+}
 
-    // if the last thing is a number, it's a page
-    if let Some(tpage) = query.split_whitespace().last() {
-        if tpage.parse::<usize>().is_ok() {
-            query = &query[..query.len() - page.len()];
-            page = tpage;
+/// Handler for the `/s.json/:query` and `/s.tsv/:query` endpoints.
+///
+/// Behaves like the search endpoint, but responds with a `SearchMap` in the
+/// given format, unless the query selects another one with `fmt:name`.
+async fn search_format(format: &str, query: String, params: Params<SearchParams>) -> Response {
+    search(Encoded(format!("fmt:{format} {query}")), params).await
+}
+
+/// Handler for the `/sN/:query` endpoints.
+///
+/// Behaves like the search endpoint, but responds with the given `SearchMap`
+/// format version, unless the query selects another one with `v:N`.
+async fn search_versioned(version: u32, query: String, params: Params<SearchParams>) -> Response {
+    search(Encoded(format!("v:{version} {query}")), params).await
+}
+
+/// Parse a client query string, applying the client blacklist it selects and
+/// resolving any aliased tags.
+async fn parse_query(input: &str) -> Result<Query, QueryError> {
+    let parse = async {
+        let config = &Config::global().query;
+
+        let mut query = Query::parse(input, config)?;
+        Blacklists::apply(&mut query).await;
+        query.validate(config)?;
+        BannedTerms::check(&query).await?;
+        if config.resolve_aliases {
+            // aliases of a banned term are banned too
+            Aliases::apply(&mut query).await;
+            BannedTerms::check(&query).await?;
         }
+
+        Ok(query)
+    };
+
+    parse
+        .instrument(tracing::info_span!("query.parse", query = input))
+        .await
+}
+
+/// Handler for the `/random/:tags` endpoint.
+///
+/// Behaves like the search endpoint, but the returned `SearchMap` contains a
+/// single random post matching the tags.
+async fn random(Encoded(tags): Encoded) -> Response {
+    let query = match parse_query(&tags).await {
+        Ok(query) => query,
+        Err(e) => return error_text(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    if let Some(refusal) = admit_search(&query) {
+        return refusal;
     }
 
-    log::info!("query: {query} page {page}");
-    let Ok(posts) = api::query(query, page).await else {
-        return text("An error occured during the external query.");
+    let Ok(_permit) = search_permit().await else {
+        tracing::warn!("too many searches, refusing random: {}", query.tags());
+        return busy();
+    };
+
+    tracing::info!("random: {}", query.tags());
+    let posts = match api::random(&query).await {
+        Ok(posts) => posts,
+        Err(e) => return upstream_error(&e),
+    };
+
+    search_map(setup_links(posts, &query).await, query.format)
+}
+
+/// Handler for the `/count/:query` endpoint.
+///
+/// Returns the total number of posts matching the query string. Counts query
+/// e621 like searches, so they are checked and limited like them.
+async fn count(Encoded(query): Encoded) -> Response {
+    let query = match parse_query(&query).await {
+        Ok(query) => query,
+        Err(e) => return error_text(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    if let Some(refusal) = admit_search(&query) {
+        return refusal;
+    }
+
+    let Ok(_permit) = search_permit().await else {
+        tracing::warn!("too many searches, refusing count: {}", query.tags());
+        return busy();
+    };
+
+    tracing::info!("count: {}", query.tags());
+    let count = match api::count(&query).await {
+        Ok(count) => count,
+        Err(e) => return upstream_error(&e),
     };
 
-    text(setup_links(posts).await.to_string())
+    text(count.to_string())
+}
+
+/// Handler for the `/bl/set/:token/:tags` endpoint.
+///
+/// Registers a client blacklist, which is applied to any search that includes
+/// `bl:token`.
+async fn blacklist_set(
+    Path((token, _)): Path<(String, String)>,
+    Encoded(tags): Encoded,
+) -> Response {
+    if !blacklist::is_valid_token(&token) {
+        return error_text(StatusCode::BAD_REQUEST, "Invalid blacklist token.");
+    }
+
+    let tags = query::split(&tags);
+    let max = blacklist::max_tags();
+    if tags.len() > max {
+        let message = format!("Blacklists are limited to {max} tags.");
+        return error_text(StatusCode::BAD_REQUEST, message);
+    }
+
+    tracing::info!("setting blacklist: {token}");
+    match Blacklists::set(&token, tags).await {
+        Ok(true) => text("OK"),
+        Ok(false) => {
+            tracing::warn!("too many blacklists, refusing: {token}");
+            error_text(
+                StatusCode::INSUFFICIENT_STORAGE,
+                "This instance can't store any more blacklists.",
+            )
+        }
+        Err(e) => {
+            tracing::error!("failed to save blacklists: {e}");
+            error_text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "An error occured while saving the blacklist.",
+            )
+        }
+    }
+}
+
+/// Handler for the `/bl/get/:token` endpoint.
+///
+/// Returns the tags blacklisted for a token, separated by spaces.
+async fn blacklist_get(Path(token): Path<String>) -> Response {
+    text(Blacklists::get(&token).await.unwrap_or_default().join(" "))
+}
+
+/// Handler for the `/bl/clear/:token` endpoint.
+async fn blacklist_clear(Path(token): Path<String>) -> Response {
+    tracing::info!("clearing blacklist: {token}");
+    match Blacklists::clear(&token).await {
+        Ok(()) => text("OK"),
+        Err(e) => {
+            tracing::error!("failed to save blacklists: {e}");
+            error_text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "An error occured while saving the blacklist.",
+            )
+        }
+    }
 }
 
 /// Handler for the `/link/:id` endpoint.
@@ -110,70 +842,584 @@ async fn search(Path(query): Path<String>) -> Response {
 /// associated with the id:
 ///
 /// - `SearchMap`: Gets a SearchMap string. (Note: The SearchMap contains an ID
-///              for itself. This is used to allow clients to display a search
-///              even if they are not the ones that made it.
+///   for itself. This is used to allow clients to display a search even if
+///   they are not the ones that made it.
+/// - `Chunk`: Gets a continuation chunk of a SearchMap string that was too
+///   long to return at once.
+/// - `NextPage`: Runs the search of a SearchMap for its next page, and gets
+///   the new SearchMap string.
 /// - `RefreshSearch`: Refreshes the SearchMap string.
-/// - `Previews`: A stitched-together image of the preview images from the initial
-///             search query.
+/// - `Previews`: A stitched-together image of the preview images from the
+///   initial search query.
 /// - `Image`: The full-size image of a post from the initial search query.
+/// - `File`: The original file of a post, streamed from e621 as it
+///   downloads.
+/// - `Video`: Redirects to the video file of a post, so that it can be played
+///   by a VRChat video player.
 /// - `RefreshImage`: Refreshes a full-size image resource.
-async fn link(Path(id): Path<String>) -> Response {
-    let Ok(id) = id.parse() else {
+///
+/// The image of a `Previews` or `Image` link can be downscaled with `?max=N`,
+/// for clients that don't need the full size. Images that are already loaded
+/// can be fetched in parts with a `Range` header, so that downloads can be
+/// resumed. Images are served with an `ETag`, and requests whose
+/// `If-None-Match` matches it are answered with a `304`, without loading the
+/// image.
+///
+/// Like every endpoint, this one answers `HEAD` requests with the headers of
+/// the `GET` response, without its body. `HEAD` requests don't refresh links,
+/// whether through a refresh link or sliding expiry; refresh links answer
+/// with the time their group has left instead.
+#[tracing::instrument(skip_all, fields(id = %id))]
+async fn link(
+    method: Method,
+    Path(id): Path<String>,
+    Params(params): Params<LinkParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(id) = signing::verify(&id) else {
+        // forged ids are how link ids are guessed when they are signed
+        abuse::record_missing_link();
+
         // mimics the behavior of the original proxy
-        return text("Link expired");
+        return error_text(StatusCode::NOT_FOUND, "Link expired");
     };
 
-    let Some(link) = LinkMap::get_ref().await.get(id) else {
-        // mimics the behavior of the original proxy
-        return text("Link expired");
+    let map = LinkMap::global();
+    let Some(link) = (match map.get(id) {
+        Some(link) => Some(link),
+        None => map.get_stored(id).await,
+    }) else {
+        return missing_link(id);
     };
 
-    match link {
+    let kind = LinkKind(link.kind());
+
+    // probing a link doesn't keep it alive
+    let probe = method == Method::HEAD;
+    if !probe && map.slide(id, &link) {
+        tracing::info!("refreshing on access: {id}");
+        tokio::spawn(store::refresh(id));
+    }
+
+    let cache_control = cache_control(&link, map.remaining(id));
+
+    // image links are tagged per downscaled variant
+    let etag = map.etag(id).map(|tag| match params.max {
+        Some(max) => format!("W/\"{tag}-{max}\""),
+        None => format!("W/\"{tag}\""),
+    });
+    if let Some(etag) = &etag {
+        if not_modified(&headers, etag) {
+            tracing::info!("image not modified: {id}");
+            return (
+                StatusCode::NOT_MODIFIED,
+                [
+                    (header::ETAG, etag.clone()),
+                    (header::CACHE_CONTROL, cache_control),
+                ],
+                Extension(kind),
+            )
+                .into_response();
+        }
+    }
+
+    let range = headers.get(header::RANGE);
+    let mut res = match link {
         Link::SearchMap(sm) => {
-            log::info!("get searchmap: {id}");
+            tracing::info!("get searchmap: {id}");
             text(sm.to_string())
         }
+        Link::Chunk(chunk) => {
+            tracing::info!("get searchmap chunk: {id}");
+            text(chunk.to_string())
+        }
+        Link::NextPage(query) => {
+            tracing::info!("get next page: {id}");
+            run_search(query).await
+        }
+        Link::RefreshSearch(refresh) | Link::RefreshImage(refresh) if probe => {
+            text(refresh.remaining().as_millis().to_string())
+        }
         Link::RefreshSearch(refresh) => {
-            log::info!("refreshing searchmap: {id}");
-            refresh.refresh();
-            text("600000")
+            tracing::info!("refreshing searchmap: {id}");
+            match refresh.refresh() {
+                Ok(remaining) => {
+                    store::refresh(id).await;
+                    text(remaining.as_millis().to_string())
+                }
+                Err(e) => refresh_error(e),
+            }
         }
         Link::Previews(image) => {
-            log::info!("get previews: {id}");
-            image
-                .get()
-                .await
-                .clone()
-                .unwrap_or_else(Image::placeholder)
-                .into_response()
+            tracing::info!("get previews: {id}");
+            serve_image(id, image, params.max, range).await
         }
         Link::Image(image) => {
-            log::info!("get image: {id}");
-            let image = image
-                .get()
-                .await
-                .clone()
-                .unwrap_or_else(Image::placeholder)
-                .into_response();
-            log::info!("serving image: {id}");
+            tracing::info!("get image: {id}");
+            let image = serve_image(id, image, params.max, range).await;
+            tracing::info!("serving image: {id}");
             image
         }
+        Link::File(url) => {
+            tracing::info!("get file: {id}");
+            serve_file(id, url, range).await
+        }
+        Link::Video(url) => {
+            tracing::info!("redirecting to video: {id}");
+            (StatusCode::FOUND, [(header::LOCATION, url.to_string())]).into_response()
+        }
         Link::RefreshImage(refresh) => {
-            log::info!("refreshing image: {id}");
-            refresh.refresh();
-            text("1200000")
+            tracing::info!("refreshing image: {id}");
+            match refresh.refresh() {
+                Ok(remaining) => {
+                    store::refresh(id).await;
+                    text(remaining.as_millis().to_string())
+                }
+                Err(e) => refresh_error(e),
+            }
         }
+    };
+
+    cache_headers(&mut res, etag, &cache_control);
+    res.extensions_mut().insert(kind);
+
+    res
+}
+
+/// Tag the response of a link with its `ETag` and `Cache-Control`, unless it
+/// is an error or a placeholder, which fall back to `no_store_by_default`.
+///
+/// Placeholders are sent as a `200` by instances without strict status codes,
+/// so they are told apart by their `IsPlaceholder` extension; caching one
+/// for good would keep the client from ever getting the real image.
+fn cache_headers(res: &mut Response, etag: Option<String>, cache_control: &str) {
+    if res.extensions().get::<IsPlaceholder>().is_some() {
+        return;
+    }
+
+    let status = res.status();
+    let etag = etag
+        .filter(|_| status.is_success())
+        .and_then(|etag| HeaderValue::from_str(&etag).ok());
+    if let Some(etag) = etag {
+        res.headers_mut().insert(header::ETAG, etag);
+    }
+
+    if status.is_success() || status.is_redirection() {
+        if let Ok(cache_control) = HeaderValue::from_str(cache_control) {
+            res.headers_mut()
+                .insert(header::CACHE_CONTROL, cache_control);
+        }
+    }
+}
+
+/// The `Cache-Control` of the successful responses of a link, given the time
+/// it has left.
+///
+/// The images of a post never change for as long as their link lives, so they
+/// are cached for good. Preview grids and video redirects are cached for as
+/// long as their link lives. Everything else changes with every request.
+fn cache_control(link: &Link, remaining: Option<Duration>) -> String {
+    let remaining = remaining.unwrap_or_default().as_secs();
+
+    match link {
+        Link::Image(_) | Link::File(_) => "public, max-age=31536000, immutable".into(),
+        Link::Previews(_) | Link::Video(_) => format!("public, max-age={remaining}"),
+        Link::SearchMap(_)
+        | Link::Chunk(_)
+        | Link::NextPage(_)
+        | Link::RefreshImage(_)
+        | Link::RefreshSearch(_) => "no-store".into(),
+    }
+}
+
+/// Middleware that refuses requests from addresses the access list doesn't
+/// allow, or that are banned for abuse, before they are routed.
+async fn check_access(
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !AccessList::allows(ip) {
+        tracing::warn!("refused request from {ip}");
+        return error_text(
+            StatusCode::FORBIDDEN,
+            "error,forbidden,This address may not use this proxy.",
+        );
+    }
+
+    if let Some(ban) = abuse::banned(ip) {
+        tracing::info!("refused request from banned {ip}");
+        let mut res = error_text(StatusCode::TOO_MANY_REQUESTS, ban.to_string());
+        retry_after(&mut res, ban.remaining().as_secs().into());
+        return res;
+    }
+
+    next.run(req).await
+}
+
+/// Middleware that refuses requests from addresses that were served their
+/// daily quota of bytes, and counts the bytes served to them. Searches are
+/// counted against their quota by `run_search`.
+async fn check_quota(
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !quota::enabled() {
+        return next.run(req).await;
+    }
+
+    if let Err(e) = quota::check_bytes(ip) {
+        tracing::warn!("refused request from {ip}: daily bytes used up");
+        return quota_exceeded(e);
+    }
+
+    let res = next.run(req).await;
+    if let Some(bytes) = access_log::body_len(&res) {
+        quota::add_bytes(ip, bytes);
+    }
+
+    res
+}
+
+/// The response for a request refused because its address went over a daily
+/// quota. With strict status codes, it is a `429` telling the client when the
+/// quota resets.
+fn quota_exceeded(e: quota::Exceeded) -> Response {
+    let mut res = error_text(StatusCode::TOO_MANY_REQUESTS, e.to_string());
+    retry_after(&mut res, quota::reset_in().as_secs().into());
+
+    res
+}
+
+/// The response for a search refused because of its client token. With
+/// strict status codes, it is a `401` if the token is missing or unknown, or
+/// a `429` telling a throttled client when it may search again.
+fn token_refused(e: tokens::Refused) -> Response {
+    if e != tokens::Refused::Throttled {
+        return error_text(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+
+    let mut res = error_text(StatusCode::TOO_MANY_REQUESTS, e.to_string());
+    retry_after(&mut res, tokens::retry_in().as_secs().into());
+
+    res
+}
+
+/// Middleware that answers requests that take longer than the configured
+/// limit for their endpoint with a timeout error, so that a stuck request to
+/// e621 doesn't hold a connection forever. Work that outlives its request,
+/// like loading an image, keeps running.
+async fn timeout(req: Request, next: Next) -> Response {
+    let config = &Config::global().server;
+    let path = req.uri().path().to_owned();
+
+    let limit = if path.starts_with("/link/") {
+        config.link_timeout
+    } else {
+        config.search_timeout
+    };
+    let Some(secs) = limit else {
+        return next.run(req).await;
+    };
+
+    match tokio::time::timeout(Duration::from_secs(secs), next.run(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            tracing::warn!("request timed out: {path}");
+            error_text(
+                StatusCode::GATEWAY_TIMEOUT,
+                "error,timeout,The request took too long. Try again later.",
+            )
+        }
+    }
+}
+
+/// Mark responses that don't say how they may be cached as uncacheable, since
+/// most responses, like `SearchMap`s and refreshes, differ between requests.
+async fn no_store_by_default(mut res: Response) -> Response {
+    res.headers_mut()
+        .entry(header::CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static("no-store"));
+
+    res
+}
+
+/// Whether the `If-None-Match` header of a request matches an entity tag,
+/// so that the client can keep using its copy. Tags are compared weakly, as
+/// HTTP requires for `If-None-Match`.
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(tags) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|tags| tags.to_str().ok())
+    else {
+        return false;
+    };
+
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = opaque(etag);
+
+    tags.split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// Handler for the `/link/:id/ttl` endpoint.
+///
+/// Responds with the time left before a link is torn down, in milliseconds,
+/// like the `ttl` column of a `SearchMap` and the responses of refresh links.
+async fn link_ttl(Path(id): Path<String>) -> Response {
+    let Some(id) = signing::verify(&id) else {
+        return error_text(StatusCode::NOT_FOUND, "Link expired");
+    };
+
+    let map = LinkMap::global();
+    let remaining = match map.remaining(id) {
+        Some(remaining) => Some(remaining),
+        None => map.get_stored(id).await.and_then(|_| map.remaining(id)),
+    };
+
+    match remaining {
+        Some(remaining) => text(remaining.as_millis().to_string()),
+        None => missing_link(id),
     }
 }
 
+/// Handler for the `/link/:id/status` endpoint.
+///
+/// Responds with how far along the image of a `Previews` or `Image` link is,
+/// without loading it:
+///
+/// - `loading,received,total`: The image is loading, with the bytes received
+///   so far. `total` is empty if the size of the image isn't known yet.
+/// - `ready,size,size`: The image is loaded, and is `size` bytes.
+/// - `error,code,message`: The image failed to load.
+async fn link_status(Path(id): Path<String>) -> Response {
+    let Some(id) = signing::verify(&id) else {
+        return error_text(StatusCode::NOT_FOUND, "Link expired");
+    };
+
+    let map = LinkMap::global();
+    let Some(link) = (match map.get(id) {
+        Some(link) => Some(link),
+        None => map.get_stored(id).await,
+    }) else {
+        return missing_link(id);
+    };
+
+    let (Link::Previews(image) | Link::Image(image)) = link else {
+        return error_text(
+            StatusCode::BAD_REQUEST,
+            "error,not_an_image,This link has no image.",
+        );
+    };
+
+    match image.status() {
+        LoadStatus::Loading { received, total } => {
+            let total = total.map(|n| n.to_string()).unwrap_or_default();
+            text(format!("loading,{received},{total}"))
+        }
+        LoadStatus::Loaded(size) => text(format!("ready,{size},{size}")),
+        LoadStatus::Failed(e) => text(e.to_string()),
+    }
+}
+
+/// How long in seconds clients are told to wait before asking again for an
+/// image that is still loading, or that failed to load but may not next time.
+const RETRY_AFTER: &str = "5";
+
+/// How long in seconds clients are told to wait before searching again, while
+/// the instance is under maintenance.
+const MAINTENANCE_RETRY_AFTER: &str = "60";
+
+/// Serve the image of a `Previews` or `Image` link, downscaled to fit `max`
+/// pixels if it is given.
+///
+/// If the image doesn't load within the configured timeout, the loading
+/// placeholder is served, asking the client to retry; see `retry_later`. The
+/// image keeps loading in the meantime, so that it is ready for the next
+/// request.
+async fn serve_image(
+    id: LinkId,
+    slot: ImageSlot,
+    max: Option<u32>,
+    range: Option<&HeaderValue>,
+) -> Response {
+    let slot = match max {
+        Some(max) => slot.variant(max),
+        None => slot,
+    };
+    let load = async move { slot.get().await };
+    let load = tokio::spawn(load.in_current_span());
+
+    let image = match Config::global().image.load_timeout {
+        Some(secs) => match tokio::time::timeout(Duration::from_secs(secs), load).await {
+            Ok(image) => image,
+            Err(_) => {
+                tracing::info!("image {id} is still loading");
+                return retry_later(Placeholder::Loading);
+            }
+        },
+        None => load.await,
+    };
+
+    // the load only fails to join if it panicked
+    image_response(id, image.unwrap_or(Err(FetchError::Decode)), range)
+}
+
+/// Serve the file of a `File` link, passing it on to the client as it
+/// downloads from e621, unless it is cached.
+async fn serve_file(id: LinkId, url: Arc<str>, range: Option<&HeaderValue>) -> Response {
+    match api::stream_image(url).await {
+        Ok(ImageBody::Cached(image)) => image.into_range_response(range),
+        Ok(ImageBody::Stream {
+            mime_type,
+            len,
+            body,
+        }) => {
+            let mut res = (
+                [(header::CONTENT_TYPE, mime_type.to_string())],
+                Body::from_stream(body),
+            )
+                .into_response();
+            if let Some(len) = len {
+                res.headers_mut().insert(header::CONTENT_LENGTH, len.into());
+            }
+
+            res
+        }
+        Err(e) => image_response(id, Err(e), range),
+    }
+}
+
+/// Marks a response whose body is a placeholder image rather than the image
+/// of the link, whatever its status.
+#[derive(Clone, Copy)]
+struct IsPlaceholder;
+
+/// A placeholder image, with a `503` asking the client to try again later if
+/// the instance sends strict status codes.
+fn retry_later(placeholder: Placeholder) -> Response {
+    let status = error_status(StatusCode::SERVICE_UNAVAILABLE);
+    let placeholder = Image::placeholder(placeholder);
+    let mut res = (status, Extension(IsPlaceholder), placeholder).into_response();
+    retry_after(&mut res, HeaderValue::from_static(RETRY_AFTER));
+
+    res
+}
+
+/// The response for the image of a `Previews` or `Image` link.
+///
+/// Loaded images are served in part if asked for by a `Range` header; see
+/// `Image::into_range_response`.
+///
+/// Images that failed to load are answered with a placeholder. Instances with
+/// strict status codes also tell clients whether trying again may help:
+/// `503` for failures that may be temporary, and `404`, `410` or `502` for
+/// the others.
+fn image_response(
+    id: LinkId,
+    image: Result<Image, FetchError>,
+    range: Option<&HeaderValue>,
+) -> Response {
+    let e = match image {
+        Ok(image) => return image.into_range_response(range),
+        Err(e) => e,
+    };
+
+    tracing::warn!("failed to load image {id}: {e}");
+
+    if e.is_retryable() {
+        return retry_later(Placeholder::Failed);
+    }
+
+    let (status, placeholder) = match e {
+        FetchError::Status(StatusCode::NOT_FOUND | StatusCode::GONE) => {
+            (StatusCode::NOT_FOUND, Placeholder::Failed)
+        }
+        FetchError::Cancelled => (StatusCode::GONE, Placeholder::Expired),
+        _ => (StatusCode::BAD_GATEWAY, Placeholder::Failed),
+    };
+
+    let placeholder = Image::placeholder(placeholder);
+    (error_status(status), Extension(IsPlaceholder), placeholder).into_response()
+}
+
+/// The response for a link that isn't in the `LinkMap`.
+fn missing_link(id: LinkId) -> Response {
+    if let Some(removal) = LinkMap::global().removal(id) {
+        tracing::info!("link {id} was removed: {removal:?}");
+        return error_text(StatusCode::GONE, removal.to_string());
+    }
+
+    // links that never existed are how link ids are guessed
+    abuse::record_missing_link();
+
+    // mimics the behavior of the original proxy
+    error_text(StatusCode::NOT_FOUND, "Link expired")
+}
+
+/// The response for a refresh that failed.
+fn refresh_error(e: RefreshError) -> Response {
+    let status = match e {
+        RefreshError::Expired => StatusCode::GONE,
+        RefreshError::LimitReached => StatusCode::FORBIDDEN,
+    };
+
+    error_text(status, e.to_string())
+}
+
+/// The response for a failed request to the e621 API.
+fn upstream_error(e: &reqwest::Error) -> Response {
+    tracing::warn!("external query failed: {e}");
+
+    let status = if e.is_timeout() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+
+    error_text(status, "An error occured during the external query.")
+}
+
+/// Handler for the `/comments/:post_id/:page` endpoint.
+///
+/// Returns one line per comment, formatted as `author,score,body`. The body
+/// has its DText markup stripped, and is placed last so that clients only
+/// need to split on the first two commas.
+async fn comments(Path((post_id, page)): Path<(String, String)>) -> Response {
+    let (Ok(post_id), Ok(page)) = (post_id.parse(), page.parse()) else {
+        return error_text(StatusCode::BAD_REQUEST, "Invalid post id or page.");
+    };
+
+    tracing::info!("comments: {post_id} page {page}");
+    let comments = match api::comments(post_id, page).await {
+        Ok(comments) => comments,
+        Err(e) => return upstream_error(&e),
+    };
+
+    let lines = comments
+        .iter()
+        .filter(|comment| !comment.is_hidden)
+        .map(|comment| {
+            let body = dtext::strip(&comment.body);
+            format!("{},{},{body}", comment.creator_name, comment.score)
+        })
+        .join("\n");
+
+    text(lines)
+}
+
 /// Handler for any route that doesn't match the other handlers.
 ///
 /// Returns HTML to mimic the behavior of the original proxy.
 async fn fallback(req: Request) -> Response {
-    log::warn!("tried to GET: {}", req.uri().path());
+    tracing::warn!("tried to GET: {}", req.uri().path());
 
-    text(format!(
-        r#"<html lang="en">
+    error_text(
+        StatusCode::NOT_FOUND,
+        format!(
+            r#"<html lang="en">
     <head>
         <meta charset="utf-8">
         <title>Error</title>
@@ -182,8 +1428,17 @@ async fn fallback(req: Request) -> Response {
         <pre>Cannot GET {}</pre>
     </body>
 </html>"#,
-        req.uri().path()
-    ))
+            req.uri().path()
+        ),
+    )
+}
+
+/// Create a response for a `SearchMap`, with the content type of its format.
+fn search_map(search_map: impl ToString, format: Format) -> Response {
+    match format {
+        Format::Json => json(search_map.to_string()),
+        Format::Classic | Format::Tsv => text(search_map.to_string()),
+    }
 }
 
 /// Create a text/html response.
@@ -194,3 +1449,62 @@ fn text(str: impl Into<String>) -> Response {
     )
         .into_response()
 }
+
+/// Create a text/html response for an error.
+///
+/// Like the original proxy, errors are sent with a `200`, since old VRChat
+/// worlds only read the body of successful responses. Instances with strict
+/// status codes send the given status instead.
+fn error_text(status: StatusCode, str: impl Into<String>) -> Response {
+    (error_status(status), text(str)).into_response()
+}
+
+/// The status of an error response: the given status for instances with
+/// strict status codes, and a `200` like the original proxy otherwise.
+fn error_status(status: StatusCode) -> StatusCode {
+    if Config::global().server.strict_status {
+        status
+    } else {
+        StatusCode::OK
+    }
+}
+
+/// Tell the client of an error response when to try again, if the instance
+/// sends strict status codes. Without them, the error is a `200`, which
+/// clients aren't expected to retry.
+fn retry_after(res: &mut Response, value: HeaderValue) {
+    if Config::global().server.strict_status {
+        res.headers_mut().insert(header::RETRY_AFTER, value);
+    }
+}
+
+/// Create an application/json response.
+fn json(str: impl Into<String>) -> Response {
+    ([(header::CONTENT_TYPE, "application/json")], str.into()).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{header, StatusCode};
+
+    use super::{cache_headers, retry_later, text, Placeholder};
+
+    #[test]
+    fn test_loading_placeholder_is_not_cached() {
+        let etag = || Some("W/\"1\"".to_owned());
+        let immutable = "public, max-age=31536000, immutable";
+
+        // without strict status codes, the placeholder is a `200`
+        let mut res = retry_later(Placeholder::Loading);
+        assert_eq!(res.status(), StatusCode::OK);
+
+        cache_headers(&mut res, etag(), immutable);
+        assert!(res.headers().get(header::ETAG).is_none());
+        assert!(res.headers().get(header::CACHE_CONTROL).is_none());
+
+        let mut res = text("image");
+        cache_headers(&mut res, etag(), immutable);
+        assert_eq!(res.headers()[header::ETAG], "W/\"1\"");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], immutable);
+    }
+}
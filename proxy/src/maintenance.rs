@@ -0,0 +1,34 @@
+//! Maintenance mode.
+//!
+//! While the instance is under maintenance, new searches are answered with a
+//! message asking clients to try again later, instead of failing, while links
+//! to existing searches keep being served. Operators turn it on before an
+//! upgrade, so that worlds show a friendly message rather than an error.
+//!
+//! It is turned on by `server.maintenance`, or through the admin API, which
+//! overrides the configuration until the proxy restarts.
+
+use std::sync::Mutex;
+
+use crate::config::Config;
+
+/// Whether the admin API turned maintenance mode on or off, overriding the
+/// configuration.
+static OVERRIDE: Mutex<Option<bool>> = Mutex::new(None);
+
+/// Whether the instance is under maintenance.
+pub fn enabled() -> bool {
+    let overridden = *OVERRIDE.lock().expect("poisoned");
+    overridden.unwrap_or(Config::global().server.maintenance)
+}
+
+/// Turn maintenance mode on or off, or back to the configuration if `None`.
+pub fn set(enabled: Option<bool>) {
+    *OVERRIDE.lock().expect("poisoned") = enabled;
+
+    match enabled {
+        Some(true) => tracing::warn!("maintenance mode turned on"),
+        Some(false) => tracing::warn!("maintenance mode turned off"),
+        None => tracing::warn!("maintenance mode follows the config again"),
+    }
+}
@@ -0,0 +1,397 @@
+//! Counters of link activity, requests and requests to e621.
+//!
+//! The counters are served through `/metrics` in the Prometheus text format,
+//! so that operators can tune link lifetimes based on how links are actually
+//! used, and see which routes and upstream calls are slow. The endpoint may
+//! be served on a separate internal address instead, so that it isn't public.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use itertools::Itertools;
+
+use crate::links::LinkMap;
+use crate::{api, budget, epoch};
+
+/// How many minutes of requests to e621 `recent_upstream` covers.
+const RECENT_MINUTES: u64 = 5;
+
+/// The maximum number of queries with their own stats. Past this, the least
+/// searched query is forgotten to make room.
+const MAX_QUERIES: usize = 1000;
+
+/// The upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters of link activity and requests since the proxy started.
+pub struct Metrics {
+    /// The number of teardowns waiting for their deadline.
+    timers: AtomicU64,
+    /// The number of refreshes that kept a resource alive.
+    refreshes: AtomicU64,
+    /// The number of refreshes that came too late, or went over the limits.
+    refused: AtomicU64,
+    /// The number of teardowns that ran because they weren't refreshed.
+    expirations: AtomicU64,
+    /// The number of links evicted to stay under the maximum.
+    evictions: AtomicU64,
+    /// The number of images found in the image cache.
+    cache_hits: AtomicU64,
+    /// The number of images not found in the image cache.
+    cache_misses: AtomicU64,
+    /// How long requests took to answer, by route.
+    routes: Mutex<BTreeMap<String, Histogram>>,
+    /// How long requests to e621 took, and how many failed, by host.
+    upstream: Mutex<BTreeMap<String, (Histogram, u64)>>,
+    /// The number of requests to e621, and how many failed, by minute since
+    /// the epoch, over the last `RECENT_MINUTES`.
+    recent: Mutex<VecDeque<(u64, u64, u64)>>,
+    /// How often each query was searched, by its tags.
+    queries: Mutex<BTreeMap<String, QueryStats>>,
+}
+
+/// How often a query was searched, and how it was answered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueryStats {
+    /// Searches that queried e621.
+    pub searches: u64,
+    /// Searches answered with the `SearchMap` of a live search.
+    pub shared: u64,
+    /// Searches that queried e621 and failed.
+    pub failed: u64,
+    /// The total time taken by searches that queried e621.
+    pub elapsed: Duration,
+}
+
+impl QueryStats {
+    const fn total(&self) -> u64 {
+        self.searches + self.shared
+    }
+}
+
+impl Metrics {
+    /// Get the global `Metrics`.
+    pub fn global() -> &'static Self {
+        static METRICS: Metrics = Metrics {
+            timers: AtomicU64::new(0),
+            refreshes: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            routes: Mutex::new(BTreeMap::new()),
+            upstream: Mutex::new(BTreeMap::new()),
+            recent: Mutex::new(VecDeque::new()),
+            queries: Mutex::new(BTreeMap::new()),
+        };
+
+        &METRICS
+    }
+
+    /// Count a teardown that started waiting for its deadline.
+    pub fn timer_started(&self) {
+        self.timers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a teardown that stopped waiting, because it ran or was
+    /// cancelled.
+    pub fn timer_stopped(&self, expired: bool) {
+        self.timers.fetch_sub(1, Ordering::Relaxed);
+        if expired {
+            self.expirations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a refresh, and whether it kept its resource alive.
+    pub fn refreshed(&self, ok: bool) {
+        let counter = if ok { &self.refreshes } else { &self.refused };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count evicted links.
+    pub fn evicted(&self, count: usize) {
+        let count = u64::try_from(count).unwrap_or(u64::MAX);
+        self.evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Count a lookup in the image cache, and whether it found the image.
+    pub fn image_cache(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request to e621, with how long it took to get a response, and
+    /// whether it failed.
+    pub fn upstream(&self, host: &str, elapsed: Duration, failed: bool) {
+        let mut upstream = self.upstream.lock().expect("poisoned");
+        let (latency, errors) = upstream.entry(host.to_owned()).or_default();
+
+        latency.observe(elapsed);
+        if failed {
+            *errors += 1;
+        }
+        drop(upstream);
+
+        let minute = epoch::minute();
+        let mut recent = self.recent.lock().expect("poisoned");
+        match recent.back_mut() {
+            Some((at, requests, errors)) if *at == minute => {
+                *requests += 1;
+                *errors += u64::from(failed);
+            }
+            _ => recent.push_back((minute, 1, u64::from(failed))),
+        }
+        while recent
+            .front()
+            .is_some_and(|&(at, ..)| at + RECENT_MINUTES <= minute)
+        {
+            recent.pop_front();
+        }
+    }
+
+    /// Count a search for a query, by its tags. Shared searches have no
+    /// `elapsed`, since they don't query e621.
+    pub fn query(&self, tags: &str, elapsed: Option<Duration>, failed: bool) {
+        let mut queries = self.queries.lock().expect("poisoned");
+        if queries.len() >= MAX_QUERIES && !queries.contains_key(tags) {
+            let least = queries
+                .iter()
+                .min_by_key(|(_, stats)| stats.total())
+                .map(|(tags, _)| tags.clone());
+            if let Some(least) = least {
+                queries.remove(&least);
+            }
+        }
+
+        let stats = queries.entry(tags.to_owned()).or_default();
+        match elapsed {
+            Some(elapsed) => {
+                stats.searches += 1;
+                stats.elapsed += elapsed;
+            }
+            None => stats.shared += 1,
+        }
+        if failed {
+            stats.failed += 1;
+        }
+    }
+
+    /// The stats of the most searched queries, most searched first.
+    pub fn top_queries(&self, limit: usize) -> Vec<(String, QueryStats)> {
+        let queries = self.queries.lock().expect("poisoned");
+
+        queries
+            .iter()
+            .map(|(tags, stats)| (tags.clone(), *stats))
+            .sorted_unstable_by_key(|(_, stats)| std::cmp::Reverse(stats.total()))
+            .take(limit)
+            .collect()
+    }
+
+    /// The number of requests to e621 over the last few minutes, and how many
+    /// of them failed.
+    pub fn recent_upstream(&self) -> (u64, u64) {
+        let minute = epoch::minute();
+
+        self.recent
+            .lock()
+            .expect("poisoned")
+            .iter()
+            .filter(|&&(at, ..)| at + RECENT_MINUTES > minute)
+            .fold((0, 0), |(requests, errors), &(_, r, e)| {
+                (requests + r, errors + e)
+            })
+    }
+}
+
+/// Middleware that counts requests, and how long they took to answer, by
+/// route.
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("fallback", MatchedPath::as_str)
+        .to_owned();
+    let start = Instant::now();
+
+    let res = next.run(req).await;
+
+    let mut routes = Metrics::global().routes.lock().expect("poisoned");
+    routes.entry(route).or_default().observe(start.elapsed());
+
+    res
+}
+
+/// A histogram of latencies, with the Prometheus `BUCKETS`.
+#[derive(Default)]
+struct Histogram {
+    /// The number of latencies at most as long as each bucket.
+    buckets: [u64; BUCKETS.len()],
+    /// The total of the latencies, in seconds.
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, &le) in self.buckets.iter_mut().zip(&BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    /// Write the samples of this histogram, with the given labels.
+    fn write(&self, f: &mut fmt::Formatter<'_>, name: &str, labels: &str) -> fmt::Result {
+        for (count, le) in self.buckets.iter().zip(BUCKETS) {
+            writeln!(f, "roli_{name}_bucket{{{labels},le=\"{le}\"}} {count}")?;
+        }
+        writeln!(
+            f,
+            "roli_{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+            self.count
+        )?;
+        writeln!(f, "roli_{name}_sum{{{labels}}} {}", self.sum)?;
+        writeln!(f, "roli_{name}_count{{{labels}}} {}", self.count)
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let len = |n: usize| u64::try_from(n).unwrap_or(u64::MAX);
+
+        let metrics = [
+            (
+                "refresh_timers",
+                "gauge",
+                "Teardowns waiting for their deadline.",
+                load(&self.timers),
+            ),
+            (
+                "refreshes_total",
+                "counter",
+                "Refreshes that kept a link alive.",
+                load(&self.refreshes),
+            ),
+            (
+                "refreshes_refused_total",
+                "counter",
+                "Refreshes of expired or limited links.",
+                load(&self.refused),
+            ),
+            (
+                "expirations_total",
+                "counter",
+                "Teardowns of links that weren't refreshed.",
+                load(&self.expirations),
+            ),
+            (
+                "evictions_total",
+                "counter",
+                "Links evicted to stay under the maximum.",
+                load(&self.evictions),
+            ),
+            (
+                "links",
+                "gauge",
+                "Links held by this instance.",
+                len(LinkMap::global().count()),
+            ),
+            (
+                "image_bytes",
+                "gauge",
+                "Bytes of images held by links.",
+                len(budget::used_bytes()),
+            ),
+            (
+                "image_cache_bytes",
+                "gauge",
+                "Bytes of images in the image cache.",
+                len(api::cached_bytes()),
+            ),
+            (
+                "image_cache_hits_total",
+                "counter",
+                "Images found in the image cache.",
+                load(&self.cache_hits),
+            ),
+            (
+                "image_cache_misses_total",
+                "counter",
+                "Images not found in the image cache.",
+                load(&self.cache_misses),
+            ),
+        ];
+
+        for (name, kind, help, value) in metrics {
+            writeln!(f, "# HELP roli_{name} {help}")?;
+            writeln!(f, "# TYPE roli_{name} {kind}")?;
+            writeln!(f, "roli_{name} {value}")?;
+        }
+
+        writeln!(
+            f,
+            "# HELP roli_request_duration_seconds Time taken to answer requests."
+        )?;
+        writeln!(f, "# TYPE roli_request_duration_seconds histogram")?;
+        for (route, latency) in self.routes.lock().expect("poisoned").iter() {
+            latency.write(f, "request_duration_seconds", &format!("route=\"{route}\""))?;
+        }
+
+        let upstream = self.upstream.lock().expect("poisoned");
+        writeln!(
+            f,
+            "# HELP roli_upstream_duration_seconds Time taken by e621 to respond."
+        )?;
+        writeln!(f, "# TYPE roli_upstream_duration_seconds histogram")?;
+        for (host, (latency, _)) in upstream.iter() {
+            latency.write(f, "upstream_duration_seconds", &format!("host=\"{host}\""))?;
+        }
+        writeln!(
+            f,
+            "# HELP roli_upstream_errors_total Requests to e621 that failed."
+        )?;
+        writeln!(f, "# TYPE roli_upstream_errors_total counter")?;
+        for (host, (_, errors)) in upstream.iter() {
+            writeln!(f, "roli_upstream_errors_total{{host=\"{host}\"}} {errors}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = super::Histogram::default();
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_millis(300));
+        histogram.observe(Duration::from_secs(60));
+
+        // buckets are cumulative, and the slowest request is only in `+Inf`
+        assert_eq!(histogram.buckets, [0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+        assert_eq!(histogram.count, 3);
+        assert!((histogram.sum - 60.32).abs() < 1e-9);
+    }
+}
@@ -4,12 +4,18 @@
 //! not be ready yet. The `Promise` type should be used when the computation
 //! should start immediately, while the `LazyPromise` type should be used when
 //! the computation should start only when the value is first "requested".
+//!
+//! Once every clone of a promise is dropped, its computation is dropped with
+//! it, even if it hasn't finished.
 
 use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use futures::future::BoxFuture;
-use tokio::sync::{Barrier, Mutex, OnceCell};
+use tokio::sync::{Mutex, Notify, OnceCell};
+use tokio::task::AbortHandle;
+use tracing::Instrument;
 
 /// Asynchronously obtain a reference to a value that may not be ready yet.
 ///
@@ -19,39 +25,92 @@ use tokio::sync::{Barrier, Mutex, OnceCell};
 #[derive(Clone)]
 pub struct Promise<T> {
     item: Arc<OnceCell<T>>,
+    /// Notified once the value is set.
+    ready: Arc<Notify>,
+    /// Only held to be dropped with the last clone.
+    _task: Arc<AbortOnDrop>,
+}
+
+/// The number of `Promise`s whose value is still being computed.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of `Promise`s whose value is still being computed.
+pub fn pending() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// Counts a `Promise` as pending for as long as it is held by its task, which
+/// drops it once it finishes or is aborted.
+struct Pending;
+
+impl Pending {
+    fn new() -> Self {
+        PENDING.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Aborts the task computing the value of a `Promise` when the last clone of
+/// the promise is dropped.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl<T: Send + 'static + Sync> Promise<T> {
     /// Construct a new `Promise` where `T` is the output of the given future.
     ///
     /// The future will immediately spawn.
-    pub async fn new<Fut>(fut: Fut) -> Self
+    pub fn new<Fut>(fut: Fut) -> Self
     where
         Fut: Future<Output = T> + Send + 'static,
     {
-        // todo: is there a way to do this without the weird af barrier stuff
         let item: Arc<OnceCell<T>> = Arc::default();
+        let ready = Arc::new(Notify::new());
 
-        let bar = Arc::new(Barrier::new(2));
-        let bar_c = bar.clone();
+        let (ptr, notify) = (item.clone(), ready.clone());
+        let pending = Pending::new();
+        let task = async move {
+            let _pending = pending;
+            let _ = ptr.set(fut.await);
+            notify.notify_waiters();
+        };
+        let task = tokio::spawn(task.in_current_span());
 
-        let ptr = item.clone();
-        tokio::spawn(async move {
-            let initer = ptr.get_or_init(|| fut);
+        Self {
+            item,
+            ready,
+            _task: Arc::new(AbortOnDrop(task.abort_handle())),
+        }
+    }
 
-            bar.wait().await;
+    /// Get a reference to the inner value.
+    pub async fn get(&self) -> &T {
+        loop {
+            // registered before checking the value, so that it can't be set
+            // in between without waking this
+            let ready = self.ready.notified();
 
-            let _ = initer.await;
-        });
+            if let Some(item) = self.item.get() {
+                return item;
+            }
 
-        bar_c.wait().await;
-        Self { item }
+            ready.await;
+        }
     }
 
-    /// Get a reference to the inner value.
-    pub async fn get(&self) -> &T {
-        // pending is essentially a no-op future
-        self.item.get_or_init(futures::future::pending).await
+    /// Get a reference to the inner value, if it is ready.
+    pub fn try_get(&self) -> Option<&T> {
+        self.item.get()
     }
 }
 
@@ -87,8 +146,12 @@ impl<T: Send + 'static + Sync> LazyPromise<T> {
     }
 
     /// Initialize the inner value.
+    ///
+    /// Only one caller of `get()` runs this at a time. If it is cancelled
+    /// partway, the next caller waits for the lock and resumes the same future
+    /// where it was left.
     async fn init(&self) -> T {
-        let mut t = self.fut.try_lock().expect("locked twice");
+        let mut t = self.fut.lock().await;
         (&mut *t).await
     }
 
@@ -99,6 +162,13 @@ impl<T: Send + 'static + Sync> LazyPromise<T> {
     pub async fn get(&self) -> &T {
         self.item.get_or_init(|| self.init()).await
     }
+
+    /// Get a reference to the inner value, if it is ready.
+    ///
+    /// Unlike `get()`, this doesn't start the computation.
+    pub fn try_get(&self) -> Option<&T> {
+        self.item.get()
+    }
 }
 
 #[cfg(test)]
@@ -112,8 +182,7 @@ mod test {
             // some async computation
             tokio::time::sleep(std::time::Duration::from_millis(500)).await;
             "hello".to_string()
-        })
-        .await;
+        });
 
         tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 
@@ -124,6 +193,25 @@ mod test {
         assert_eq!(*p.get().await, "hello");
     }
 
+    #[tokio::test]
+    async fn test_promise_dropped() {
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let flag = done.clone();
+        let p = super::Promise::new(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+
+        let clone = p.clone();
+        drop(p);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        drop(clone);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        assert!(!done.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
     #[tokio::test]
     async fn test_lazy_promise() {
         let now = std::time::Instant::now();
@@ -142,4 +230,34 @@ mod test {
         assert!(now.elapsed().as_millis() > 500);
         assert_eq!(*p.get().await, "hello");
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lazy_promise_concurrent() {
+        let p = super::LazyPromise::new(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            "hello".to_string()
+        });
+
+        let gets = (0..8).map(|_| {
+            let p = p.clone();
+            tokio::spawn(async move { p.get().await.clone() })
+        });
+
+        for get in futures::future::join_all(gets).await {
+            assert_eq!(get.unwrap(), "hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lazy_promise_cancelled_get() {
+        let p = super::LazyPromise::new(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            "hello".to_string()
+        });
+
+        let first = tokio::time::timeout(std::time::Duration::from_millis(10), p.get()).await;
+        assert!(first.is_err());
+
+        assert_eq!(*p.get().await, "hello");
+    }
 }
@@ -0,0 +1,728 @@
+//! Parsing of client search queries.
+//!
+//! Clients send a free-form query string, which is mostly a list of e621 tags,
+//! but may also contain tokens that are only meaningful to the proxy (like
+//! `page:2`, `!nogore` or `@macro`). `Query::parse` separates the two, so that only
+//! tags are forwarded to the e621 API.
+//!
+//! Before parsing, the query string is normalized. It is taken from the path
+//! as sent, split on whitespace and `+`, which VRChat clients use as a tag
+//! separator, and only then percent-decoded, once, so that tags containing
+//! `+` can be searched as `%2B`.
+//!
+//! Problems with a query are reported as a `QueryError`, which is displayed as
+//! a single `error,code,message` line that in-world UIs can parse.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::api;
+use crate::config::QueryConfig;
+use crate::links::{Format, COLUMNS, SEARCH_MAP_VERSIONS};
+
+/// A post rating.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Safe,
+    Questionable,
+    Explicit,
+}
+
+impl Rating {
+    /// Every rating, from safest to least safe.
+    const ALL: [Self; 3] = [Self::Safe, Self::Questionable, Self::Explicit];
+
+    /// Parse a rating toggle, like `safe`.
+    fn from_toggle(token: &str) -> Option<Self> {
+        match token {
+            "safe" => Some(Self::Safe),
+            "questionable" => Some(Self::Questionable),
+            "explicit" => Some(Self::Explicit),
+            _ => None,
+        }
+    }
+
+    /// The e621 meta-tag that selects posts with this rating.
+    const fn tag(self) -> &'static str {
+        match self {
+            Self::Safe => "rating:s",
+            Self::Questionable => "rating:q",
+            Self::Explicit => "rating:e",
+        }
+    }
+}
+
+/// A parsed client search query.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+pub struct Query {
+    /// The tags to forward to the e621 API.
+    pub tags: Vec<String>,
+    /// The page of results to request, starting at 1.
+    pub page: u32,
+    /// The token of a client blacklist to apply, given with `bl:token`.
+    pub blacklist: Option<String>,
+    /// Whether to include animated posts in the results.
+    pub animated: bool,
+    /// The `SearchMap` format version to respond with, given with `v:N`.
+    pub version: u32,
+    /// The `SearchMap` format to respond with, given with `fmt:name`.
+    pub format: Format,
+    /// The post columns to include in the `SearchMap`, given with
+    /// `cols:a,b,c`. All columns of the format version are included if none
+    /// are given.
+    pub columns: Option<Vec<String>>,
+}
+
+impl Query {
+    /// Parse a query string.
+    ///
+    /// The page is given explicitly with `page:N` or `p:N`. If neither is
+    /// present and `trailing_page_number` is enabled, a trailing number is
+    /// treated as the page instead.
+    ///
+    /// Blacklist presets are enabled with `!name`, and are expanded into the
+    /// excluded tags configured for them. Macros are used with `@name`, and
+    /// are replaced by the query string configured for them before anything
+    /// else is parsed. A client blacklist is selected with `bl:token`, but is
+    /// only recorded here; see `Blacklists` for how it is applied.
+    ///
+    /// The `safe`, `questionable` and `explicit` toggles limit the results to
+    /// the ratings given; without any, all ratings are allowed. Ratings above
+    /// the instance's `max_rating` are always excluded, regardless of input.
+    ///
+    /// Animated posts are included with `anim:yes` and excluded with
+    /// `anim:no`, defaulting to the instance's `allow_animated`.
+    ///
+    /// `wide`, `tall` and `square` limit the results to posts of that shape.
+    ///
+    /// `v:N` selects the `SearchMap` format version, defaulting to 1, and
+    /// `fmt:name` selects the `SearchMap` format. `cols:a,b,c` selects the
+    /// post columns to include, overriding the format version.
+    ///
+    /// `min:N` is shorthand for `score:>=N`. If the query doesn't filter by
+    /// score at all, the instance's `min_score` is applied, if it has one.
+    pub fn parse(input: &str, config: &QueryConfig) -> Result<Self, QueryError> {
+        let mut tags = Vec::new();
+        let mut presets = Vec::new();
+        let mut ratings = Vec::new();
+        let mut page = None;
+        let mut blacklist = None;
+        let mut animated = None;
+        let mut version = None;
+        let mut format = Format::default();
+        let mut columns = None;
+
+        for token in expand_macros(split(input), config)? {
+            if let Some(n) = parse_page(&token) {
+                page = Some(n);
+            } else if let Some(rating) = Rating::from_toggle(&token) {
+                ratings.push(rating);
+            } else if let Some(ratio) = parse_shape(&token) {
+                tags.push(ratio.to_owned());
+            } else if let Some(score) = parse_min_score(&token) {
+                tags.push(format!("score:>={score}"));
+            } else if let Some(n) = parse_version(&token) {
+                version = Some(n);
+            } else if let Some(name) = token.strip_prefix("fmt:") {
+                format = Format::from_name(name)
+                    .ok_or_else(|| QueryError::UnknownFormat(name.into()))?;
+            } else if let Some(names) = token.strip_prefix("cols:") {
+                columns = Some(parse_columns(names)?);
+            } else if let Some(flag) = parse_animated(&token) {
+                animated = Some(flag);
+            } else if let Some(token) = token.strip_prefix("bl:") {
+                blacklist = Some(token.to_owned());
+            } else if let Some(name) = token.strip_prefix('!') {
+                let preset = config.presets.get(name);
+                presets.push(preset.ok_or_else(|| QueryError::UnknownPreset(name.into()))?);
+            } else {
+                tags.push(token);
+            }
+        }
+
+        if page.is_none() && config.trailing_page_number {
+            if let Some(n) = tags.last().and_then(|tag| tag.parse().ok()) {
+                tags.pop();
+                page = Some(n);
+            }
+        }
+
+        if let Some(score) = config.min_score {
+            let has_score = tags
+                .iter()
+                .any(|tag| tag.trim_start_matches(['-', '~']).starts_with("score:"));
+
+            if !has_score {
+                tags.push(format!("score:>={score}"));
+            }
+        }
+
+        let max_rating = config.max_rating.unwrap_or(Rating::Explicit);
+        let allowed = |rating: &Rating| {
+            *rating <= max_rating && (ratings.is_empty() || ratings.contains(rating))
+        };
+
+        if !ratings.is_empty() && !ratings.iter().any(allowed) {
+            return Err(QueryError::RatingNotAllowed(max_rating));
+        }
+
+        let excluded_ratings = Rating::ALL.into_iter().filter(|rating| !allowed(rating));
+        tags.extend(excluded_ratings.map(|rating| format!("-{}", rating.tag())));
+
+        let mut query = Self {
+            tags,
+            page: page.unwrap_or(1).max(1),
+            blacklist,
+            animated: animated.unwrap_or(config.allow_animated),
+            version: version.unwrap_or(1),
+            format,
+            columns,
+        };
+
+        if !SEARCH_MAP_VERSIONS.contains(&query.version) {
+            return Err(QueryError::UnsupportedVersion(query.version));
+        }
+
+        for preset in presets {
+            query.exclude(preset);
+        }
+
+        Ok(query)
+    }
+
+    /// Check that the query can be sent to the e621 API.
+    ///
+    /// This should be called once the query is complete, i.e. after any client
+    /// blacklist has been applied.
+    pub fn validate(&self, config: &QueryConfig) -> Result<(), QueryError> {
+        if self.tags.is_empty() && !config.allow_empty {
+            return Err(QueryError::Empty);
+        }
+
+        let limit = tag_limit(config, self.animated);
+        if self.tags.len() > limit {
+            return Err(QueryError::TooManyTags(limit));
+        }
+
+        // tags like `16:9` or `re:zero` have colons too, so only names one
+        // typo away from a meta-tag are rejected
+        let unknown = self.tags.iter().find_map(|tag| {
+            let (name, _) = tag.trim_start_matches(['-', '~']).split_once(':')?;
+            let typo = !METATAGS.contains(&name.to_lowercase().as_str())
+                && METATAGS
+                    .iter()
+                    .any(|metatag| edit_distance(&name.to_lowercase(), metatag) == 1);
+            typo.then(|| name.to_owned())
+        });
+
+        match unknown {
+            Some(name) => Err(QueryError::UnknownMetatag(name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Exclude the given tags from the query, skipping any that are already
+    /// excluded.
+    pub fn exclude<T: AsRef<str>>(&mut self, tags: impl IntoIterator<Item = T>) {
+        for tag in tags {
+            let tag = format!("-{}", tag.as_ref().trim_start_matches('-'));
+
+            if !self.tags.contains(&tag) {
+                self.tags.push(tag);
+            }
+        }
+    }
+
+    /// Get the tags as a single string, as expected by the e621 API.
+    pub fn tags(&self) -> String {
+        self.tags.join(" ")
+    }
+}
+
+/// The number of tags a query may contain: the configured maximum, unless
+/// the tags the instance adds would take the search past what e621 accepts.
+pub fn tag_limit(config: &QueryConfig, animated: bool) -> usize {
+    let added = api::added_tags(config, animated).len();
+    config.max_tags.min(api::MAX_TAGS.saturating_sub(added))
+}
+
+/// The number of single-character insertions, deletions and substitutions
+/// between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// The meta-tags understood by the e621 API.
+const METATAGS: &[&str] = &[
+    "id",
+    "rating",
+    "score",
+    "favcount",
+    "fav",
+    "user",
+    "approver",
+    "commenter",
+    "noter",
+    "pool",
+    "set",
+    "order",
+    "type",
+    "width",
+    "height",
+    "mpixels",
+    "ratio",
+    "filesize",
+    "date",
+    "status",
+    "source",
+    "description",
+    "md5",
+    "parent",
+    "child",
+    "ischild",
+    "isparent",
+    "hassource",
+    "hasdescription",
+    "tagcount",
+    "gentags",
+    "arttags",
+    "chartags",
+    "copytags",
+    "spectags",
+    "invtags",
+    "lortags",
+    "metatags",
+    "comment_count",
+    "duration",
+    "upvotes",
+    "downvotes",
+    "ratinglocked",
+    "notelocked",
+    "statuslocked",
+    "pending_replacements",
+    "inpool",
+    "delreason",
+    "deletedby",
+    "randseed",
+];
+
+/// A problem with a client query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// The query contains no tags.
+    Empty,
+    /// The query contains more than the given number of tags.
+    TooManyTags(usize),
+    /// The query uses a meta-tag that e621 doesn't support.
+    UnknownMetatag(String),
+    /// The query enables a blacklist preset that doesn't exist.
+    UnknownPreset(String),
+    /// The query uses a macro that doesn't exist.
+    UnknownMacro(String),
+    /// The query only allows ratings above the given maximum.
+    RatingNotAllowed(Rating),
+    /// The query asks for a `SearchMap` version that doesn't exist.
+    UnsupportedVersion(u32),
+    /// The query asks for a `SearchMap` format that doesn't exist.
+    UnknownFormat(String),
+    /// The query selects a `SearchMap` column that doesn't exist.
+    UnknownColumn(String),
+    /// The query searches for a term the instance banned.
+    BannedTerm,
+}
+
+impl QueryError {
+    /// A short, stable identifier for this kind of error.
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty_query",
+            Self::TooManyTags(_) => "too_many_tags",
+            Self::UnknownMetatag(_) => "unknown_metatag",
+            Self::UnknownPreset(_) => "unknown_preset",
+            Self::UnknownMacro(_) => "unknown_macro",
+            Self::RatingNotAllowed(_) => "rating_not_allowed",
+            Self::UnsupportedVersion(_) => "unsupported_version",
+            Self::UnknownFormat(_) => "unknown_format",
+            Self::UnknownColumn(_) => "unknown_column",
+            Self::BannedTerm => "banned_term",
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error,{},", self.code())?;
+
+        match self {
+            Self::Empty => write!(f, "Enter at least one tag to search for."),
+            Self::TooManyTags(max) => write!(f, "Searches are limited to {max} tags."),
+            Self::UnknownMetatag(name) => write!(f, "Unknown meta-tag: {name}"),
+            Self::UnknownPreset(name) => write!(f, "Unknown blacklist preset: {name}"),
+            Self::UnknownMacro(name) => write!(f, "Unknown macro: {name}"),
+            Self::RatingNotAllowed(max) => {
+                write!(f, "This instance only allows posts up to {max:?}.")
+            }
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported SearchMap version: {version}")
+            }
+            Self::UnknownFormat(name) => write!(f, "Unknown SearchMap format: {name}"),
+            Self::UnknownColumn(name) => write!(f, "Unknown SearchMap column: {name}"),
+            Self::BannedTerm => write!(f, "This search isn't allowed on this instance."),
+        }
+    }
+}
+
+/// Split a query string, as sent in a URL, into its normalized tokens.
+///
+/// The string is split before it is percent-decoded, so that an encoded `+`
+/// is part of a tag. Encoded whitespace still separates tags, since tags
+/// can't contain any.
+pub fn split(input: &str) -> Vec<String> {
+    input
+        .split(is_separator)
+        .map(percent_decode)
+        .flat_map(|token| {
+            let tokens = token.split_whitespace().map(str::to_owned);
+            tokens.collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Replace any `@name` macros in a list of tokens with the tokens of the
+/// query string configured for them.
+///
+/// Macros are only expanded once, so a macro can't use another macro.
+fn expand_macros(tokens: Vec<String>, config: &QueryConfig) -> Result<Vec<String>, QueryError> {
+    let mut expanded = Vec::with_capacity(tokens.len());
+
+    for token in tokens {
+        let Some(name) = token.strip_prefix('@') else {
+            expanded.push(token);
+            continue;
+        };
+
+        let body = config.macros.get(name);
+        expanded.extend(split(
+            body.ok_or_else(|| QueryError::UnknownMacro(name.into()))?,
+        ));
+    }
+
+    Ok(expanded)
+}
+
+/// Whether a character separates tags in a query string.
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c == '+'
+}
+
+/// Decode any percent-encoded sequences in a query string.
+///
+/// The input is returned unchanged if it contains no valid escapes, or if
+/// decoding would produce invalid UTF-8.
+fn percent_decode(input: &str) -> Cow<'_, str> {
+    if !input.contains('%') {
+        return Cow::Borrowed(input);
+    }
+
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            // `from_str_radix` would also accept a sign, like `%+1`
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(out).map_or(Cow::Borrowed(input), Cow::Owned)
+}
+
+/// Parse a `v:N` token.
+fn parse_version(token: &str) -> Option<u32> {
+    token.strip_prefix("v:")?.parse().ok()
+}
+
+/// Parse the comma-separated column names of a `cols:` token.
+fn parse_columns(names: &str) -> Result<Vec<String>, QueryError> {
+    names
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            if COLUMNS.contains(&name) {
+                Ok(name.to_owned())
+            } else {
+                Err(QueryError::UnknownColumn(name.into()))
+            }
+        })
+        .collect()
+}
+
+/// Parse an `anim:yes` or `anim:no` token.
+fn parse_animated(token: &str) -> Option<bool> {
+    match token.strip_prefix("anim:")? {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a shape filter token into the `ratio` meta-tag it stands for.
+///
+/// A post is considered square if its sides are within 20% of each other.
+fn parse_shape(token: &str) -> Option<&'static str> {
+    match token {
+        "wide" => Some("ratio:>1.2"),
+        "tall" => Some("ratio:<0.83"),
+        "square" => Some("ratio:0.83..1.2"),
+        _ => None,
+    }
+}
+
+/// Parse a `min:N` token into its score.
+fn parse_min_score(token: &str) -> Option<i64> {
+    token.strip_prefix("min:")?.parse().ok()
+}
+
+/// Parse a `page:N` or `p:N` token.
+fn parse_page(token: &str) -> Option<u32> {
+    let n = token
+        .strip_prefix("page:")
+        .or_else(|| token.strip_prefix("p:"))?;
+
+    n.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Query, QueryError, Rating};
+    use crate::config::QueryConfig;
+    use crate::links::Format;
+
+    #[test]
+    fn test_explicit_page() {
+        let config = QueryConfig::default();
+
+        let query = Query::parse("luigi 64 page:3", &config).unwrap();
+        assert_eq!(query.tags(), "luigi 64");
+        assert_eq!(query.page, 3);
+
+        let query = Query::parse("p:2 luigi", &config).unwrap();
+        assert_eq!(query.tags(), "luigi");
+        assert_eq!(query.page, 2);
+    }
+
+    #[test]
+    fn test_normalization() {
+        let config = QueryConfig::default();
+
+        let query = Query::parse("fox+%28cat%29%20\t rating%3As+%C3%A9", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "(cat)", "rating:s", "é"]);
+
+        let query = Query::parse("100% fox", &config).unwrap();
+        assert_eq!(query.tags, ["100%", "fox"]);
+
+        // encoded separators are part of a tag, and escapes are decoded once
+        let query = Query::parse("c%2B%2B+100%2525", &config).unwrap();
+        assert_eq!(query.tags, ["c++", "100%25"]);
+
+        let query = Query::parse("a%+1b", &config).unwrap();
+        assert_eq!(query.tags, ["a%", "1b"]);
+    }
+
+    #[test]
+    fn test_presets() {
+        let mut config = QueryConfig::default();
+        config
+            .presets
+            .insert("nocats".into(), vec!["cat".into(), "lion".into()]);
+
+        let query = Query::parse("!nocats fox -lion", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "-lion", "-cat"]);
+
+        let error = Query::parse("fox !unknown", &config).unwrap_err();
+        assert_eq!(error, QueryError::UnknownPreset("unknown".into()));
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut config = QueryConfig::default();
+        config
+            .macros
+            .insert("canines".into(), "canine !noferal score:>=50".into());
+
+        let query = Query::parse("@canines solo p:2", &config).unwrap();
+        assert_eq!(query.tags, ["canine", "score:>=50", "solo", "-feral"]);
+        assert_eq!(query.page, 2);
+
+        let error = Query::parse("@felines", &config).unwrap_err();
+        assert_eq!(error, QueryError::UnknownMacro("felines".into()));
+    }
+
+    #[test]
+    fn test_min_score() {
+        let config = QueryConfig {
+            min_score: Some(10),
+            ..QueryConfig::default()
+        };
+
+        let query = Query::parse("fox min:50", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "score:>=50"]);
+
+        let query = Query::parse("fox -score:<0", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "-score:<0"]);
+
+        let query = Query::parse("fox", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "score:>=10"]);
+    }
+
+    #[test]
+    fn test_ratings() {
+        let mut config = QueryConfig::default();
+
+        let query = Query::parse("fox safe questionable", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "-rating:e"]);
+
+        config.max_rating = Some(Rating::Questionable);
+
+        let query = Query::parse("fox rating:e", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "rating:e", "-rating:e"]);
+
+        let query = Query::parse("fox questionable", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "-rating:s", "-rating:e"]);
+
+        let error = Query::parse("fox explicit", &config).unwrap_err();
+        assert_eq!(error, QueryError::RatingNotAllowed(Rating::Questionable));
+    }
+
+    #[test]
+    fn test_animated() {
+        let config = QueryConfig::default();
+
+        let query = Query::parse("fox", &config).unwrap();
+        assert!(!query.animated);
+
+        let query = Query::parse("fox anim:yes", &config).unwrap();
+        assert_eq!(query.tags, ["fox"]);
+        assert!(query.animated);
+    }
+
+    #[test]
+    fn test_shapes() {
+        let config = QueryConfig::default();
+
+        let query = Query::parse("fox wide", &config).unwrap();
+        assert_eq!(query.tags, ["fox", "ratio:>1.2"]);
+        assert_eq!(query.validate(&config), Ok(()));
+    }
+
+    #[test]
+    fn test_format() {
+        let config = QueryConfig::default();
+
+        let query = Query::parse("fox fmt:json", &config).unwrap();
+        assert_eq!(query.tags, ["fox"]);
+        assert_eq!(query.format, Format::Json);
+
+        let error = Query::parse("fox fmt:xml", &config).unwrap_err();
+        assert_eq!(error, QueryError::UnknownFormat("xml".into()));
+    }
+
+    #[test]
+    fn test_columns() {
+        let config = QueryConfig::default();
+
+        let query = Query::parse("fox cols:id,score,rating page:2", &config).unwrap();
+        assert_eq!(query.tags, ["fox"]);
+        assert_eq!(query.page, 2);
+        assert_eq!(query.columns.unwrap(), ["id", "score", "rating"]);
+
+        let error = Query::parse("fox cols:id,favs", &config).unwrap_err();
+        assert_eq!(error, QueryError::UnknownColumn("favs".into()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = QueryConfig {
+            max_tags: 2,
+            allow_empty: false,
+            ..QueryConfig::default()
+        };
+
+        let query = Query::parse("fox rating:s -score:<0 :3", &config).unwrap();
+        assert_eq!(query.validate(&config), Err(QueryError::TooManyTags(2)));
+
+        let query = Query::parse("fox -ratin:s", &config).unwrap();
+        let error = query.validate(&config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "error,unknown_metatag,Unknown meta-tag: ratin"
+        );
+
+        // tags with colons that aren't meta-tags are left to e621
+        let query = Query::parse("16:9 re:zero", &config).unwrap();
+        assert_eq!(query.validate(&config), Ok(()));
+
+        let query = Query::parse("page:2", &config).unwrap();
+        assert_eq!(query.validate(&config), Err(QueryError::Empty));
+
+        // the excludes added by the instance count towards e621's limit
+        let config = QueryConfig {
+            excludes: (0..10).map(|n| format!("tag{n}")).collect(),
+            ..QueryConfig::default()
+        };
+        let tags = (0..27).map(|n| format!("fox{n}")).collect::<Vec<_>>();
+        let tags = tags.join(" ");
+        let query = Query::parse(&tags, &config).unwrap();
+        assert_eq!(query.validate(&config), Err(QueryError::TooManyTags(26)));
+    }
+
+    #[test]
+    fn test_trailing_page() {
+        let mut config = QueryConfig {
+            trailing_page_number: true,
+            ..QueryConfig::default()
+        };
+
+        let query = Query::parse("  fox  wolf 12 ", &config).unwrap();
+        assert_eq!(query.tags(), "fox wolf");
+        assert_eq!(query.page, 12);
+
+        config.trailing_page_number = false;
+
+        let query = Query::parse("luigi 64", &config).unwrap();
+        assert_eq!(query.tags(), "luigi 64");
+        assert_eq!(query.page, 1);
+    }
+}
@@ -0,0 +1,203 @@
+//! Daily usage quotas per client address.
+//!
+//! Instances may limit how many searches each address makes, and how many
+//! bytes are served to it, per UTC day, so that a single busy world can't use
+//! up a public instance. Usage is written to disk every minute, and read
+//! back on startup, so that restarting the proxy doesn't reset it.
+//!
+//! Bytes are counted once a response is answered, from its size, so the
+//! response that goes over the quota is still served in full. Streamed
+//! responses without a `Content-Length` aren't counted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::{client, epoch};
+
+/// How often usage is written to disk.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The usage of a single address over the current day.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+struct Usage {
+    searches: u64,
+    bytes: u64,
+}
+
+/// The usage of every address over a day.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Ledger {
+    /// The day the usage is for, in days since the unix epoch.
+    day: u64,
+    clients: HashMap<IpAddr, Usage>,
+}
+
+/// A quota an address went over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+    Searches,
+    Bytes,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self {
+            Self::Searches => "searches",
+            Self::Bytes => "downloads",
+        };
+
+        write!(
+            f,
+            "error,quota_exceeded,This address has used up its {what} for today. Try again tomorrow."
+        )
+    }
+}
+
+impl Ledger {
+    /// Get a lock to the global `Ledger`.
+    fn get_lock() -> &'static Mutex<Self> {
+        static LEDGER: OnceLock<Mutex<Ledger>> = OnceLock::new();
+        LEDGER.get_or_init(Default::default)
+    }
+
+    /// The usage of an address today, starting a new day if it changed.
+    fn today(&mut self, ip: IpAddr) -> &mut Usage {
+        let day = epoch::day();
+        if self.day != day {
+            self.day = day;
+            self.clients.clear();
+        }
+
+        self.clients.entry(ip.to_canonical()).or_default()
+    }
+}
+
+/// The time left until the quotas reset, at midnight UTC.
+pub fn reset_in() -> Duration {
+    epoch::until_next(epoch::DAY)
+}
+
+/// Whether quotas are configured at all.
+pub fn enabled() -> bool {
+    let config = &Config::global().server.quota;
+    config.daily_searches.is_some() || config.daily_bytes.is_some()
+}
+
+/// Check that an address hasn't gone over its quota of bytes.
+pub fn check_bytes(ip: IpAddr) -> Result<(), Exceeded> {
+    let Some(max) = Config::global().server.quota.daily_bytes else {
+        return Ok(());
+    };
+
+    let mut ledger = Ledger::get_lock().lock().expect("poisoned");
+    if ledger.today(ip).bytes >= max {
+        return Err(Exceeded::Bytes);
+    }
+
+    Ok(())
+}
+
+/// Count bytes served to an address.
+pub fn add_bytes(ip: IpAddr, bytes: u64) {
+    let mut ledger = Ledger::get_lock().lock().expect("poisoned");
+    ledger.today(ip).bytes += bytes;
+}
+
+/// Count a search by the client of the current request, unless it has used
+/// up its quota of searches.
+pub fn take_search() -> Result<(), Exceeded> {
+    let Some(max) = Config::global().server.quota.daily_searches else {
+        return Ok(());
+    };
+    let Some(ip) = client::current() else {
+        return Ok(());
+    };
+
+    let mut ledger = Ledger::get_lock().lock().expect("poisoned");
+    let usage = ledger.today(ip);
+    if usage.searches >= max {
+        return Err(Exceeded::Searches);
+    }
+
+    usage.searches += 1;
+    Ok(())
+}
+
+/// Read the usage saved at the configured path, if it is for today, and
+/// spawn a task that saves it periodically.
+pub async fn init() {
+    if !enabled() {
+        return;
+    }
+
+    let path = &Config::global().server.quota.path;
+    match tokio::fs::read(path).await {
+        Ok(file) => match serde_json::from_slice::<Ledger>(&file) {
+            Ok(ledger) if ledger.day == epoch::day() => {
+                tracing::info!("restored quota usage of {} addresses", ledger.clients.len());
+                *Ledger::get_lock().lock().expect("poisoned") = ledger;
+            }
+            Ok(_) => tracing::info!("saved quota usage is from another day"),
+            Err(e) => tracing::error!("invalid quota usage at {}: {e}", path.display()),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => tracing::error!("failed to read quota usage {}: {e}", path.display()),
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        // the first tick completes immediately, and the usage was just read
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = save(path).await {
+                tracing::error!("failed to save quota usage to {}: {e}", path.display());
+            }
+        }
+    });
+}
+
+/// Save the usage to the given path, through a temporary file, so that a
+/// crash while saving doesn't lose it.
+async fn save(path: &Path) -> io::Result<()> {
+    let json = {
+        let ledger = Ledger::get_lock().lock().expect("poisoned");
+        serde_json::to_vec(&*ledger).map_err(io::Error::other)?
+    };
+
+    let temp = path.with_extension("tmp");
+    tokio::fs::write(&temp, json).await?;
+    tokio::fs::rename(&temp, path).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::{Ledger, Usage};
+
+    #[test]
+    fn test_ledger_roundtrip() {
+        let mut ledger = Ledger::default();
+        let ip: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        *ledger.today(ip) = Usage {
+            searches: 3,
+            bytes: 1024,
+        };
+
+        let json = serde_json::to_string(&ledger).unwrap();
+        assert!(json.contains("\"192.0.2.1\""));
+
+        let ledger: Ledger = serde_json::from_str(&json).unwrap();
+        let usage = ledger.clients[&"192.0.2.1".parse::<IpAddr>().unwrap()];
+        assert_eq!((usage.searches, usage.bytes), (3, 1024));
+    }
+}
@@ -1,37 +1,253 @@
 //! Keepalive logic for deferring resource teardown.
+//!
+//! Every teardown future waits in a single global `Wheel`, ordered by its
+//! deadline, and one task spawns each future once its deadline passes.
+//! Refreshing a resource only moves its entry in the wheel, so resources
+//! that are waiting to be torn down don't cost a task each.
 
-use futures::Future;
-use tokio::sync::{broadcast, mpsc};
-use tokio::time::{sleep, Duration};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt};
+use rand::Rng;
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::config::Config;
+use crate::metrics::Metrics;
 
 /// A `Refresher` refreshes an "owned" resource, or something that
 /// that has registered takedown logic through a `RefreshHandler`.
 #[derive(Clone)]
-pub enum Refresher {
-    One(mpsc::Sender<()>),
-    Many(broadcast::Sender<()>),
+pub struct Refresher {
+    target: Target,
 }
 
-impl Refresher {
-    /// Refreshes the associated resource.
-    pub fn refresh(&self) {
+/// The timers a `Refresher` resets.
+#[derive(Clone)]
+enum Target {
+    /// The timer of a single teardown future.
+    One(Arc<Timer>),
+    /// Every timer attached to a `RefreshHandler`.
+    Many(Arc<Group>),
+}
+
+/// The timers attached to a `RefreshHandler`.
+struct Group {
+    /// The timers attached with `attach`, which live as long as the group.
+    timers: Mutex<Vec<Arc<Timer>>>,
+    /// The timers attached with `attach_with_local`, which live as long as
+    /// their own `Refresher`.
+    members: Mutex<Vec<Weak<Timer>>>,
+    limits: Limits,
+}
+
+/// The limits on how long a resource can be kept alive by refreshing it.
+struct Limits {
+    /// The number of times the resource was refreshed.
+    refreshes: AtomicU32,
+    /// When the resource was created.
+    created: SystemTime,
+    /// The time past which the resource is torn down, however often it is
+    /// refreshed.
+    expiry: Option<Instant>,
+}
+
+/// The state of the refresh limits of a resource, as saved with it, so that
+/// restoring it, on this instance or another, doesn't reset them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SavedLimits {
+    /// The number of times the resource was refreshed.
+    pub refreshes: u32,
+    /// When the resource was created, in seconds since the unix epoch.
+    created: u64,
+}
+
+impl Limits {
+    /// The limits configured for the instance, starting now.
+    fn new() -> Self {
+        Self::since(SystemTime::now(), 0)
+    }
+
+    /// The limits configured for the instance, for a resource restored from
+    /// its saved limits, or starting now if it has none.
+    fn restored(saved: Option<SavedLimits>) -> Self {
+        match saved {
+            Some(saved) => {
+                let created = UNIX_EPOCH + Duration::from_secs(saved.created);
+                Self::since(created, saved.refreshes)
+            }
+            None => Self::new(),
+        }
+    }
+
+    /// The limits configured for the instance, for a resource created at the
+    /// given time, and refreshed the given number of times since.
+    fn since(created: SystemTime, refreshes: u32) -> Self {
+        let age = created.elapsed().unwrap_or_default();
+        let expiry = Config::global()
+            .links
+            .max_lifetime
+            .map(|secs| Instant::now() + Duration::from_secs(secs).saturating_sub(age));
+
+        Self {
+            refreshes: AtomicU32::new(refreshes),
+            created,
+            expiry,
+        }
+    }
+
+    /// Save the state of these limits.
+    fn save(&self) -> SavedLimits {
+        let created = self.created.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        SavedLimits {
+            refreshes: self.refreshes.load(Ordering::Relaxed),
+            created: created.as_secs(),
+        }
+    }
+
+    /// Take one refresh, if the limits allow for another.
+    fn take(&self) -> Result<(), RefreshError> {
+        if self.expiry.is_some_and(|expiry| expiry <= Instant::now()) {
+            return Err(RefreshError::LimitReached);
+        }
+
+        let max = Config::global().links.max_refreshes;
+        self.refreshes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| match max {
+                Some(max) if n >= max => None,
+                _ => Some(n.saturating_add(1)),
+            })
+            .map_err(|_| RefreshError::LimitReached)?;
+
+        Ok(())
+    }
+}
+
+/// The reason a `Refresher` couldn't refresh its resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshError {
+    /// The resource was torn down before the refresh.
+    Expired,
+    /// The resource was refreshed too often, or has lived too long.
+    LimitReached,
+}
+
+impl RefreshError {
+    /// A short, stable identifier for this kind of error.
+    pub const fn code(self) -> &'static str {
         match self {
-            Self::One(tx) => drop(tx.try_send(())),
-            Self::Many(tx) => drop(tx.send(())),
+            Self::Expired => "link_expired",
+            Self::LimitReached => "refresh_limit",
+        }
+    }
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error,{},", self.code())?;
+
+        match self {
+            Self::Expired => write!(
+                f,
+                "This link expired before it was refreshed. Search again."
+            ),
+            Self::LimitReached => write!(f, "This link can't be refreshed anymore. Search again."),
+        }
+    }
+}
+
+impl Refresher {
+    /// Refreshes the associated resource, unless it was already torn down or
+    /// has reached its refresh limits. Returns the time left before the
+    /// resource is torn down, after the refresh.
+    pub fn refresh(&self) -> Result<Duration, RefreshError> {
+        let until = match &self.target {
+            Target::One(timer) => {
+                timer
+                    .limits
+                    .take()
+                    .inspect_err(|_| Metrics::global().refreshed(false))?;
+                timer.reset()
+            }
+            Target::Many(group) => {
+                group
+                    .limits
+                    .take()
+                    .inspect_err(|_| Metrics::global().refreshed(false))?;
+                let timers = group.timers.lock().expect("poisoned");
+                let until = timers.iter().filter_map(|timer| timer.reset()).max();
+
+                for member in group.members.lock().expect("poisoned").iter() {
+                    if let Some(member) = member.upgrade() {
+                        member.reset();
+                    }
+                }
+
+                until
+            }
+        };
+
+        let until = until.ok_or(RefreshError::Expired);
+        Metrics::global().refreshed(until.is_ok());
+
+        Ok(until?.saturating_duration_since(Instant::now()))
+    }
+
+    /// The state of the refresh limits of the associated resource, to save
+    /// with it.
+    pub fn limits(&self) -> SavedLimits {
+        match &self.target {
+            Target::One(timer) => timer.limits.save(),
+            Target::Many(group) => group.limits.save(),
+        }
+    }
+
+    /// The time left before the associated resource is torn down.
+    pub fn remaining(&self) -> Duration {
+        match &self.target {
+            Target::One(timer) => timer.remaining(),
+            Target::Many(group) => group
+                .timers
+                .lock()
+                .expect("poisoned")
+                .iter()
+                .map(|timer| timer.remaining())
+                .max()
+                .unwrap_or_default(),
         }
     }
 }
 
 /// Manage teardown logic for some "resource" with ethereal ownership.
 pub struct RefreshHandler {
-    refresh: broadcast::Sender<()>,
+    group: Arc<Group>,
 }
 
 impl RefreshHandler {
-    /// Create a new `RefreshHandler`.
+    /// Create a new `RefreshHandler`, with the refresh limits configured for
+    /// the instance.
     pub fn new() -> Self {
+        Self::restored(None)
+    }
+
+    /// Create a `RefreshHandler` for a restored resource, with the refresh
+    /// limits configured for the instance, counted from its saved limits if
+    /// it has them.
+    pub fn restored(limits: Option<SavedLimits>) -> Self {
+        let group = Group {
+            timers: Mutex::default(),
+            members: Mutex::default(),
+            limits: Limits::restored(limits),
+        };
+
         Self {
-            refresh: broadcast::channel(1).0,
+            group: Arc::new(group),
         }
     }
 
@@ -39,23 +255,20 @@ impl RefreshHandler {
     ///
     /// The execution of the teardown future will begin after the given
     /// duration. Calling the `refresh` method on a `Refresher` associated
-    /// with this handler will reset the timer.
+    /// with this handler will reset the timer. If every such `Refresher` is
+    /// dropped first, the teardown is cancelled.
+    ///
+    /// The `Refresher` returned by `into_refresher` reports the time left
+    /// before this teardown future runs.
     pub fn attach<F>(&self, len: u64, f: F)
     where
         F: Future + Send + 'static,
     {
-        let mut many = self.refresh.subscribe();
+        let limits = &self.group.limits;
+        let limits = Limits::since(limits.created, limits.refreshes.load(Ordering::Relaxed));
 
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    () = sleep(Duration::from_secs(len)) => break,
-                    Ok(()) = many.recv() => (),
-                }
-            }
-
-            f.await;
-        });
+        let timer = Timer::schedule(len, limits, f);
+        self.group.timers.lock().expect("poisoned").push(timer);
     }
 
     /// Attach a teardown future to this handlers global refresh signal,
@@ -64,32 +277,245 @@ impl RefreshHandler {
     /// The execution of the teardown future will begin after the given
     /// duration. Calling the `refresh` method on a `Refresher` associated
     /// with this handler, or the one returned by this method, will reset
-    /// the timer.
-    pub fn attach_with_local<F>(&self, len: u64, f: F) -> Refresher
+    /// the timer. If the `Refresher` returned by this method is dropped
+    /// first, the teardown is cancelled.
+    ///
+    /// The returned `Refresher` has refresh limits of its own, counted from
+    /// the given saved limits, if the resource is restored. Its lifetime
+    /// limit also applies to refreshes through this handler.
+    pub fn attach_with_local<F>(&self, len: u64, limits: Option<SavedLimits>, f: F) -> Refresher
     where
         F: Future + Send + 'static,
     {
-        let mut many = self.refresh.subscribe();
-        let (refresh, mut one) = mpsc::channel(1);
-
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    () = sleep(Duration::from_secs(len)) => break,
-                    Ok(()) = many.recv() => (),
-                    Some(()) = one.recv() => (),
-                }
-            }
+        let timer = Timer::schedule(len, Limits::restored(limits), f);
 
-            f.await;
-        });
+        let mut members = self.group.members.lock().expect("poisoned");
+        members.retain(|member| member.strong_count() > 0);
+        members.push(Arc::downgrade(&timer));
 
-        Refresher::One(refresh)
+        Refresher {
+            target: Target::One(timer),
+        }
     }
 
     /// Convert this `RefreshHandler` into a `Refresher`, which
     /// can be used to refresh any takedown timers.
     pub fn into_refresher(self) -> Refresher {
-        Refresher::Many(self.refresh)
+        Refresher {
+            target: Target::Many(self.group),
+        }
+    }
+}
+
+/// The entry of a teardown future in the `Wheel`. Dropping the timer removes
+/// the entry, which cancels the teardown if it hasn't run yet.
+struct Timer {
+    /// The key of the entry in the `Wheel`.
+    id: u64,
+    /// The lifetime of the resource, in seconds.
+    len: u64,
+    /// The deadline of the entry, which stays in place once it has passed.
+    until: Mutex<Instant>,
+    limits: Limits,
+}
+
+impl Timer {
+    /// Schedule a teardown future to run once the given number of seconds
+    /// have passed, or the lifetime limit is reached.
+    fn schedule<F>(len: u64, limits: Limits, f: F) -> Arc<Self>
+    where
+        F: Future + Send + 'static,
+    {
+        static IDS: AtomicU64 = AtomicU64::new(0);
+
+        let until = deadline(len, &limits);
+        let timer = Self {
+            id: IDS.fetch_add(1, Ordering::Relaxed),
+            len,
+            until: Mutex::new(until),
+            limits,
+        };
+
+        Wheel::global().insert(timer.id, until, f.map(drop).boxed());
+        Arc::new(timer)
+    }
+
+    /// Push the deadline of this timer back, returning the new deadline, or
+    /// `None` if the teardown already ran.
+    fn reset(&self) -> Option<Instant> {
+        let until = deadline(self.len, &self.limits);
+        Wheel::global().reschedule(self.id, until)?;
+
+        *self.until.lock().expect("poisoned") = until;
+        Some(until)
+    }
+
+    /// The time left before the teardown runs.
+    fn remaining(&self) -> Duration {
+        let until = *self.until.lock().expect("poisoned");
+        until.saturating_duration_since(Instant::now())
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // the future is dropped here, once the wheel is released
+        drop(Wheel::global().remove(self.id));
+    }
+}
+
+/// The given number of seconds from now, plus some jitter, or the lifetime
+/// limit, whichever is sooner.
+///
+/// The jitter spreads out the teardowns of links that were created, or
+/// refreshed, together.
+fn deadline(len: u64, limits: &Limits) -> Instant {
+    let jitter = Config::global().links.teardown_jitter * 1000;
+    let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..=jitter));
+
+    let until = Instant::now() + Duration::from_secs(len) + jitter;
+    limits.expiry.map_or(until, |expiry| until.min(expiry))
+}
+
+/// The teardown futures waiting for their deadlines, and the task that runs
+/// them.
+struct Wheel {
+    inner: Mutex<WheelInner>,
+    /// Wakes the task when a deadline comes before the one it waits for.
+    wake: Notify,
+}
+
+#[derive(Default)]
+struct WheelInner {
+    /// The deadline and key of every entry, in order.
+    queue: BTreeSet<(Instant, u64)>,
+    entries: HashMap<u64, Entry>,
+}
+
+struct Entry {
+    until: Instant,
+    teardown: BoxFuture<'static, ()>,
+}
+
+impl Wheel {
+    /// Get the global `Wheel`, spawning its task on first use.
+    fn global() -> &'static Self {
+        static WHEEL: OnceLock<Wheel> = OnceLock::new();
+
+        WHEEL.get_or_init(|| {
+            tokio::spawn(Self::run());
+
+            Self {
+                inner: Mutex::default(),
+                wake: Notify::new(),
+            }
+        })
+    }
+
+    /// Add a teardown future with the given key and deadline.
+    fn insert(&self, id: u64, until: Instant, teardown: BoxFuture<'static, ()>) {
+        let mut inner = self.inner.lock().expect("poisoned");
+        inner.queue.insert((until, id));
+        inner.entries.insert(id, Entry { until, teardown });
+        Metrics::global().timer_started();
+
+        self.wake_if_first(&inner, until, id);
+    }
+
+    /// Move the deadline of an entry, if it is still waiting.
+    fn reschedule(&self, id: u64, until: Instant) -> Option<()> {
+        let mut inner = self.inner.lock().expect("poisoned");
+        let entry = inner.entries.get_mut(&id)?;
+        let old = std::mem::replace(&mut entry.until, until);
+
+        inner.queue.remove(&(old, id));
+        inner.queue.insert((until, id));
+
+        self.wake_if_first(&inner, until, id);
+        Some(())
+    }
+
+    /// Remove an entry, returning its future if it is still waiting.
+    fn remove(&self, id: u64) -> Option<BoxFuture<'static, ()>> {
+        let mut inner = self.inner.lock().expect("poisoned");
+        let entry = inner.entries.remove(&id)?;
+        inner.queue.remove(&(entry.until, id));
+        Metrics::global().timer_stopped(false);
+
+        Some(entry.teardown)
+    }
+
+    /// Wake the task if the given entry is now the first to run, since the
+    /// task may be waiting for a later deadline.
+    fn wake_if_first(&self, inner: &WheelInner, until: Instant, id: u64) {
+        if inner.queue.first() == Some(&(until, id)) {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Take the futures whose deadlines have passed, and the next deadline.
+    fn take_due(&self) -> (Vec<BoxFuture<'static, ()>>, Option<Instant>) {
+        let mut inner = self.inner.lock().expect("poisoned");
+        let now = Instant::now();
+        let mut due = Vec::new();
+
+        while let Some(&(until, id)) = inner.queue.first() {
+            if until > now {
+                return (due, Some(until));
+            }
+
+            inner.queue.pop_first();
+            if let Some(entry) = inner.entries.remove(&id) {
+                Metrics::global().timer_stopped(true);
+                due.push(entry.teardown);
+            }
+        }
+
+        (due, None)
+    }
+
+    /// Run teardown futures as their deadlines pass.
+    async fn run() {
+        let wheel = Self::global();
+
+        loop {
+            let (due, next) = wheel.take_due();
+            for teardown in due {
+                tokio::spawn(teardown);
+            }
+
+            match next {
+                Some(until) => tokio::select! {
+                    () = sleep_until(until) => (),
+                    () = wheel.wake.notified() => (),
+                },
+                None => wheel.wake.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{Limits, SavedLimits};
+
+    #[test]
+    fn test_restored_limits() {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let saved = SavedLimits {
+            refreshes: 3,
+            created: created.as_secs() - 100,
+        };
+
+        let limits = Limits::restored(Some(saved));
+        assert_eq!(limits.save(), saved);
+
+        limits.take().unwrap();
+        assert_eq!(limits.save().refreshes, 4);
+        assert_eq!(limits.save().created, saved.created);
+
+        assert_eq!(Limits::restored(None).save().refreshes, 0);
     }
 }
@@ -0,0 +1,67 @@
+//! Request IDs, to correlate bug reports with logs.
+//!
+//! Every request is given a random ID, which is sent back to the client in
+//! the `X-Request-Id` header. The request is answered in a `request` span
+//! with the ID as its `request_id` field, which every log line written while
+//! answering it carries. Work spawned on behalf of a request, like a search
+//! building its previews, stays in its span, and so keeps its ID.
+
+use std::fmt;
+
+use axum::extract::Request;
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::Instrument;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+use crate::trace;
+
+/// The header the ID of a request is sent back in.
+static HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The ID of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    /// The ID of the request whose span the current task is in, if any.
+    pub fn current() -> Option<Self> {
+        tracing::Span::current()
+            .with_subscriber(|(id, dispatch)| {
+                let registry = dispatch.downcast_ref::<Registry>()?;
+                let span = registry.span(id)?;
+
+                span.scope()
+                    .find_map(|span| span.extensions().get::<Self>().copied())
+            })
+            .flatten()
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Middleware that gives a request an ID, and answers it in a span with the
+/// ID.
+pub async fn assign(req: Request, next: Next) -> Response {
+    let id = RequestId(rand::random());
+
+    let span = trace::request_span(&req, id);
+    span.with_subscriber(|(span, dispatch)| {
+        let span = dispatch.downcast_ref::<Registry>()?.span(span)?;
+        span.extensions_mut().insert(id);
+        Some(())
+    });
+
+    let mut res = next.run(req).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id.to_string()) {
+        res.headers_mut().insert(HEADER.clone(), value);
+    }
+
+    res
+}
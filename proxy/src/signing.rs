@@ -0,0 +1,104 @@
+//! Signed link ids.
+//!
+//! Link ids are random, so they can't be enumerated, but nothing proves that
+//! an id was handed out by this instance. If the instance is configured with
+//! a signing key, every link id in a `SearchMap` carries an HMAC of the id,
+//! as `id.mac`, and `/link/` refuses ids whose HMAC doesn't match before
+//! looking them up. Ids signed by instances with another key are refused as
+//! well, so instances that share links through Redis must share the key.
+
+use std::sync::OnceLock;
+
+use ring::hmac;
+
+use crate::config::Config;
+use crate::links::LinkId;
+
+/// The number of bytes of the HMAC kept in a signed id, which is plenty
+/// against guessing, without making `SearchMap`s much longer.
+const MAC_LEN: usize = 12;
+
+/// The configured signing key, if any.
+///
+/// The key is read once, since changing it would invalidate every link that
+/// was handed out.
+fn key() -> Option<&'static hmac::Key> {
+    static KEY: OnceLock<Option<hmac::Key>> = OnceLock::new();
+
+    KEY.get_or_init(|| {
+        let secret = Config::global().links.signing_key.as_ref()?;
+        Some(hmac::Key::new(
+            hmac::HMAC_SHA256,
+            secret.expose().as_bytes(),
+        ))
+    })
+    .as_ref()
+}
+
+/// Whether link ids are signed.
+pub fn enabled() -> bool {
+    key().is_some()
+}
+
+/// The HMAC of a link id, as hex.
+fn mac(key: &hmac::Key, id: LinkId) -> String {
+    let tag = hmac::sign(key, &id.to_be_bytes());
+    tag.as_ref()[..MAC_LEN]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The id of a link as it is handed to clients, signed if signing is
+/// enabled.
+pub fn sign(id: LinkId) -> String {
+    match key() {
+        Some(key) => format!("{id}.{}", mac(key, id)),
+        None => id.to_string(),
+    }
+}
+
+/// The link id of an id sent by a client, if it is valid, and correctly
+/// signed if signing is enabled.
+pub fn verify(token: &str) -> Option<LinkId> {
+    let Some(key) = key() else {
+        return token.parse().ok();
+    };
+
+    let (id, sent) = token.split_once('.')?;
+    let id = id.parse().ok()?;
+
+    constant_time_eq(mac(key, id).as_bytes(), sent.as_bytes()).then_some(id)
+}
+
+/// Compare secrets without returning early, so that the time taken doesn't
+/// tell how much of them was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use ring::hmac;
+
+    use super::{constant_time_eq, mac, MAC_LEN};
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter3"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter"));
+        assert!(!constant_time_eq(b"", b"hunter2"));
+    }
+
+    #[test]
+    fn test_mac() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"instance key");
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other key");
+
+        assert_eq!(mac(&key, 42).len(), MAC_LEN * 2);
+        assert_eq!(mac(&key, 42), mac(&key, 42));
+        assert_ne!(mac(&key, 42), mac(&key, 43));
+        assert_ne!(mac(&key, 42), mac(&other, 42));
+    }
+}
@@ -0,0 +1,39 @@
+//! Warnings about slow work.
+//!
+//! Searches, preview generation and image downloads that take longer than
+//! their configured threshold are logged as warnings, with what they were
+//! working on, so that slow regressions and e621 slowness show up in the logs
+//! without tracing.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Run a future, warning if it takes longer than a threshold in
+/// milliseconds. `context` describes what the future was working on.
+pub async fn warn_if_slow<F: Future>(
+    what: &str,
+    threshold: Option<u64>,
+    context: impl Display,
+    fut: F,
+) -> F::Output {
+    let Some(threshold) = threshold.map(Duration::from_millis) else {
+        return fut.await;
+    };
+
+    let start = Instant::now();
+    let output = fut.await;
+
+    let elapsed = start.elapsed();
+    if elapsed > threshold {
+        tracing::warn!(
+            elapsed_ms = elapsed.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            "slow {what}: took {}ms, over {}ms: {context}",
+            elapsed.as_millis(),
+            threshold.as_millis()
+        );
+    }
+
+    output
+}
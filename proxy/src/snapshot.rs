@@ -0,0 +1,99 @@
+//! Saving links across restarts.
+//!
+//! If the instance is configured with a snapshot path, the `LinkMap` is written
+//! there periodically and when the proxy shuts down, and restored from it on
+//! startup. Only what's needed to recreate each link is saved, along with the
+//! time it had left, minus however long the proxy was down.
+
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::links::{LinkMap, Snapshot};
+
+/// A `Snapshot`, as it is written to disk.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SnapshotFile {
+    /// When the snapshot was taken, in seconds since the unix epoch.
+    saved_at: u64,
+    links: Snapshot,
+}
+
+/// The current time, in seconds since the unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Restore the links saved at the configured snapshot path, if any.
+pub async fn restore() {
+    let Some(path) = &Config::global().links.snapshot else {
+        return;
+    };
+
+    let Ok(file) = tokio::fs::read(path).await else {
+        tracing::info!("no snapshot at {}", path.display());
+        return;
+    };
+
+    match serde_json::from_slice::<SnapshotFile>(&file) {
+        Ok(file) => LinkMap::global().restore(file.links, now().saturating_sub(file.saved_at)),
+        Err(e) => tracing::error!("invalid snapshot at {}: {e}", path.display()),
+    }
+}
+
+/// Spawn a task that saves the links to the configured snapshot path
+/// periodically, if there is one.
+pub fn spawn_saver() {
+    let links = &Config::global().links;
+    let Some(path) = &links.snapshot else {
+        return;
+    };
+
+    let period = Duration::from_secs(links.snapshot_interval.max(1));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // the first tick completes immediately, and there's nothing new to save
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = save(path).await {
+                tracing::error!("failed to save snapshot to {}: {e}", path.display());
+            }
+        }
+    });
+}
+
+/// Save the links to the configured snapshot path, if any, like when the
+/// proxy shuts down.
+pub async fn save_configured() {
+    let Some(path) = &Config::global().links.snapshot else {
+        return;
+    };
+
+    match save(path).await {
+        Ok(()) => tracing::info!("saved snapshot to {}", path.display()),
+        Err(e) => tracing::error!("failed to save snapshot to {}: {e}", path.display()),
+    }
+}
+
+/// Save the links to the given path.
+///
+/// The snapshot is written next to the path first, and then moved over it,
+/// so that a crash while saving doesn't lose the previous snapshot.
+async fn save(path: &Path) -> io::Result<()> {
+    let file = SnapshotFile {
+        saved_at: now(),
+        links: LinkMap::global().snapshot(),
+    };
+    let json = serde_json::to_vec(&file).map_err(io::Error::other)?;
+
+    let temp = path.with_extension("tmp");
+    tokio::fs::write(&temp, json).await?;
+    tokio::fs::rename(&temp, path).await
+}
@@ -0,0 +1,74 @@
+//! The status document of the instance.
+//!
+//! `/status` answers a plain `OK`, like the original proxy, for monitoring
+//! that only checks whether the proxy is up. `/status.json` answers with what
+//! the instance is doing and how it is configured, for operators. Secrets,
+//! like the Redis URL, are only reported as whether they are set.
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::links::LinkMap;
+use crate::metrics::Metrics;
+use crate::{api, budget, maintenance, promise};
+
+/// When the proxy started.
+fn started() -> Instant {
+    static STARTED: OnceLock<Instant> = OnceLock::new();
+    *STARTED.get_or_init(Instant::now)
+}
+
+/// Start the uptime clock.
+pub fn init() {
+    started();
+}
+
+/// The status document of the instance.
+pub fn document() -> Value {
+    let config = Config::global();
+    let (upstream_requests, upstream_errors) = Metrics::global().recent_upstream();
+    let error_rate = if upstream_requests == 0 {
+        0.0
+    } else {
+        upstream_errors as f64 / upstream_requests as f64
+    };
+
+    json!({
+        "status": "ok",
+        "maintenance": maintenance::enabled(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": started().elapsed().as_secs(),
+        "links": LinkMap::global().count_by_kind(),
+        "pending_promises": promise::pending(),
+        "image_bytes": budget::used_bytes(),
+        "image_cache_bytes": api::cached_bytes(),
+        "upstream": {
+            "recent_requests": upstream_requests,
+            "recent_errors": upstream_errors,
+            "recent_error_rate": error_rate,
+        },
+        "config": {
+            "strict_status": config.server.strict_status,
+            "max_searches": config.server.max_searches,
+            "search_ttl": config.links.search_ttl,
+            "post_ttl": config.links.post_ttl,
+            "max_entries": config.links.max_entries,
+            "memory_budget": config.image.memory_budget,
+            "image_cache_size": config.image.cache_size,
+            "response_cache_ttl": config.api.cache_ttl,
+            "shared_links": config.links.redis.is_some(),
+            "snapshots": config.links.snapshot.is_some(),
+            "access_list": config.server.access_list.is_some(),
+            "trusted_proxies": config.server.trusted_proxies.len(),
+            "tracing": config.server.otlp_endpoint.is_some(),
+            "admin_api": config.server.admin.token.is_some(),
+            "daily_searches": config.server.quota.daily_searches,
+            "daily_bytes": config.server.quota.daily_bytes,
+            "client_tokens": config.server.clients.tokens.len(),
+            "tokens_required": config.server.clients.required,
+        },
+    })
+}
@@ -0,0 +1,194 @@
+//! Link storage shared between instances.
+//!
+//! To run several instances of the proxy behind a load balancer, any instance
+//! must be able to serve any link. If the instance is configured with a Redis
+//! URL, every group of links is published to Redis when it is created, with
+//! the time it has left as its expiry. An instance asked for a link it doesn't
+//! hold fetches the group from Redis, and restores it into its own `LinkMap`.
+//! Images are never shared, and are downloaded by each instance that serves
+//! them.
+//!
+//! Refreshing a link on any instance pushes back its expiry in Redis, and
+//! counts the refresh against the group's refresh limits. The local copies of
+//! a group on other instances expire on their own, and are fetched again from
+//! Redis if the group is still live.
+
+use std::sync::OnceLock;
+
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, RedisResult};
+
+use crate::config::Config;
+use crate::links::{GroupKeys, LinkId, SavedGroup};
+
+/// The connection to Redis, if the instance is configured with one.
+static CONNECTION: OnceLock<ConnectionManager> = OnceLock::new();
+
+/// Connect to the configured Redis instance, if any.
+pub async fn connect() -> RedisResult<()> {
+    let Some(url) = &Config::global().links.redis else {
+        return Ok(());
+    };
+
+    let client = redis::Client::open(url.expose())?;
+    let connection = ConnectionManager::new(client).await?;
+    tracing::info!("sharing links through redis");
+
+    // `connect` is only called once, at startup
+    let _ = CONNECTION.set(connection);
+    Ok(())
+}
+
+/// Whether links are shared between instances.
+pub fn enabled() -> bool {
+    CONNECTION.get().is_some()
+}
+
+/// Get a handle to the Redis connection, if there is one.
+fn connection() -> Option<ConnectionManager> {
+    CONNECTION.get().cloned()
+}
+
+/// The key of a link, which holds the identifier of its group.
+fn link_key(id: LinkId) -> String {
+    format!("roli:link:{id}")
+}
+
+/// The key of a group of links, which holds the saved group.
+fn group_key(group: LinkId) -> String {
+    format!("roli:group:{group}")
+}
+
+/// The key of the number of times a group of links was refreshed since it was
+/// published.
+fn refreshes_key(group: LinkId) -> String {
+    format!("roli:refreshes:{group}")
+}
+
+/// Publish groups of links, so that other instances can serve them.
+pub async fn publish(groups: &[SavedGroup]) {
+    let Some(mut conn) = connection() else {
+        return;
+    };
+
+    if let Err(e) = try_publish(&mut conn, groups).await {
+        tracing::error!("failed to publish links: {e}");
+    }
+}
+
+async fn try_publish(conn: &mut ConnectionManager, groups: &[SavedGroup]) -> RedisResult<()> {
+    let mut pipe = redis::pipe();
+
+    for group in groups {
+        let keys = group.keys();
+        let ttl = group.ttl().max(1);
+        let json = serde_json::to_string(group).expect("serializable");
+
+        pipe.set_ex(group_key(keys.group), json, ttl).ignore();
+        for id in keys.links {
+            pipe.set_ex(link_key(id), keys.group, ttl).ignore();
+        }
+    }
+
+    pipe.query_async(conn).await
+}
+
+/// Fetch the group of a link published by any instance, with the time it
+/// has left.
+pub async fn fetch(id: LinkId) -> Option<SavedGroup> {
+    let mut conn = connection()?;
+
+    match try_fetch(&mut conn, id).await {
+        Ok(group) => group,
+        Err(e) => {
+            tracing::error!("failed to fetch link {id}: {e}");
+            None
+        }
+    }
+}
+
+async fn try_fetch(conn: &mut ConnectionManager, id: LinkId) -> RedisResult<Option<SavedGroup>> {
+    let Some(group): Option<LinkId> = conn.get(link_key(id)).await? else {
+        return Ok(None);
+    };
+
+    let (json, ttl, refreshes): (Option<String>, i64, Option<u32>) = redis::pipe()
+        .get(group_key(group))
+        .ttl(group_key(group))
+        .get(refreshes_key(group))
+        .query_async(conn)
+        .await?;
+
+    // the group expired between the two requests
+    let (Some(json), Ok(ttl @ 1..)) = (json, u64::try_from(ttl)) else {
+        return Ok(None);
+    };
+
+    let Ok(mut group) = serde_json::from_str::<SavedGroup>(&json) else {
+        tracing::error!("invalid group in redis: {group}");
+        return Ok(None);
+    };
+    group.set_ttl(ttl);
+    group.add_refreshes(refreshes.unwrap_or(0));
+
+    tracing::info!("fetched link {id} from redis");
+    Ok(Some(group))
+}
+
+/// Push back the expiry of the group of a link, and the groups refreshed
+/// along with it.
+pub async fn refresh(id: LinkId) {
+    let Some(mut conn) = connection() else {
+        return;
+    };
+
+    if let Err(e) = try_refresh(&mut conn, id).await {
+        tracing::error!("failed to refresh link {id}: {e}");
+    }
+}
+
+async fn try_refresh(conn: &mut ConnectionManager, id: LinkId) -> RedisResult<()> {
+    let Some(group): Option<LinkId> = conn.get(link_key(id)).await? else {
+        return Ok(());
+    };
+    let Some(json): Option<String> = conn.get(group_key(group)).await? else {
+        return Ok(());
+    };
+    let Ok(group) = serde_json::from_str::<SavedGroup>(&json) else {
+        return Ok(());
+    };
+
+    let mut pipe = redis::pipe();
+    let refreshed = group.keys().group;
+    for GroupKeys { group, links, len } in group.refreshed() {
+        let len = i64::try_from(len).unwrap_or(i64::MAX);
+
+        // groups refreshed along with this one don't take a refresh of their own
+        if group == refreshed {
+            pipe.incr(refreshes_key(group), 1).ignore();
+            pipe.expire(refreshes_key(group), len).ignore();
+        }
+        pipe.expire(group_key(group), len).ignore();
+        for id in links {
+            pipe.expire(link_key(id), len).ignore();
+        }
+    }
+
+    pipe.query_async(conn).await
+}
+
+/// Stop serving links from Redis, so that links purged by one instance can't
+/// be fetched again by any instance. Their groups expire on their own.
+pub async fn forget(ids: &[LinkId]) {
+    let Some(mut conn) = connection() else {
+        return;
+    };
+    if ids.is_empty() {
+        return;
+    }
+
+    let keys = ids.iter().map(|&id| link_key(id)).collect::<Vec<_>>();
+    if let Err(e) = conn.del::<_, ()>(keys).await {
+        tracing::error!("failed to forget {} links: {e}", ids.len());
+    }
+}
@@ -0,0 +1,281 @@
+//! The TLS policy of the listener.
+//!
+//! By default, the listener accepts TLS 1.2 and 1.3 with rustls' default
+//! cipher suites, from any client. Operators of private instances can raise
+//! the minimum version, narrow the cipher suites, and require clients, like
+//! their own relay, to present a certificate signed by a given CA.
+//!
+//! The HTTP/3 listener follows the same policy, but QUIC only runs over TLS
+//! 1.3, so it is built with the newer rustls that QUIC needs, and only takes
+//! the TLS 1.3 cipher suites.
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum_server::tls_rustls::RustlsConfig;
+use quic_rustls::crypto::ring;
+use quic_rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use quic_rustls::server::WebPkiClientVerifier;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, SupportedCipherSuite};
+
+use crate::config::{Config, TlsVersion};
+
+/// When the certificate of the listener expires, once it is loaded.
+static EXPIRY: OnceLock<Option<SystemTime>> = OnceLock::new();
+
+/// When the certificate of the listener expires, if it is loaded and its
+/// expiry could be read.
+pub fn expiry() -> Option<SystemTime> {
+    EXPIRY.get().copied().flatten()
+}
+
+/// Build the rustls configuration of the listener, with the given certificate
+/// chain and private key, following the configured TLS policy.
+pub async fn config(cert: &Path, key: &Path) -> io::Result<RustlsConfig> {
+    let policy = &Config::global().server.tls;
+
+    let versions: &[_] = match policy.min_version {
+        TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+    let builder = ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites(&policy.cipher_suites)?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .map_err(io::Error::other)?;
+
+    let builder = match &policy.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path).await? {
+                roots.add(&cert).map_err(io::Error::other)?;
+            }
+
+            tracing::info!("requiring client certificates signed by {}", path.display());
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let certs = read_certs(cert).await?;
+    let _ = EXPIRY.set(certs.first().and_then(|cert| not_after(&cert.0)));
+
+    let mut config = builder
+        .with_single_cert(certs, read_key(key).await?)
+        .map_err(io::Error::other)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Build the rustls configuration of the HTTP/3 listener, with the given
+/// certificate chain and private key, following the configured TLS policy as
+/// far as TLS 1.3 goes.
+pub async fn quic_config(cert: &Path, key: &Path) -> io::Result<quic_rustls::ServerConfig> {
+    let policy = &Config::global().server.tls;
+
+    let mut provider = ring::default_provider();
+    if !policy.cipher_suites.is_empty() {
+        provider.cipher_suites.retain(|suite| {
+            let name = format!("{:?}", suite.suite());
+            policy.cipher_suites.contains(&name)
+        });
+    }
+    let provider = Arc::new(provider);
+
+    let builder = quic_rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&quic_rustls::version::TLS13])
+        .map_err(io::Error::other)?;
+
+    let builder = match &policy.client_ca {
+        Some(path) => {
+            let mut roots = quic_rustls::RootCertStore::empty();
+            for cert in read_pem_certs(path).await? {
+                roots.add(cert).map_err(io::Error::other)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(read_pem_certs(cert).await?, read_pem_key(key).await?)
+        .map_err(io::Error::other)?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+
+    Ok(config)
+}
+
+/// Look up cipher suites by their names, like `TLS13_AES_256_GCM_SHA384`.
+/// rustls' default suites are used if no names are given.
+fn cipher_suites(names: &[String]) -> io::Result<Vec<SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(rustls::DEFAULT_CIPHER_SUITES.to_vec());
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            rustls::ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()) == *name)
+                .copied()
+                .ok_or_else(|| io::Error::other(format!("unknown cipher suite: {name}")))
+        })
+        .collect()
+}
+
+/// Read the certificates of a PEM file.
+async fn read_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = read_pem_certs(path).await?;
+
+    Ok(certs
+        .into_iter()
+        .map(|cert| Certificate(cert.to_vec()))
+        .collect())
+}
+
+/// Read the private key of a PEM file.
+async fn read_key(path: &Path) -> io::Result<PrivateKey> {
+    let key = read_pem_key(path).await?;
+
+    Ok(PrivateKey(key.secret_der().to_vec()))
+}
+
+/// Read the certificates of a PEM file, as DER.
+async fn read_pem_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let file = tokio::fs::read(path).await?;
+
+    rustls_pemfile::certs(&mut file.as_slice()).collect()
+}
+
+/// Read the private key of a PEM file, as DER.
+async fn read_pem_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let file = tokio::fs::read(path).await?;
+
+    rustls_pemfile::private_key(&mut file.as_slice())?
+        .ok_or_else(|| io::Error::other(format!("no private key in {}", path.display())))
+}
+
+/// Read when a DER certificate expires, from the end of its validity.
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(cert)?;
+
+    // skip the version, if there is one, the serial number, the signature
+    // algorithm and the issuer
+    let (tag, _, rest) = der_element(tbs)?;
+    if tag == 0xa0 {
+        tbs = rest;
+    }
+    for _ in 0..3 {
+        tbs = der_element(tbs)?.2;
+    }
+
+    let (_, validity, _) = der_element(tbs)?;
+    let (_, _, validity) = der_element(validity)?;
+    let (tag, time, _) = der_element(validity)?;
+
+    asn1_time(tag, std::str::from_utf8(time).ok()?)
+}
+
+/// Split the first DER element off of some bytes, into its tag, its contents
+/// and the bytes after it.
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+
+    let (len, rest) = if len < 0x80 {
+        (usize::from(len), rest)
+    } else {
+        let n = usize::from(len & 0x7f);
+        if n > 4 || rest.len() < n {
+            return None;
+        }
+        let (len, rest) = rest.split_at(n);
+        (
+            len.iter().fold(0, |len, &b| len << 8 | usize::from(b)),
+            rest,
+        )
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+
+    Some((tag, contents, rest))
+}
+
+/// Parse an ASN.1 `UTCTime` (tag `0x17`) or `GeneralizedTime` (tag `0x18`),
+/// as they appear in certificates: in UTC, to the second.
+fn asn1_time(tag: u8, time: &str) -> Option<SystemTime> {
+    let time = time.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let year: i64 = time.get(..2)?.parse().ok()?;
+            let century = if year < 50 { 2000 } else { 1900 };
+            (century + year, time.get(2..)?)
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, time.get(4..)?),
+        _ => return None,
+    };
+    if rest.len() != 10 {
+        return None;
+    }
+
+    let field = |i: usize| rest.get(i..i + 2)?.parse::<i64>().ok();
+    let (month, day) = (field(0)?, field(2)?);
+    let (hour, minute, second) = (field(4)?, field(6)?, field(8)?);
+
+    // days since the epoch, from Howard Hinnant's `days_from_civil`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let days = era * 146_097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719_468;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_cipher_suites() {
+        use super::cipher_suites;
+
+        let names = ["TLS13_AES_256_GCM_SHA384".to_owned()];
+        let suites = cipher_suites(&names).unwrap();
+        assert_eq!(suites.len(), 1);
+        assert_eq!(
+            suites[0].suite(),
+            rustls::CipherSuite::TLS13_AES_256_GCM_SHA384
+        );
+
+        assert!(!cipher_suites(&[]).unwrap().is_empty());
+        assert!(cipher_suites(&["TLS_NULL_WITH_NULL_NULL".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_asn1_time() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use super::asn1_time;
+
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(asn1_time(0x17, "700101000000Z"), at(0));
+        assert_eq!(asn1_time(0x17, "240229123456Z"), at(1_709_210_096));
+        assert_eq!(asn1_time(0x18, "20491231235959Z"), at(2_524_607_999));
+
+        assert_eq!(asn1_time(0x17, "2402291234Z"), None);
+        assert_eq!(asn1_time(0x04, "240229123456Z"), None);
+    }
+}
@@ -0,0 +1,271 @@
+//! Access tokens of the worlds and relays using the instance.
+//!
+//! Every world using a public instance, and every relay in front of it,
+//! looks alike to the proxy. Operators may register a token for each of
+//! them, which they send as a `/t/token/` prefix of the path, since worlds
+//! can't set headers, or in the `X-Proxy-Token` header. The prefix is
+//! stripped before the request is routed or logged, so tokens don't end up
+//! in logs.
+//!
+//! Searches are counted per token, may be limited per minute per token, and
+//! may be refused without a registered token. Usage is kept in memory and
+//! listed through the admin API.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::Request;
+use axum::http::Uri;
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::config::Config;
+use crate::{epoch, signing};
+
+/// The header a token may be sent in, instead of the path.
+const HEADER: &str = "x-proxy-token";
+
+tokio::task_local! {
+    static CURRENT: Identity;
+}
+
+/// Who made a request, going by its token.
+#[derive(Debug, Clone)]
+enum Identity {
+    /// No token was sent.
+    Anonymous,
+    /// A registered token was sent, by the client of this name.
+    Client(String),
+    /// A token was sent, but it isn't registered.
+    Unknown,
+}
+
+/// A search refused because of its token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// The instance requires a token, and none was sent.
+    Missing,
+    /// The token isn't registered.
+    Unknown,
+    /// The client made too many searches this minute.
+    Throttled,
+}
+
+impl Refused {
+    /// A short, stable identifier for this refusal.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::Missing => "token_required",
+            Self::Unknown => "unknown_token",
+            Self::Throttled => "throttled",
+        }
+    }
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Missing => "error,token_required,This instance requires a client token. Ask its operator for one.",
+            Self::Unknown => "error,unknown_token,This client token isn't registered on this instance.",
+            Self::Throttled => "error,throttled,This client made too many searches. Try again in a minute.",
+        })
+    }
+}
+
+/// The searches made by a client.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// The number of searches made since the proxy started.
+    pub searches: u64,
+    /// The number of searches refused for going over the limit.
+    pub throttled: u64,
+    /// When the last search was made.
+    pub last_search: Option<SystemTime>,
+    /// The minute `recent` counts searches of, in minutes since the unix
+    /// epoch.
+    minute: u64,
+    recent: u32,
+}
+
+/// The searches made by every client.
+#[derive(Default)]
+struct Usage {
+    clients: HashMap<String, Stats>,
+    /// The number of searches made without a token.
+    anonymous: u64,
+}
+
+impl Usage {
+    /// Get a lock to the global `Usage`.
+    fn get_lock() -> &'static Mutex<Self> {
+        static USAGE: OnceLock<Mutex<Usage>> = OnceLock::new();
+        USAGE.get_or_init(Default::default)
+    }
+
+    /// Count a search by a client in the given minute, unless it went over
+    /// its limit.
+    fn take(&mut self, name: String, limit: Option<u32>, minute: u64) -> Result<(), Refused> {
+        let stats = self.clients.entry(name).or_default();
+        if stats.minute != minute {
+            stats.minute = minute;
+            stats.recent = 0;
+        }
+
+        if limit.is_some_and(|limit| stats.recent >= limit) {
+            stats.throttled += 1;
+            return Err(Refused::Throttled);
+        }
+
+        stats.recent += 1;
+        stats.searches += 1;
+        stats.last_search = Some(SystemTime::now());
+        Ok(())
+    }
+}
+
+/// The time left until the current minute ends, and throttled clients may
+/// search again.
+pub fn retry_in() -> Duration {
+    epoch::until_next(epoch::MINUTE)
+}
+
+/// Middleware that strips the token prefix from the path of a request, and
+/// answers it with the client its token belongs to as the current one.
+///
+/// This must wrap the router, since the path is rewritten.
+pub async fn identify(mut req: Request, next: Next) -> Response {
+    let sent = match strip_token(req.uri()) {
+        Some((token, uri)) => {
+            *req.uri_mut() = uri;
+            Some(token)
+        }
+        None => req
+            .headers()
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned),
+    };
+
+    let identity = match sent {
+        Some(sent) => client_named(&sent).map_or(Identity::Unknown, Identity::Client),
+        None => Identity::Anonymous,
+    };
+
+    CURRENT.scope(identity, next.run(req)).await
+}
+
+/// Split the token prefix off a URI, if it has one.
+fn strip_token(uri: &Uri) -> Option<(String, Uri)> {
+    let rest = uri.path().strip_prefix("/t/")?;
+    let (token, path) = rest.split_once('/')?;
+
+    let path = match uri.query() {
+        Some(query) => format!("/{path}?{query}"),
+        None => format!("/{path}"),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path.parse().ok()?);
+
+    Some((token.to_owned(), Uri::from_parts(parts).ok()?))
+}
+
+/// The name of the client a token is registered to, if any.
+fn client_named(sent: &str) -> Option<String> {
+    let tokens = &Config::global().server.clients.tokens;
+
+    tokens
+        .iter()
+        .find(|(_, client)| {
+            signing::constant_time_eq(client.token.expose().as_bytes(), sent.as_bytes())
+        })
+        .map(|(name, _)| name.clone())
+}
+
+/// Count a search by the client of the current request, unless its token is
+/// missing or unknown, or it made too many searches this minute.
+pub fn take_search() -> Result<(), Refused> {
+    let config = &Config::global().server.clients;
+    let identity = CURRENT
+        .try_with(Clone::clone)
+        .unwrap_or(Identity::Anonymous);
+
+    let mut usage = Usage::get_lock().lock().expect("poisoned");
+    match identity {
+        Identity::Anonymous if config.required => Err(Refused::Missing),
+        Identity::Anonymous => {
+            usage.anonymous += 1;
+            Ok(())
+        }
+        Identity::Unknown => Err(Refused::Unknown),
+        Identity::Client(name) => {
+            let limit = config
+                .tokens
+                .get(&name)
+                .and_then(|client| client.searches_per_minute);
+
+            let result = usage.take(name.clone(), limit, epoch::minute());
+            if result.is_err() {
+                tracing::warn!(client = name.as_str(), "throttling client {name}");
+            }
+            result
+        }
+    }
+}
+
+/// The searches made by every registered client, by name, and the number
+/// made without a token.
+pub fn usage() -> (Vec<(String, Stats)>, u64) {
+    let usage = Usage::get_lock().lock().expect("poisoned");
+
+    let mut clients = Config::global()
+        .server
+        .clients
+        .tokens
+        .keys()
+        .map(|name| {
+            let stats = usage.clients.get(name).copied().unwrap_or_default();
+            (name.clone(), stats)
+        })
+        .collect::<Vec<_>>();
+    clients.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    (clients, usage.anonymous)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::Uri;
+
+    use super::{strip_token, Refused, Usage};
+
+    #[test]
+    fn test_strip_token() {
+        let uri = Uri::from_static("/t/hunter2/s/fox%20solo?cols=id");
+        let (token, uri) = strip_token(&uri).unwrap();
+        assert_eq!(token, "hunter2");
+        assert_eq!(uri, "/s/fox%20solo?cols=id");
+
+        assert!(strip_token(&Uri::from_static("/s/fox")).is_none());
+        assert!(strip_token(&Uri::from_static("/t/hunter2")).is_none());
+    }
+
+    #[test]
+    fn test_throttling() {
+        let mut usage = Usage::default();
+
+        assert_eq!(usage.take("world".into(), Some(2), 1), Ok(()));
+        assert_eq!(usage.take("world".into(), Some(2), 1), Ok(()));
+        assert_eq!(
+            usage.take("world".into(), Some(2), 1),
+            Err(Refused::Throttled)
+        );
+        assert_eq!(usage.take("relay".into(), Some(2), 1), Ok(()));
+        // the limit is per minute
+        assert_eq!(usage.take("world".into(), Some(2), 2), Ok(()));
+
+        let stats = usage.clients["world"];
+        assert_eq!((stats.searches, stats.throttled), (3, 1));
+    }
+}
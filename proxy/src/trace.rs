@@ -0,0 +1,113 @@
+//! Trace export over OTLP.
+//!
+//! Requests are answered in `tracing` spans, with spans inside them for the
+//! steps of a search: parsing the query, calling e621, registering the links,
+//! generating the preview and downloading images. Work spawned on behalf of a
+//! request, like its preview, stays in its span even once it is answered.
+//!
+//! If an OTLP endpoint is configured, these spans are also exported as traces,
+//! one per request, in batches over OTLP/HTTP with the JSON encoding, which the
+//! OpenTelemetry Collector, Jaeger and Tempo all accept.
+
+use std::sync::OnceLock;
+
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::field::Empty;
+use tracing::Span;
+use tracing_subscriber::Layer;
+
+use crate::config::Config;
+use crate::logging::Output;
+use crate::request_id::RequestId;
+
+/// The name traces are exported under.
+const SERVICE_NAME: &str = "roli_proxy";
+
+/// The provider of the exported traces, once the exporter is started.
+static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+/// The span a request is answered in, and the root span of its trace. Its
+/// route and status are recorded by `root` once they are known.
+pub fn request_span(req: &Request, id: RequestId) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %id,
+        otel.name = Empty,
+        otel.kind = "server",
+        otel.status_code = Empty,
+        http.request.method = %req.method(),
+        http.route = Empty,
+        http.response.status_code = Empty,
+    )
+}
+
+/// Middleware that records the route and status of a request on its span.
+pub async fn root(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("fallback", MatchedPath::as_str)
+        .to_owned();
+
+    let span = Span::current();
+    span.record("otel.name", format!("{} {route}", req.method()));
+    span.record("http.route", route);
+
+    let res = next.run(req).await;
+
+    // recorded as a signed number, which exporters send as an integer
+    span.record(
+        "http.response.status_code",
+        i64::from(res.status().as_u16()),
+    );
+    if res.status().is_server_error() {
+        span.record("otel.status_code", "error");
+    }
+
+    res
+}
+
+/// The output that exports spans to the configured OTLP endpoint, starting
+/// the exporter, if an endpoint is configured.
+pub fn output() -> Option<Output> {
+    let endpoint = Config::global().server.otlp_endpoint.clone()?;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpJson)
+        .with_endpoint(&endpoint)
+        .build();
+    let exporter = match exporter {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            tracing::error!("can't export traces to {endpoint}: {e}");
+            return None;
+        }
+    };
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]))
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    let _ = PROVIDER.set(provider);
+
+    tracing::info!("exporting traces to {endpoint}");
+    Some(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+/// Export the spans that are still queued, before the proxy stops.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!("failed to export the last spans: {e}");
+        }
+    }
+}